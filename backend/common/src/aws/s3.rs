@@ -1,135 +1,764 @@
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
+use std::fmt::Display;
 use std::marker::PhantomData;
-use std::time::Duration;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
 use aws_config::meta::region::RegionProviderChain;
+use aws_sdk_s3::model::{CompletedMultipartUpload, CompletedPart};
 use aws_sdk_s3::output::{GetObjectOutput, PutObjectOutput};
 use aws_sdk_s3::presigning::config::PresigningConfig;
-use aws_sdk_s3::{Client, Region};
+use aws_sdk_s3::{Client, Endpoint, Region};
+use aws_sigv4::sign::{calculate_signature, generate_signing_key};
+use aws_smithy_types::retry::ProvideErrorKind;
+use aws_types::credentials::{ProvideCredentials, SharedCredentialsProvider};
+use aws_types::Credentials;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use http::Uri;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Serialize;
+use utoipa::ToSchema;
 
 pub use aws_sdk_s3::types::ByteStream;
+use aws_sdk_s3::types::SdkError;
 
-use crate::config;
+use crate::config::{self, PresignConfig};
+use crate::metrics::observe_s3;
 
 pub struct Thumbnails;
 pub struct Media;
 
 pub struct S3Bucket<T> {
     bucket: String,
+    region: String,
+    // S3 compatible server used instead of AWS
+    endpoint: Option<Uri>,
     client: Client,
+    // Signs presigned POST policies, which the SDK doesn't build
+    credentials: Option<SharedCredentialsProvider>,
+    presign: PresignConfig,
+    // Presigned GET urls by key, with the time they stop being handed out
+    presigned_urls: Mutex<HashMap<String, (String, Instant)>>,
     data: PhantomData<T>,
 }
 
-const EXPIRES_IN: Duration = Duration::from_secs(5 * 60);
+/// A presigned POST upload, a form of `fields` followed by the file sent to `url`
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct PresignedPost {
+    pub url: String,
+    pub fields: BTreeMap<String, String>,
+}
 
-impl S3Bucket<Media> {
-    pub async fn new(config: &config::Config) -> Self {
-        let region_provider =
-            RegionProviderChain::first_try(Some(Region::new(config.aws_region.clone())))
-                .or_default_provider()
-                .or_else("us-east-1");
+/// A part of a multipart upload S3 has received
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct UploadedPart {
+    pub part_number: i32,
+    pub etag: String,
+    pub size: u64,
+}
 
-        let shared_config = aws_config::from_env().region(region_provider).load().await;
+/// A multipart upload neither completed nor aborted
+#[derive(Clone, Debug)]
+pub struct PendingUpload {
+    pub key: String,
+    pub upload_id: String,
+    pub initiated: DateTime<Utc>,
+}
 
-        let client = Client::new(&shared_config);
+#[derive(Debug)]
+pub enum MultipartError {
+    NoSuchUpload,
+    TooLarge { size: u64, max: u64 },
+    // The parts can't make up the object, e.g. some but the last are too small
+    InvalidParts(String),
+    S3(Box<dyn Error>),
+}
 
-        S3Bucket {
-            bucket: config.aws_media_bucket.clone(),
-            client,
-            data: PhantomData,
-        }
+const SIGNING_ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+pub const MAX_UPLOAD_PARTS: i32 = 10_000;
+/// Smallest size S3 accepts for the parts of multipart uploads, but the last
+pub const MIN_UPLOAD_PART_BYTES: u64 = 5 * 1024 * 1024;
+
+/// Presigned GET urls are handed out again until this much is left of their lifetime,
+/// responses holding them may be cached for less
+pub const PRESIGNED_URL_MIN_LIFETIME: Duration = Duration::from_secs(60);
+
+const ORIGINALS_PREFIX: &str = "originals/";
+
+// Characters of keys kept as is in copy sources, the unreserved ones of RFC 3986 and the
+// slashes between path segments
+const COPY_SOURCE_KEY: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'/');
+
+/// Key of the animated thumbnail stored next to the poster frame of `key`
+pub fn animated_thumbnail_key(key: &str) -> String {
+    format!("{}.webp", key)
+}
+
+/// Key of the untouched original kept in the media bucket once `key` has been edited
+pub fn original_media_key(key: &str) -> String {
+    format!("{}{}", ORIGINALS_PREFIX, key)
+}
+
+pub fn is_original_media_key(key: &str) -> bool {
+    key.starts_with(ORIGINALS_PREFIX)
+}
+
+// S3 reads the source of a copy url-encoded
+fn copy_source(bucket: &str, key: &str) -> String {
+    format!("{}/{}", bucket, utf8_percent_encode(key, COPY_SOURCE_KEY))
+}
+
+impl S3Bucket<Media> {
+    pub async fn new(config: &config::Config) -> Self {
+        S3Bucket::connect(
+            config,
+            config.aws.media_bucket.clone(),
+            config.aws.media_presign.clone(),
+        )
+        .await
     }
 }
 
 impl S3Bucket<Thumbnails> {
     pub async fn new(config: &config::Config) -> Self {
+        S3Bucket::connect(
+            config,
+            config.aws.thumbnails_bucket.clone(),
+            config.aws.thumbnails_presign.clone(),
+        )
+        .await
+    }
+}
+
+impl<T> S3Bucket<T> {
+    async fn connect(config: &config::Config, bucket: String, presign: PresignConfig) -> Self {
         let region_provider =
-            RegionProviderChain::first_try(Some(Region::new(config.aws_region.clone())))
+            RegionProviderChain::first_try(Some(Region::new(config.aws.region.clone())))
                 .or_default_provider()
                 .or_else("us-east-1");
 
         let shared_config = aws_config::from_env().region(region_provider).load().await;
 
-        let client = Client::new(&shared_config);
+        // Requests to other endpoints name the bucket in the path, as MinIO expects
+        let mut s3_config = aws_sdk_s3::config::Builder::from(&shared_config);
+        if let Some(endpoint) = &config.aws.s3_endpoint {
+            s3_config = s3_config.endpoint_resolver(Endpoint::immutable(endpoint.clone()));
+        }
+        let client = Client::from_conf(s3_config.build());
 
         S3Bucket {
-            bucket: config.aws_thumbnails_bucket.clone(),
+            bucket,
+            region: shared_config
+                .region()
+                .map_or(config.aws.region.clone(), |region| region.to_string()),
+            endpoint: config.aws.s3_endpoint.clone(),
             client,
+            credentials: shared_config.credentials_provider().cloned(),
+            presign,
+            presigned_urls: Mutex::new(HashMap::new()),
             data: PhantomData,
         }
     }
-}
 
-impl<T> S3Bucket<T> {
+    /// Whether uploads may declare the content type
+    pub fn accepts_upload(&self, content_type: &str) -> bool {
+        self.presign
+            .upload_content_types
+            .iter()
+            .any(|accepted| accepted.eq_ignore_ascii_case(content_type))
+    }
+
+    /// Largest object uploads may create
+    pub fn upload_max_bytes(&self) -> u64 {
+        self.presign.upload_max_bytes
+    }
+
     pub async fn put_object(
         &self,
         object: &String,
         content_type: impl Into<String>,
         data: ByteStream,
     ) -> Result<PutObjectOutput, Box<dyn Error>> {
-        let resp = self
+        let request = self
             .client
             .put_object()
             .content_type(content_type)
             .bucket(&self.bucket)
             .key(object)
             .body(data)
-            .send()
-            .await?;
+            .send();
+        let resp = observe_s3(&self.bucket, "put_object", request).await?;
 
         Ok(resp)
     }
 
     pub async fn get_object(&self, object: &String) -> Result<GetObjectOutput, Box<dyn Error>> {
-        let resp = self
+        let request = self
             .client
             .get_object()
             .bucket(&self.bucket)
             .key(object)
-            .send()
-            .await?;
+            .send();
+        let resp = observe_s3(&self.bucket, "get_object", request).await?;
 
         Ok(resp)
     }
 
+    pub async fn copy_object(&self, from: &str, to: &String) -> Result<(), Box<dyn Error>> {
+        let request = self
+            .client
+            .copy_object()
+            .bucket(&self.bucket)
+            .copy_source(copy_source(&self.bucket, from))
+            .key(to)
+            .send();
+        observe_s3(&self.bucket, "copy_object", request).await?;
+
+        Ok(())
+    }
+
+    pub async fn object_exists(&self, object: &String) -> Result<bool, Box<dyn Error>> {
+        let request = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(object)
+            .send();
+        let resp = observe_s3(&self.bucket, "head_object", request).await;
+
+        match resp {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError { err, .. }) if err.is_not_found() => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Size of the object in bytes, `None` when it doesn't exist
+    pub async fn object_size(&self, object: &String) -> Result<Option<u64>, Box<dyn Error>> {
+        let request = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(object)
+            .send();
+        let resp = observe_s3(&self.bucket, "head_object", request).await;
+
+        match resp {
+            Ok(head) => Ok(Some(u64::try_from(head.content_length).unwrap_or(0))),
+            Err(SdkError::ServiceError { err, .. }) if err.is_not_found() => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     pub async fn delete_object(&self, object: &String) -> Result<(), Box<dyn Error>> {
-        self.client
+        let request = self
+            .client
             .delete_object()
             .bucket(&self.bucket)
             .key(object)
-            .send()
-            .await?;
+            .send();
+        observe_s3(&self.bucket, "delete_object", request).await?;
+
+        self.presigned_urls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(object);
+        Ok(())
+    }
+
+    /// Checks the bucket exists and is accessible with the current credentials
+    pub async fn head_bucket(&self) -> Result<(), Box<dyn Error>> {
+        let request = self.client.head_bucket().bucket(&self.bucket).send();
+        observe_s3(&self.bucket, "head_bucket", request).await?;
 
         Ok(())
     }
 
+    /// Presigns a GET url, or hands out the one presigned earlier while enough is left of
+    /// its lifetime, so responses listing it stay the same
     pub async fn get_object_presigned_url(
         &self,
         object: &String,
     ) -> Result<String, Box<dyn Error>> {
+        let now = Instant::now();
+        if let Some((url, renew_at)) = self
+            .presigned_urls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(object)
+        {
+            if now < *renew_at {
+                return Ok(url.clone());
+            }
+        }
+
         let presigned_req = self
             .client
             .get_object()
             .bucket(&self.bucket)
             .key(object)
-            .presigned(PresigningConfig::expires_in(EXPIRES_IN)?)
+            .presigned(PresigningConfig::expires_in(self.presign.get_expires_in)?)
             .await?;
+        let url = presigned_req.uri().to_string();
 
-        Ok(presigned_req.uri().to_string())
+        let mut presigned_urls = self
+            .presigned_urls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        presigned_urls.retain(|_, (_, renew_at)| now < *renew_at);
+        presigned_urls.insert(
+            object.clone(),
+            (
+                url.clone(),
+                now + self
+                    .presign
+                    .get_expires_in
+                    .saturating_sub(PRESIGNED_URL_MIN_LIFETIME),
+            ),
+        );
+        Ok(url)
     }
 
+    /// Presigns a PUT of the object, which must be sent with the `Content-Type` given if
+    /// any. Its size can't be limited, objects larger than the upload limit have to be
+    /// deleted once created.
     pub async fn put_object_presigned_url(
         &self,
         object: &String,
+        content_type: Option<&str>,
+    ) -> Result<String, Box<dyn Error>> {
+        let mut request = self.client.put_object().bucket(&self.bucket).key(object);
+        if let Some(content_type) = content_type {
+            self.check_upload(content_type)?;
+            request = request.content_type(content_type);
+        }
+
+        let presigned_req = request
+            .presigned(PresigningConfig::expires_in(
+                self.presign.upload_expires_in,
+            )?)
+            .await?;
+
+        Ok(presigned_req.uri().to_string())
+    }
+
+    /// Presigns a POST of the object, which S3 rejects unless it has the `Content-Type`
+    /// given and a size within the upload limit
+    pub async fn post_object_presigned_form(
+        &self,
+        object: &str,
+        content_type: &str,
+    ) -> Result<PresignedPost, Box<dyn Error>> {
+        self.check_upload(content_type)?;
+
+        let credentials = self
+            .credentials
+            .as_ref()
+            .ok_or("No AWS credentials to sign uploads with")?
+            .provide_credentials()
+            .await?;
+
+        self.post_form(object, content_type, &credentials, Utc::now())
+    }
+
+    /// Starts a multipart upload of the object, which gets the `Content-Type` given
+    pub async fn create_multipart_upload(
+        &self,
+        object: &str,
+        content_type: &str,
+    ) -> Result<String, Box<dyn Error>> {
+        self.check_upload(content_type)?;
+
+        let request = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(object)
+            .content_type(content_type)
+            .send();
+        let resp = observe_s3(&self.bucket, "create_multipart_upload", request).await?;
+
+        Ok(resp
+            .upload_id()
+            .ok_or("S3 returned no upload id")?
+            .to_string())
+    }
+
+    /// Presigns a PUT of a part of a multipart upload
+    pub async fn upload_part_presigned_url(
+        &self,
+        object: &str,
+        upload_id: &str,
+        part_number: i32,
     ) -> Result<String, Box<dyn Error>> {
         let presigned_req = self
             .client
-            .put_object()
+            .upload_part()
             .bucket(&self.bucket)
             .key(object)
-            .presigned(PresigningConfig::expires_in(EXPIRES_IN)?)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .presigned(PresigningConfig::expires_in(
+                self.presign.upload_expires_in,
+            )?)
             .await?;
 
         Ok(presigned_req.uri().to_string())
     }
+
+    /// Lists the parts of a multipart upload received so far
+    pub async fn list_parts(
+        &self,
+        object: &str,
+        upload_id: &str,
+    ) -> Result<Vec<UploadedPart>, MultipartError> {
+        let mut parts = Vec::new();
+        let mut marker = None;
+        loop {
+            let request = self
+                .client
+                .list_parts()
+                .bucket(&self.bucket)
+                .key(object)
+                .upload_id(upload_id)
+                .set_part_number_marker(marker)
+                .send();
+            let resp = observe_s3(&self.bucket, "list_parts", request).await?;
+
+            parts.extend(
+                resp.parts()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|part| UploadedPart {
+                        part_number: part.part_number(),
+                        etag: part.e_tag().unwrap_or_default().to_string(),
+                        size: part.size().try_into().unwrap_or_default(),
+                    }),
+            );
+            match resp.next_part_number_marker() {
+                Some(next) if resp.is_truncated() => marker = Some(next.to_string()),
+                _ => return Ok(parts),
+            }
+        }
+    }
+
+    /// Completes a multipart upload with every part received. Uploads larger than the
+    /// upload limit are aborted instead.
+    pub async fn complete_multipart_upload(
+        &self,
+        object: &str,
+        upload_id: &str,
+    ) -> Result<(), MultipartError> {
+        let parts = self.list_parts(object, upload_id).await?;
+        if parts.is_empty() {
+            return Err(MultipartError::InvalidParts(
+                "No parts have been uploaded".to_string(),
+            ));
+        }
+
+        let size = parts.iter().map(|part| part.size).sum();
+        if size > self.presign.upload_max_bytes {
+            self.abort_multipart_upload(object, upload_id).await?;
+            return Err(MultipartError::TooLarge {
+                size,
+                max: self.presign.upload_max_bytes,
+            });
+        }
+
+        let upload = parts
+            .into_iter()
+            .fold(CompletedMultipartUpload::builder(), |upload, part| {
+                upload.parts(
+                    CompletedPart::builder()
+                        .part_number(part.part_number)
+                        .e_tag(part.etag)
+                        .build(),
+                )
+            })
+            .build();
+        let request = self
+            .client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(object)
+            .upload_id(upload_id)
+            .multipart_upload(upload)
+            .send();
+        observe_s3(&self.bucket, "complete_multipart_upload", request).await?;
+
+        Ok(())
+    }
+
+    /// Aborts a multipart upload, deleting the parts received
+    pub async fn abort_multipart_upload(
+        &self,
+        object: &str,
+        upload_id: &str,
+    ) -> Result<(), MultipartError> {
+        let request = self
+            .client
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(object)
+            .upload_id(upload_id)
+            .send();
+        observe_s3(&self.bucket, "abort_multipart_upload", request).await?;
+
+        Ok(())
+    }
+
+    /// Lists the multipart uploads of the bucket neither completed nor aborted
+    pub async fn list_multipart_uploads(&self) -> Result<Vec<PendingUpload>, Box<dyn Error>> {
+        let mut uploads = Vec::new();
+        let mut markers = (None, None);
+        loop {
+            let request = self
+                .client
+                .list_multipart_uploads()
+                .bucket(&self.bucket)
+                .set_key_marker(markers.0)
+                .set_upload_id_marker(markers.1)
+                .send();
+            let resp = observe_s3(&self.bucket, "list_multipart_uploads", request).await?;
+
+            for upload in resp.uploads().unwrap_or_default() {
+                let initiated = upload.initiated().and_then(|initiated| {
+                    DateTime::from_timestamp(initiated.secs(), initiated.subsec_nanos())
+                });
+                if let (Some(key), Some(upload_id), Some(initiated)) =
+                    (upload.key(), upload.upload_id(), initiated)
+                {
+                    uploads.push(PendingUpload {
+                        key: key.to_string(),
+                        upload_id: upload_id.to_string(),
+                        initiated,
+                    });
+                }
+            }
+            if !resp.is_truncated() {
+                return Ok(uploads);
+            }
+            markers = (
+                resp.next_key_marker().map(String::from),
+                resp.next_upload_id_marker().map(String::from),
+            );
+        }
+    }
+
+    fn check_upload(&self, content_type: &str) -> Result<(), Box<dyn Error>> {
+        if self.accepts_upload(content_type) {
+            Ok(())
+        } else {
+            Err(format!("Uploads of {} are not accepted", content_type).into())
+        }
+    }
+
+    // Signs a POST policy as described by
+    // https://docs.aws.amazon.com/AmazonS3/latest/API/sigv4-HTTPPOSTConstructPolicy.html
+    fn post_form(
+        &self,
+        object: &str,
+        content_type: &str,
+        credentials: &Credentials,
+        now: DateTime<Utc>,
+    ) -> Result<PresignedPost, Box<dyn Error>> {
+        let credential = format!(
+            "{}/{}/{}/s3/aws4_request",
+            credentials.access_key_id(),
+            now.format("%Y%m%d"),
+            self.region
+        );
+
+        let mut fields = BTreeMap::from([
+            ("key".to_string(), object.to_string()),
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("x-amz-algorithm".to_string(), SIGNING_ALGORITHM.to_string()),
+            ("x-amz-credential".to_string(), credential),
+            (
+                "x-amz-date".to_string(),
+                now.format("%Y%m%dT%H%M%SZ").to_string(),
+            ),
+        ]);
+        if let Some(token) = credentials.session_token() {
+            fields.insert("x-amz-security-token".to_string(), token.to_string());
+        }
+
+        let mut conditions = vec![
+            serde_json::json!({ "bucket": self.bucket }),
+            serde_json::json!(["content-length-range", 1, self.presign.upload_max_bytes]),
+        ];
+        conditions.extend(
+            fields
+                .iter()
+                .map(|(field, value)| serde_json::json!({ field: value })),
+        );
+        let expiration = now + chrono::Duration::from_std(self.presign.upload_expires_in)?;
+        let policy = serde_json::json!({
+            "expiration": expiration.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            "conditions": conditions,
+        });
+        let policy = BASE64.encode(serde_json::to_vec(&policy)?);
+
+        let signature = sign_policy(credentials.secret_access_key(), now, &self.region, &policy);
+        fields.insert("policy".to_string(), policy);
+        fields.insert("x-amz-signature".to_string(), signature);
+
+        Ok(PresignedPost {
+            url: self.post_url(),
+            fields,
+        })
+    }
+
+    fn post_url(&self) -> String {
+        match &self.endpoint {
+            Some(endpoint) => format!(
+                "{}/{}/",
+                endpoint.to_string().trim_end_matches('/'),
+                self.bucket
+            ),
+            None => format!("https://{}.s3.{}.amazonaws.com/", self.bucket, self.region),
+        }
+    }
+}
+
+// Signs the base64 encoded policy with a key derived for S3 in the region on the day of `now`
+fn sign_policy(secret_access_key: &str, now: DateTime<Utc>, region: &str, policy: &str) -> String {
+    let signing_key = generate_signing_key(secret_access_key, now.into(), region, "s3");
+    calculate_signature(signing_key, policy.as_bytes())
+}
+
+impl<E> From<SdkError<E>> for MultipartError
+where
+    E: ProvideErrorKind + Error + 'static,
+{
+    fn from(err: SdkError<E>) -> Self {
+        if let SdkError::ServiceError { err, .. } = &err {
+            match err.code() {
+                Some("NoSuchUpload") => return MultipartError::NoSuchUpload,
+                Some("EntityTooSmall" | "InvalidPart" | "InvalidPartOrder") => {
+                    return MultipartError::InvalidParts(err.to_string())
+                }
+                _ => (),
+            }
+        }
+        MultipartError::S3(err.into())
+    }
+}
+
+impl Display for MultipartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MultipartError::NoSuchUpload => write!(f, "No such multipart upload"),
+            MultipartError::TooLarge { size, max } => write!(
+                f,
+                "Upload of {} bytes is larger than the {} bytes allowed",
+                size, max
+            ),
+            MultipartError::InvalidParts(message) => write!(f, "{}", message),
+            MultipartError::S3(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for MultipartError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_post_policies() {
+        let mut bucket = S3Bucket::<Media> {
+            bucket: "media".to_string(),
+            region: "us-east-1".to_string(),
+            endpoint: None,
+            client: Client::from_conf(aws_sdk_s3::Config::builder().build()),
+            credentials: None,
+            presign: PresignConfig {
+                upload_content_types: vec!["image/png".to_string()],
+                upload_max_bytes: 1024,
+                ..PresignConfig::default()
+            },
+            presigned_urls: Mutex::new(HashMap::new()),
+            data: PhantomData,
+        };
+        let credentials = Credentials::new("AKIDEXAMPLE", "secret", None, None, "test");
+        let now = DateTime::parse_from_rfc3339("2026-10-19T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let post = bucket
+            .post_form("image", "image/png", &credentials, now)
+            .unwrap();
+        assert_eq!(post.url, "https://media.s3.us-east-1.amazonaws.com/");
+        assert_eq!(
+            post.fields["x-amz-credential"],
+            "AKIDEXAMPLE/20261019/us-east-1/s3/aws4_request"
+        );
+        assert_eq!(
+            post.fields["x-amz-signature"],
+            sign_policy("secret", now, "us-east-1", &post.fields["policy"])
+        );
+
+        let policy: serde_json::Value =
+            serde_json::from_slice(&BASE64.decode(&post.fields["policy"]).unwrap()).unwrap();
+        assert_eq!(policy["expiration"], "2026-10-19T12:05:00Z");
+        let conditions = policy["conditions"].as_array().unwrap();
+        assert!(conditions.contains(&serde_json::json!(["content-length-range", 1, 1024])));
+        assert!(conditions.contains(&serde_json::json!({ "Content-Type": "image/png" })));
+
+        assert!(bucket.check_upload("image/gif").is_err());
+
+        bucket.endpoint = Some("http://localhost:9000".parse().unwrap());
+        assert_eq!(bucket.post_url(), "http://localhost:9000/media/");
+    }
+
+    #[test]
+    fn encodes_copy_sources() {
+        assert_eq!(
+            copy_source("media", "originals/a b+c%d/é.png"),
+            "media/originals/a%20b%2Bc%25d/%C3%A9.png"
+        );
+    }
+
+    // Example of the S3 documentation for browser-based uploads using HTTP POST
+    // https://docs.aws.amazon.com/AmazonS3/latest/API/sigv4-post-example.html
+    #[test]
+    fn signs_policies_like_aws() {
+        let policy = concat!(
+            "eyAiZXhwaXJhdGlvbiI6ICIyMDE1LTEyLTMwVDEyOjAwOjAwLjAwMFoiLA0KICAiY29uZGl0aW9ucyI6",
+            "IFsNCiAgICB7ImJ1Y2tldCI6ICJzaWd2NGV4YW1wbGVidWNrZXQifSwNCiAgICBbInN0YXJ0cy13aXRo",
+            "IiwgIiRrZXkiLCAidXNlci91c2VyMS8iXSwNCiAgICB7ImFjbCI6ICJwdWJsaWMtcmVhZCJ9LA0KICAg",
+            "IHsic3VjY2Vzc19hY3Rpb25fcmVkaXJlY3QiOiAiaHR0cDovL3NpZ3Y0ZXhhbXBsZWJ1Y2tldC5zMy5h",
+            "bWF6b25hd3MuY29tL3N1Y2Nlc3NmdWxfdXBsb2FkLmh0bWwifSwNCiAgICBbInN0YXJ0cy13aXRoIiwg",
+            "IiRDb250ZW50LVR5cGUiLCAiaW1hZ2UvIl0sDQogICAgeyJ4LWFtei1tZXRhLXV1aWQiOiAiMTQzNjUx",
+            "MjM2NTEyNzQifSwNCiAgICB7IngtYW16LXNlcnZlci1zaWRlLWVuY3J5cHRpb24iOiAiQUVTMjU2In0s",
+            "DQogICAgWyJzdGFydHMtd2l0aCIsICIkeC1hbXotbWV0YS10YWciLCAiIl0sDQoNCiAgICB7IngtYW16",
+            "LWNyZWRlbnRpYWwiOiAiQUtJQUlPU0ZPRE5ON0VYQU1QTEUvMjAxNTEyMjkvdXMtZWFzdC0xL3MzL2F3",
+            "czRfcmVxdWVzdCJ9LA0KICAgIHsieC1hbXotYWxnb3JpdGhtIjogIkFXUzQtSE1BQy1TSEEyNTYifSwN",
+            "CiAgICB7IngtYW16LWRhdGUiOiAiMjAxNTEyMjlUMDAwMDAwWiIgfQ0KICBdDQp9",
+        );
+        let now = DateTime::parse_from_rfc3339("2015-12-29T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(
+            sign_policy(
+                "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+                now,
+                "us-east-1",
+                policy
+            ),
+            "8afdbf4008c03f22c2cd3cdb72e4afbb1f6a588f3255ac628749a66d7f09699e"
+        );
+    }
 }
@@ -1,10 +1,13 @@
+use std::collections::HashMap;
 use std::{error::Error, fmt::Display};
 
 use aws_config::{self, meta::region::RegionProviderChain};
+use aws_sdk_sqs::model::{Message, MessageAttributeValue};
 use aws_sdk_sqs::output::DeleteMessageOutput;
 use aws_sdk_sqs::{Client, Region};
 
 use crate::config::Config;
+use crate::telemetry;
 
 use std::time::Duration;
 
@@ -24,7 +27,7 @@ pub struct QueueNotFoundError;
 impl SQSQueue {
     pub async fn new(config: &Config) -> Result<Self, Box<dyn Error>> {
         let region_provider =
-            RegionProviderChain::first_try(Region::new(config.aws_region.clone()))
+            RegionProviderChain::first_try(Region::new(config.aws.region.clone()))
                 .or_default_provider()
                 .or_else("us-east-1");
 
@@ -34,7 +37,7 @@ impl SQSQueue {
 
         let queues = client
             .list_queues()
-            .queue_name_prefix(&config.aws_sqs_queue)
+            .queue_name_prefix(&config.aws.sqs_queue)
             .send()
             .await?;
 
@@ -45,19 +48,31 @@ impl SQSQueue {
         Ok(SQSQueue { queue_url, client })
     }
 
+    // The group id is only accepted by FIFO queues. The trace context of the sender is
+    // attached as message attributes, for the receiver to continue the trace.
     pub async fn send(
         &self,
         msg_body: &String,
-        msg_group_id: &String,
+        msg_group_id: Option<&String>,
     ) -> Result<SendMessageOutput, Box<dyn Error>> {
-        let rsp = self
+        let mut request = self
             .client
             .send_message()
             .queue_url(&self.queue_url)
             .message_body(msg_body)
-            .message_group_id(msg_group_id)
-            .send()
-            .await?;
+            .set_message_group_id(msg_group_id.cloned());
+
+        for (name, value) in telemetry::current_context() {
+            request = request.message_attributes(
+                name,
+                MessageAttributeValue::builder()
+                    .data_type("String")
+                    .string_value(value)
+                    .build(),
+            );
+        }
+
+        let rsp = request.send().await?;
 
         Ok(rsp)
     }
@@ -71,6 +86,8 @@ impl SQSQueue {
             .receive_message()
             .wait_time_seconds(max_wait_time.as_secs() as i32)
             .queue_url(&self.queue_url)
+            .message_attribute_names(telemetry::TRACEPARENT)
+            .message_attribute_names(telemetry::TRACESTATE)
             .send()
             .await?;
 
@@ -93,6 +110,19 @@ impl SQSQueue {
     }
 }
 
+/// Trace context attached by the sender of a message. Bucket notifications have none.
+pub fn trace_context(message: &Message) -> HashMap<String, String> {
+    message
+        .message_attributes()
+        .map(|attributes| {
+            attributes
+                .iter()
+                .filter_map(|(name, value)| Some((name.clone(), value.string_value()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 impl Display for QueueNotFoundError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "SQS Queue not Found")
@@ -0,0 +1,22 @@
+use serde::{Deserialize, Serialize};
+
+use crate::transform::Transformation;
+
+/// Commands other services enqueue for imgproc, next to the bucket notifications
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageCommand {
+    // (Re)generate the thumbnails of the media
+    Process {
+        key: String,
+    },
+    // Replace the media with the original transformed by the given list
+    Transform {
+        key: String,
+        transformations: Vec<Transformation>,
+    },
+    // Restore the untouched original
+    Revert {
+        key: String,
+    },
+}
@@ -1,12 +1,21 @@
-use std::{collections::HashMap, fmt::Display, time::Duration};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
 
-use serde::{Deserialize, Serialize};
+use clap::Parser;
 use tokio::task::spawn_blocking;
+use tracing::{info, warn};
 
 use dotenv;
+use http::Uri;
+use ipnet::IpNet;
 
-use crate::jwt;
 use crate::aws;
+use crate::jwt;
 
 pub const AWS_PROFILE: &'static str = "AWS_PROFILE";
 pub const AWS_REGION: &'static str = "AWS_REGION";
@@ -15,122 +24,1305 @@ pub const AWS_THUMBNAILS_BUCKET: &'static str = "AWS_THUMBNAILS_BUCKET";
 pub const AWS_THUMBNAILS_BASE_URL: &'static str = "AWS_THUMBNAILS_BASE_URL";
 pub const AWS_SQS_QUEUE: &'static str = "AWS_SQS_QUEUE";
 pub const AWS_SQS_MAX_WAIT_TIME_IN_SEC: &'static str = "AWS_SQS_MAX_WAIT_TIME_IN_SEC";
+pub const AWS_MEDIA_GET_URL_EXPIRY: &'static str = "AWS_MEDIA_GET_URL_EXPIRY";
+pub const AWS_MEDIA_UPLOAD_URL_EXPIRY: &'static str = "AWS_MEDIA_UPLOAD_URL_EXPIRY";
+pub const AWS_MEDIA_UPLOAD_CONTENT_TYPES: &'static str = "AWS_MEDIA_UPLOAD_CONTENT_TYPES";
+pub const AWS_MEDIA_UPLOAD_MAX_BYTES: &'static str = "AWS_MEDIA_UPLOAD_MAX_BYTES";
+pub const AWS_MEDIA_MULTIPART_MAX_AGE: &'static str = "AWS_MEDIA_MULTIPART_MAX_AGE";
+pub const AWS_THUMBNAILS_GET_URL_EXPIRY: &'static str = "AWS_THUMBNAILS_GET_URL_EXPIRY";
+pub const AWS_THUMBNAILS_UPLOAD_URL_EXPIRY: &'static str = "AWS_THUMBNAILS_UPLOAD_URL_EXPIRY";
+pub const AWS_THUMBNAILS_UPLOAD_CONTENT_TYPES: &'static str = "AWS_THUMBNAILS_UPLOAD_CONTENT_TYPES";
+pub const AWS_THUMBNAILS_UPLOAD_MAX_BYTES: &'static str = "AWS_THUMBNAILS_UPLOAD_MAX_BYTES";
+pub const AWS_S3_ENDPOINT: &'static str = "AWS_S3_ENDPOINT";
 pub const POSTGRESS_USERNAME: &'static str = "POSTGRESS_USERNAME";
 pub const POSTGRESS_PASSWORD: &'static str = "POSTGRESS_PASSWORD";
 pub const POSTGRESS_DATABASE: &'static str = "POSTGRESS_DATABASE";
 pub const POSTGRESS_HOST: &'static str = "POSTGRESS_HOST";
+pub const DATABASE_DIALECT: &'static str = "DATABASE_DIALECT";
+pub const DATABASE_URL: &'static str = "DATABASE_URL";
+pub const POSTGRESS_PORT: &'static str = "POSTGRESS_PORT";
+pub const POSTGRESS_SSLMODE: &'static str = "POSTGRESS_SSLMODE";
+pub const POSTGRESS_SSLROOTCERT: &'static str = "POSTGRESS_SSLROOTCERT";
+pub const DATABASE_POOL_MAX_SIZE: &'static str = "DATABASE_POOL_MAX_SIZE";
+pub const DATABASE_POOL_MIN_IDLE: &'static str = "DATABASE_POOL_MIN_IDLE";
+pub const DATABASE_POOL_CONNECTION_TIMEOUT: &'static str = "DATABASE_POOL_CONNECTION_TIMEOUT";
+pub const DATABASE_POOL_IDLE_TIMEOUT: &'static str = "DATABASE_POOL_IDLE_TIMEOUT";
+pub const DATABASE_STARTUP_TIMEOUT: &'static str = "DATABASE_STARTUP_TIMEOUT";
+pub const DATABASE_AUTO_MIGRATE: &'static str = "DATABASE_AUTO_MIGRATE";
+pub const DATABASE_REPLICA_URL: &'static str = "DATABASE_REPLICA_URL";
+pub const DATABASE_REPLICA_STICKINESS: &'static str = "DATABASE_REPLICA_STICKINESS";
 pub const JWT_SECRET: &'static str = "JWT_SECRET";
 pub const JWT_TOKEN_TIMEOUT: &'static str = "JWT_TOKEN_TIMEOUT";
+pub const IMGPROC_ANIMATION_MAX_FRAMES: &'static str = "IMGPROC_ANIMATION_MAX_FRAMES";
+pub const IMGPROC_ANIMATION_MAX_DURATION_MS: &'static str = "IMGPROC_ANIMATION_MAX_DURATION_MS";
+pub const IMGPROC_ANIMATION_MAX_BYTES: &'static str = "IMGPROC_ANIMATION_MAX_BYTES";
+pub const IMGPROC_MAX_IN_MEMORY_BYTES: &'static str = "IMGPROC_MAX_IN_MEMORY_BYTES";
+pub const IMGPROC_MAX_INPUT_BYTES: &'static str = "IMGPROC_MAX_INPUT_BYTES";
+pub const SWAGGER_UI_ENABLED: &'static str = "SWAGGER_UI_ENABLED";
+pub const MAX_PAYLOAD_BYTES: &'static str = "MAX_PAYLOAD_BYTES";
+pub const IMGPROC_HTTP_PORT: &'static str = "IMGPROC_HTTP_PORT";
+pub const OTEL_EXPORTER_OTLP_ENDPOINT: &'static str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+pub const RATE_LIMIT_STORE: &'static str = "RATE_LIMIT_STORE";
+pub const TRUSTED_PROXIES: &'static str = "TRUSTED_PROXIES";
+pub const CONFIG_FILE: &'static str = "CONFIG_FILE";
+
+pub static DEFAULT_AWS_PROFILE: &str = "default";
+pub static DEFAULT_PRESIGNED_URL_EXPIRY_SECS: u64 = 5 * 60;
+pub static DEFAULT_MEDIA_UPLOAD_CONTENT_TYPES: &str = "image/jpeg,image/png,image/gif,image/webp";
+// Shared by multipart uploads, which need room for several parts of at least 5 MiB
+pub static DEFAULT_MEDIA_UPLOAD_MAX_BYTES: u64 = 100 * 1024 * 1024;
+pub static DEFAULT_MEDIA_MULTIPART_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+// Thumbnails are only written by imgproc, clients upload none
+pub static DEFAULT_THUMBNAILS_UPLOAD_CONTENT_TYPES: &str = "";
+pub static DEFAULT_DATABASE_DIALECT: &str = "postgres";
+pub static DEFAULT_DATABASE_PORT: u16 = 5432;
+pub static DEFAULT_DATABASE_SSLMODE: &str = "prefer";
+pub static DEFAULT_DATABASE_POOL_MAX_SIZE: u32 = 10;
+pub static DEFAULT_DATABASE_POOL_CONNECTION_TIMEOUT_SECS: u64 = 30;
+pub static DEFAULT_DATABASE_POOL_IDLE_TIMEOUT_SECS: u64 = 10 * 60;
+pub static DEFAULT_DATABASE_STARTUP_TIMEOUT_SECS: u64 = 60;
+pub static DEFAULT_DATABASE_AUTO_MIGRATE: bool = true;
+pub static DEFAULT_DATABASE_REPLICA_STICKINESS_SECS: u64 = 10;
+pub static DEFAULT_ANIMATION_MAX_FRAMES: usize = 50;
+pub static DEFAULT_ANIMATION_MAX_DURATION_MS: u64 = 5000;
+pub static DEFAULT_ANIMATION_MAX_BYTES: usize = 512 * 1024;
+pub static DEFAULT_MAX_IN_MEMORY_BYTES: usize = 16 * 1024 * 1024;
+pub static DEFAULT_MAX_INPUT_BYTES: usize = 100 * 1024 * 1024;
+pub static DEFAULT_SWAGGER_UI_ENABLED: bool = false;
+pub static DEFAULT_MAX_PAYLOAD_BYTES: usize = 64 * 1024;
+pub static DEFAULT_IMGPROC_HTTP_PORT: u16 = 8080;
+pub static DEFAULT_RATE_LIMIT_STORE: &str = "memory";
+
+const RATE_LIMIT_STORES: &[&str] = &["memory", "postgres"];
+const DIALECTS: &[&str] = &["postgres", "sqlite"];
+const SSLMODES: &[&str] = &[
+    "disable",
+    "allow",
+    "prefer",
+    "require",
+    "verify-ca",
+    "verify-full",
+];
+// Settings a database url replaces
+const CONNECTION_SETTINGS: &[&str] = &[
+    "database.host",
+    "database.name",
+    "database.username",
+    "database.password",
+];
+// Settings of database servers, SQLite databases are the files named by database.name
+const SERVER_SETTINGS: &[&str] = &["database.host", "database.username", "database.password"];
+const REDACTED: &str = "<redacted>";
+// Longest lifetime S3 accepts for presigned urls
+const MAX_PRESIGNED_URL_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// Any setting can be read from the file named by its env var suffixed with `_FILE`, or
+// by its key suffixed with `_file`
+const FILE_SUFFIX: &str = "_FILE";
+const FILE_KEY_SUFFIX: &str = "_file";
+
+// Kubernetes updates mounted secrets by swapping a symlink, which file events on the
+// old file miss, so secret files are polled instead
+const SECRET_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A setting, named `section.key` in config files and `--set` flags
+struct Setting {
+    key: &'static str,
+    env: &'static str,
+    secret: bool,
+}
+
+const fn setting(key: &'static str, env: &'static str) -> Setting {
+    Setting {
+        key,
+        env,
+        secret: false,
+    }
+}
+
+const fn secret(key: &'static str, env: &'static str) -> Setting {
+    Setting {
+        key,
+        env,
+        secret: true,
+    }
+}
+
+const SETTINGS: &[Setting] = &[
+    setting("aws.profile", AWS_PROFILE),
+    setting("aws.region", AWS_REGION),
+    setting("aws.media_bucket", AWS_MEDIA_BUCKET),
+    setting("aws.thumbnails_bucket", AWS_THUMBNAILS_BUCKET),
+    setting("aws.thumbnails_base_url", AWS_THUMBNAILS_BASE_URL),
+    setting("aws.sqs_queue", AWS_SQS_QUEUE),
+    setting("aws.sqs_max_wait_time_secs", AWS_SQS_MAX_WAIT_TIME_IN_SEC),
+    setting("aws.media_get_url_expiry_secs", AWS_MEDIA_GET_URL_EXPIRY),
+    setting(
+        "aws.media_upload_url_expiry_secs",
+        AWS_MEDIA_UPLOAD_URL_EXPIRY,
+    ),
+    setting(
+        "aws.media_upload_content_types",
+        AWS_MEDIA_UPLOAD_CONTENT_TYPES,
+    ),
+    setting("aws.media_upload_max_bytes", AWS_MEDIA_UPLOAD_MAX_BYTES),
+    setting(
+        "aws.media_multipart_max_age_secs",
+        AWS_MEDIA_MULTIPART_MAX_AGE,
+    ),
+    setting(
+        "aws.thumbnails_get_url_expiry_secs",
+        AWS_THUMBNAILS_GET_URL_EXPIRY,
+    ),
+    setting(
+        "aws.thumbnails_upload_url_expiry_secs",
+        AWS_THUMBNAILS_UPLOAD_URL_EXPIRY,
+    ),
+    setting(
+        "aws.thumbnails_upload_content_types",
+        AWS_THUMBNAILS_UPLOAD_CONTENT_TYPES,
+    ),
+    setting(
+        "aws.thumbnails_upload_max_bytes",
+        AWS_THUMBNAILS_UPLOAD_MAX_BYTES,
+    ),
+    setting("aws.s3_endpoint", AWS_S3_ENDPOINT),
+    secret("database.url", DATABASE_URL),
+    setting("database.host", POSTGRESS_HOST),
+    setting("database.port", POSTGRESS_PORT),
+    setting("database.name", POSTGRESS_DATABASE),
+    setting("database.username", POSTGRESS_USERNAME),
+    secret("database.password", POSTGRESS_PASSWORD),
+    setting("database.dialect", DATABASE_DIALECT),
+    setting("database.sslmode", POSTGRESS_SSLMODE),
+    setting("database.sslrootcert", POSTGRESS_SSLROOTCERT),
+    setting("database.pool_max_size", DATABASE_POOL_MAX_SIZE),
+    setting("database.pool_min_idle", DATABASE_POOL_MIN_IDLE),
+    setting(
+        "database.pool_connection_timeout_secs",
+        DATABASE_POOL_CONNECTION_TIMEOUT,
+    ),
+    setting(
+        "database.pool_idle_timeout_secs",
+        DATABASE_POOL_IDLE_TIMEOUT,
+    ),
+    setting("database.startup_timeout_secs", DATABASE_STARTUP_TIMEOUT),
+    setting("database.auto_migrate", DATABASE_AUTO_MIGRATE),
+    secret("database.replica_url", DATABASE_REPLICA_URL),
+    setting(
+        "database.replica_stickiness_secs",
+        DATABASE_REPLICA_STICKINESS,
+    ),
+    secret("jwt.secret", JWT_SECRET),
+    setting("jwt.token_timeout_secs", JWT_TOKEN_TIMEOUT),
+    setting("http.swagger_ui_enabled", SWAGGER_UI_ENABLED),
+    setting("http.max_payload_bytes", MAX_PAYLOAD_BYTES),
+    setting("http.rate_limit_store", RATE_LIMIT_STORE),
+    setting("http.trusted_proxies", TRUSTED_PROXIES),
+    setting("telemetry.otlp_endpoint", OTEL_EXPORTER_OTLP_ENDPOINT),
+    setting("imgproc.animation_max_frames", IMGPROC_ANIMATION_MAX_FRAMES),
+    setting(
+        "imgproc.animation_max_duration_ms",
+        IMGPROC_ANIMATION_MAX_DURATION_MS,
+    ),
+    setting("imgproc.animation_max_bytes", IMGPROC_ANIMATION_MAX_BYTES),
+    setting("imgproc.max_in_memory_bytes", IMGPROC_MAX_IN_MEMORY_BYTES),
+    setting("imgproc.max_input_bytes", IMGPROC_MAX_INPUT_BYTES),
+    setting("imgproc.http_port", IMGPROC_HTTP_PORT),
+];
+
+fn defaults() -> BTreeMap<String, String> {
+    [
+        ("aws.profile", DEFAULT_AWS_PROFILE.to_string()),
+        (
+            "aws.sqs_max_wait_time_secs",
+            aws::sqs::DEFAULT_MAX_WAIT_TIME_IN_SEC.to_string(),
+        ),
+        (
+            "aws.media_get_url_expiry_secs",
+            DEFAULT_PRESIGNED_URL_EXPIRY_SECS.to_string(),
+        ),
+        (
+            "aws.media_upload_url_expiry_secs",
+            DEFAULT_PRESIGNED_URL_EXPIRY_SECS.to_string(),
+        ),
+        (
+            "aws.media_upload_content_types",
+            DEFAULT_MEDIA_UPLOAD_CONTENT_TYPES.to_string(),
+        ),
+        (
+            "aws.media_upload_max_bytes",
+            DEFAULT_MEDIA_UPLOAD_MAX_BYTES.to_string(),
+        ),
+        (
+            "aws.media_multipart_max_age_secs",
+            DEFAULT_MEDIA_MULTIPART_MAX_AGE_SECS.to_string(),
+        ),
+        (
+            "aws.thumbnails_get_url_expiry_secs",
+            DEFAULT_PRESIGNED_URL_EXPIRY_SECS.to_string(),
+        ),
+        (
+            "aws.thumbnails_upload_url_expiry_secs",
+            DEFAULT_PRESIGNED_URL_EXPIRY_SECS.to_string(),
+        ),
+        (
+            "aws.thumbnails_upload_content_types",
+            DEFAULT_THUMBNAILS_UPLOAD_CONTENT_TYPES.to_string(),
+        ),
+        (
+            "aws.thumbnails_upload_max_bytes",
+            DEFAULT_MEDIA_UPLOAD_MAX_BYTES.to_string(),
+        ),
+        ("database.port", DEFAULT_DATABASE_PORT.to_string()),
+        ("database.dialect", DEFAULT_DATABASE_DIALECT.to_string()),
+        ("database.sslmode", DEFAULT_DATABASE_SSLMODE.to_string()),
+        (
+            "database.pool_max_size",
+            DEFAULT_DATABASE_POOL_MAX_SIZE.to_string(),
+        ),
+        (
+            "database.pool_connection_timeout_secs",
+            DEFAULT_DATABASE_POOL_CONNECTION_TIMEOUT_SECS.to_string(),
+        ),
+        (
+            "database.pool_idle_timeout_secs",
+            DEFAULT_DATABASE_POOL_IDLE_TIMEOUT_SECS.to_string(),
+        ),
+        (
+            "database.startup_timeout_secs",
+            DEFAULT_DATABASE_STARTUP_TIMEOUT_SECS.to_string(),
+        ),
+        (
+            "database.auto_migrate",
+            DEFAULT_DATABASE_AUTO_MIGRATE.to_string(),
+        ),
+        (
+            "database.replica_stickiness_secs",
+            DEFAULT_DATABASE_REPLICA_STICKINESS_SECS.to_string(),
+        ),
+        (
+            "jwt.token_timeout_secs",
+            jwt::DEFAULT_TIMEOUT_IN_SEC.to_string(),
+        ),
+        (
+            "http.swagger_ui_enabled",
+            DEFAULT_SWAGGER_UI_ENABLED.to_string(),
+        ),
+        (
+            "http.max_payload_bytes",
+            DEFAULT_MAX_PAYLOAD_BYTES.to_string(),
+        ),
+        (
+            "http.rate_limit_store",
+            DEFAULT_RATE_LIMIT_STORE.to_string(),
+        ),
+        (
+            "imgproc.animation_max_frames",
+            DEFAULT_ANIMATION_MAX_FRAMES.to_string(),
+        ),
+        (
+            "imgproc.animation_max_duration_ms",
+            DEFAULT_ANIMATION_MAX_DURATION_MS.to_string(),
+        ),
+        (
+            "imgproc.animation_max_bytes",
+            DEFAULT_ANIMATION_MAX_BYTES.to_string(),
+        ),
+        (
+            "imgproc.max_in_memory_bytes",
+            DEFAULT_MAX_IN_MEMORY_BYTES.to_string(),
+        ),
+        (
+            "imgproc.max_input_bytes",
+            DEFAULT_MAX_INPUT_BYTES.to_string(),
+        ),
+        ("imgproc.http_port", DEFAULT_IMGPROC_HTTP_PORT.to_string()),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_string(), value))
+    .collect()
+}
+
+/// The service loading its configuration, which decides the settings it requires
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Service {
+    Feed,
+    Users,
+    Imgproc,
+    // The migrate command of the services, which only connects to the database
+    Migrate,
+    // The cleanup-uploads command of the feed, which only connects to the media bucket
+    CleanupUploads,
+}
+
+impl Service {
+    fn required(&self) -> &'static [&'static str] {
+        match self {
+            Service::Feed => &[
+                "aws.region",
+                "aws.media_bucket",
+                "aws.thumbnails_base_url",
+                "aws.sqs_queue",
+                "database.host",
+                "database.name",
+                "database.username",
+                "database.password",
+                "jwt.secret",
+            ],
+            Service::Users => &[
+                "database.host",
+                "database.name",
+                "database.username",
+                "database.password",
+                "jwt.secret",
+            ],
+            Service::Imgproc => &[
+                "aws.region",
+                "aws.media_bucket",
+                "aws.thumbnails_bucket",
+                "aws.sqs_queue",
+                "database.host",
+                "database.name",
+                "database.username",
+                "database.password",
+            ],
+            Service::Migrate => CONNECTION_SETTINGS,
+            Service::CleanupUploads => &["aws.region", "aws.media_bucket"],
+        }
+    }
+}
+
+// Command line flags shared by every service
+#[derive(Parser, Clone, Default, Debug)]
+pub struct ConfigArgs {
+    /// TOML or YAML file with settings grouped by section, overridden by the environment
+    #[arg(long = "config", env = CONFIG_FILE, global = true, value_name = "PATH")]
+    pub config_file: Option<PathBuf>,
+    /// Override a setting, such as `--set database.host=localhost`
+    #[arg(long = "set", global = true, value_name = "SECTION.KEY=VALUE")]
+    pub overrides: Vec<String>,
+    /// Print the configuration with secrets redacted, then exit
+    #[arg(long, global = true)]
+    pub print_config: bool,
+}
+
+#[derive(Clone)]
+pub struct AwsConfig {
+    pub profile: String,
+    pub region: String,
+    pub media_bucket: String,
+    pub thumbnails_bucket: String,
+    pub thumbnails_base_url: String,
+    pub sqs_queue: String,
+    pub sqs_max_wait_time: Duration,
+    pub media_presign: PresignConfig,
+    pub thumbnails_presign: PresignConfig,
+    // Multipart uploads of media started longer ago are aborted by the cleanup job
+    pub media_multipart_max_age: Duration,
+    // S3 compatible server to use instead of AWS, such as MinIO
+    pub s3_endpoint: Option<Uri>,
+}
+
+/// How the urls of a bucket are presigned
+#[derive(Clone, Debug)]
+pub struct PresignConfig {
+    pub get_expires_in: Duration,
+    pub upload_expires_in: Duration,
+    // MIME types uploads may declare, the bucket takes no uploads when empty
+    pub upload_content_types: Vec<String>,
+    // Enforced by presigned POST policies and when completing multipart uploads,
+    // presigned PUTs can't limit sizes so imgproc deletes larger media
+    pub upload_max_bytes: u64,
+}
+
+impl Default for PresignConfig {
+    fn default() -> Self {
+        PresignConfig {
+            get_expires_in: Duration::from_secs(DEFAULT_PRESIGNED_URL_EXPIRY_SECS),
+            upload_expires_in: Duration::from_secs(DEFAULT_PRESIGNED_URL_EXPIRY_SECS),
+            upload_content_types: Vec::new(),
+            upload_max_bytes: 0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DatabaseConfig {
+    // Replaces the connection settings below when set
+    pub url: Option<Secret>,
+    pub host: String,
+    pub port: u16,
+    pub name: String,
+    pub username: String,
+    pub password: Secret,
+    pub dialect: String,
+    pub sslmode: String,
+    // CA bundle verifying the certificate of the server
+    pub sslrootcert: Option<String>,
+    pub pool_max_size: u32,
+    // Defaults to the max size
+    pub pool_min_idle: Option<u32>,
+    pub pool_connection_timeout: Duration,
+    pub pool_idle_timeout: Duration,
+    // How long services wait for the database to accept connections when starting
+    pub startup_timeout: Duration,
+    // Whether services run pending migrations when starting, otherwise they refuse to
+    // start until the schema is migrated
+    pub auto_migrate: bool,
+    // Read replica serving the read-only queries, with the pool settings of the primary
+    pub replica_url: Option<Secret>,
+    // How long the reads of a user go to the primary after their own writes, for the
+    // replica to catch up
+    pub replica_stickiness: Duration,
+}
+
+#[derive(Clone)]
+pub struct JwtConfig {
+    pub secret: Secret,
+    pub token_timeout: Duration,
+}
+
+#[derive(Clone)]
+pub struct HttpConfig {
+    pub swagger_ui_enabled: bool,
+    pub max_payload_bytes: usize,
+    // Either "memory" or "postgres", to share limits across replicas
+    pub rate_limit_store: String,
+    // Peers whose X-Forwarded-For names the client, any other peer is the client
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+#[derive(Clone)]
+pub struct TelemetryConfig {
+    // Traces are only exported when set
+    pub otlp_endpoint: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct ImgprocConfig {
+    pub animation_max_frames: usize,
+    pub animation_max_duration: Duration,
+    pub animation_max_bytes: usize,
+    pub max_in_memory_bytes: usize,
+    // Media larger than this is not processed at all
+    pub max_input_bytes: usize,
+    pub http_port: u16,
+}
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Clone)]
 pub struct Config {
-    pub aws_sqs_queue: String,
-    pub aws_sqs_max_wait_time: Duration,
-    pub aws_media_bucket: String,
-    pub aws_thumbnails_bucket: String,
-    pub aws_thumbnails_base_url: String,
-    #[serde(default = "gen_aws_default_profile")]
-    pub aws_profile: String,
-    pub aws_region: String,
-    pub database_username: String,
-    pub database_password: String,
-    pub database_name: String,
-    pub database_host: String,
-    #[serde(default = "gen_default_database_dialect")]
-    pub database_dialect: String,
-    pub jwt_secret: String,
-    pub jwt_token_timeout: Duration,
+    pub aws: AwsConfig,
+    pub database: DatabaseConfig,
+    pub jwt: JwtConfig,
+    pub http: HttpConfig,
+    pub telemetry: TelemetryConfig,
+    pub imgproc: ImgprocConfig,
+    // Settings it was built from, with secrets redacted
+    rendered: String,
+}
+
+/// A secret setting, reloaded while the service runs when read from a file. Clones share
+/// the value.
+#[derive(Clone, Default)]
+pub struct Secret {
+    value: Arc<RwLock<String>>,
+    file: Option<PathBuf>,
 }
 
-fn gen_aws_default_profile() -> String {
-    "default".into()
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Secret {
+            value: Arc::new(RwLock::new(value.into())),
+            file: None,
+        }
+    }
+
+    /// Current value of the secret
+    pub fn expose(&self) -> String {
+        self.value
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // Returns whether the value changed
+    fn reload(&self) -> Result<bool, String> {
+        let Some(path) = &self.file else {
+            return Ok(false);
+        };
+        let value = read_secret(path)?;
+
+        let mut current = self.value.write().unwrap_or_else(PoisonError::into_inner);
+        if *current == value {
+            return Ok(false);
+        }
+        *current = value;
+        Ok(true)
+    }
 }
 
-fn gen_default_database_dialect() -> String {
-    "postgres".into()
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret({})", REDACTED)
+    }
 }
 
-#[derive(Debug)]
-pub struct VarNotFound<'a>(&'a str);
+/// Every problem found while loading the configuration
+pub struct ConfigError(pub Vec<String>);
+
+// Services return it from main, which prints errors with Debug
+impl std::fmt::Debug for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
 
-impl Display for VarNotFound<'_> {
+impl Display for ConfigError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Config variable \"{}\" is required, but was not found",
-            self.0
-        )
+        write!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
     }
 }
-impl std::error::Error for VarNotFound<'_> {}
+impl std::error::Error for ConfigError {}
 
 impl Config {
-    pub async fn load_dotenv() -> Result<Config, Box<dyn std::error::Error>> {
-        let vars = spawn_blocking(init_dotenv).await??;
-
-        let default_timeout = format!("{}", jwt::DEFAULT_TIMEOUT_IN_SEC);
-        let timeout = vars
-            .get(JWT_TOKEN_TIMEOUT)
-            .unwrap_or(&default_timeout)
-            .parse::<u64>()
-            .expect("Failed to parse JWT_TOKEN_TIMEOUT from env");
-        let timeout = Duration::new(timeout, 0);
-
-        let default_max_wait_time = format!("{}", aws::sqs::DEFAULT_MAX_WAIT_TIME_IN_SEC);
-        let sqs_max_wait_time = vars
-            .get(AWS_SQS_MAX_WAIT_TIME_IN_SEC)
-            .unwrap_or(&default_max_wait_time)
-            .parse::<u64>()
-            .expect("Failed to parse AWS_SQS_MAX_WAIT_TIME_IN_SEC from env");
-        let sqs_max_wait_time = Duration::from_secs(sqs_max_wait_time);
-
-        Ok(Config {
-            aws_sqs_queue: vars
-                .get(AWS_SQS_QUEUE)
-                .ok_or(VarNotFound(AWS_SQS_QUEUE))?
-                .clone(),
-            aws_sqs_max_wait_time: sqs_max_wait_time,
-            aws_thumbnails_bucket: vars
-                .get(AWS_THUMBNAILS_BUCKET)
-                .ok_or(VarNotFound(AWS_THUMBNAILS_BUCKET))?
-                .clone(),
-            aws_thumbnails_base_url: vars
-                .get(AWS_THUMBNAILS_BASE_URL)
-                .ok_or(VarNotFound(AWS_THUMBNAILS_BASE_URL))?
-                .clone(),
-            aws_media_bucket: vars
-                .get(AWS_MEDIA_BUCKET)
-                .ok_or(VarNotFound(AWS_MEDIA_BUCKET))?
-                .clone(),
-            aws_profile: vars
-                .get(AWS_PROFILE)
-                .unwrap_or(&gen_aws_default_profile())
-                .clone(),
-            aws_region: vars.get(AWS_REGION).ok_or(VarNotFound(AWS_REGION))?.clone(),
-            database_host: vars
-                .get(POSTGRESS_HOST)
-                .ok_or(VarNotFound(POSTGRESS_HOST))?
-                .clone(),
-            database_name: vars
-                .get(POSTGRESS_DATABASE)
-                .ok_or(VarNotFound(POSTGRESS_DATABASE))?
-                .clone(),
-            database_username: vars
-                .get(POSTGRESS_USERNAME)
-                .ok_or(VarNotFound(POSTGRESS_USERNAME))?
-                .clone(),
-            database_password: vars
-                .get(POSTGRESS_PASSWORD)
-                .ok_or(VarNotFound(POSTGRESS_PASSWORD))?
-                .clone(),
-            database_dialect: gen_default_database_dialect(),
-            jwt_secret: vars.get(JWT_SECRET).ok_or(VarNotFound(JWT_SECRET))?.clone(),
-            jwt_token_timeout: timeout,
+    /// Loads the configuration of a service from, by increasing precedence, defaults,
+    /// the config file, the environment (and `.env` file) then `--set` flags. Empty values
+    /// count as unset.
+    pub async fn load(service: Service, args: &ConfigArgs) -> Result<Config, ConfigError> {
+        let args = args.clone();
+        // Settings may be read from files, while layering
+        let layers = spawn_blocking(move || {
+            // A missing .env file is fine, the environment may hold everything
+            dotenv::dotenv().ok();
+
+            let mut layers = Layers::new();
+            match args.config_file.as_deref().map(read_file) {
+                Some(Ok(file)) => layers.file(file),
+                Some(Err(err)) => layers.errors.push(err),
+                None => (),
+            }
+            layers.env(&std::env::vars().collect());
+            layers.overrides(&args.overrides);
+            layers
         })
+        .await
+        .map_err(|err| ConfigError(vec![err.to_string()]))?;
+
+        layers.build(service)
+    }
+
+    /// The settings of the configuration as TOML, with secrets redacted, for
+    /// `--print-config`
+    pub fn render(&self) -> &str {
+        &self.rendered
+    }
+
+    /// Reloads the secrets read from files whenever the files change, such as a rotated
+    /// database password or JWT key
+    pub fn watch_secrets(&self) -> std::io::Result<()> {
+        let secrets = [
+            ("database.url", self.database.url.as_ref()),
+            ("database.password", Some(&self.database.password)),
+            ("database.replica_url", self.database.replica_url.as_ref()),
+            ("jwt.secret", Some(&self.jwt.secret)),
+        ]
+        .into_iter()
+        .filter_map(|(key, secret)| Some((key, secret?)))
+        .filter(|(_, secret)| secret.file.is_some())
+        .map(|(key, secret)| (key, secret.clone()))
+        .collect::<Vec<_>>();
+
+        if secrets.is_empty() {
+            return Ok(());
+        }
+
+        std::thread::Builder::new()
+            .name("secret-watcher".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(SECRET_POLL_INTERVAL);
+                for (key, secret) in &secrets {
+                    match secret.reload() {
+                        Ok(true) => info!("reloaded {}", key),
+                        Ok(false) => (),
+                        Err(err) => warn!("failed to reload {}: {}", key, err),
+                    }
+                }
+            })
+            .map(|_| ())
     }
 }
 
-fn init_dotenv() -> Result<HashMap<String, String>, dotenv::Error> {
-    dotenv::dotenv()?;
-    Ok(dotenv::vars().collect::<HashMap<String, String>>())
+fn read_secret(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path)
+        .map(|value| value.trim_end_matches(['\r', '\n']).to_string())
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))
+}
+
+fn read_file(path: &Path) -> Result<serde_json::Value, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+
+    let parsed = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&content).map_err(|err| err.to_string()),
+        Some("yaml" | "yml") => serde_yaml::from_str(&content).map_err(|err| err.to_string()),
+        _ => Err("expected a .toml, .yaml or .yml file".to_string()),
+    };
+    parsed.map_err(|err| format!("Failed to parse {}: {}", path.display(), err))
+}
+
+/// Settings merged from every source, later sources overriding earlier ones
+struct Layers {
+    values: BTreeMap<String, String>,
+    // Files settings were last read from
+    files: BTreeMap<String, PathBuf>,
+    errors: Vec<String>,
+}
+
+impl Layers {
+    fn new() -> Self {
+        Layers {
+            values: defaults(),
+            files: BTreeMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: String, source: &str) {
+        let known = |key: &str| SETTINGS.iter().any(|setting| setting.key == key);
+
+        let file_key = key.strip_suffix(FILE_KEY_SUFFIX).filter(|key| known(key));
+        if !known(key) && file_key.is_none() {
+            self.errors
+                .push(format!("{}: unknown setting \"{}\"", source, key));
+            return;
+        }
+
+        // Empty values leave the value of lower layers, such as the default
+        if value.is_empty() {
+            return;
+        }
+
+        match file_key {
+            Some(key) => self.set_file(key, PathBuf::from(value), source),
+            None => {
+                self.values.insert(key.to_string(), value);
+                self.files.remove(key);
+            }
+        }
+    }
+
+    fn set_file(&mut self, key: &str, path: PathBuf, source: &str) {
+        match read_secret(&path) {
+            Ok(value) => {
+                self.values.insert(key.to_string(), value);
+                self.files.insert(key.to_string(), path);
+            }
+            Err(err) => self.errors.push(format!("{}: {}: {}", source, key, err)),
+        }
+    }
+
+    fn file(&mut self, file: serde_json::Value) {
+        let mut settings = Vec::new();
+        flatten("", file, &mut settings);
+        for (key, value) in settings {
+            match value {
+                Ok(value) => self.set(&key, value, "config file"),
+                Err(err) => self.errors.push(format!("config file: {}: {}", key, err)),
+            }
+        }
+    }
+
+    fn env(&mut self, vars: &HashMap<String, String>) {
+        for setting in SETTINGS {
+            let file_var = format!("{}{}", setting.env, FILE_SUFFIX);
+            let var = |name: &str| vars.get(name).filter(|value| !value.is_empty());
+            match (var(setting.env), var(&file_var)) {
+                (Some(_), Some(_)) => self.errors.push(format!(
+                    "environment: set either {} or {}",
+                    setting.env, file_var
+                )),
+                (Some(value), None) => self.set(setting.key, value.clone(), "environment"),
+                (None, Some(path)) => self.set_file(setting.key, PathBuf::from(path), &file_var),
+                (None, None) => (),
+            }
+        }
+    }
+
+    fn overrides(&mut self, overrides: &[String]) {
+        for setting in overrides {
+            match setting.split_once('=') {
+                Some((key, value)) => self.set(key.trim(), value.to_string(), "--set"),
+                None => self.errors.push(format!(
+                    "--set: expected SECTION.KEY=VALUE, got \"{}\"",
+                    setting
+                )),
+            }
+        }
+    }
+
+    fn build(&self, service: Service) -> Result<Config, ConfigError> {
+        let url_set = self
+            .values
+            .get("database.url")
+            .is_some_and(|url| !url.is_empty());
+        let sqlite = self
+            .values
+            .get("database.dialect")
+            .is_some_and(|dialect| dialect == "sqlite");
+        let required = service
+            .required()
+            .iter()
+            .copied()
+            .filter(|key| !(url_set && CONNECTION_SETTINGS.contains(key)))
+            .filter(|key| !(sqlite && SERVER_SETTINGS.contains(key)))
+            .collect();
+
+        let mut values = Values {
+            values: &self.values,
+            files: &self.files,
+            required,
+            errors: self.errors.clone(),
+        };
+
+        let config = Config {
+            aws: AwsConfig {
+                profile: values.string("aws.profile"),
+                region: values.string("aws.region"),
+                media_bucket: values.string("aws.media_bucket"),
+                thumbnails_bucket: values.string("aws.thumbnails_bucket"),
+                thumbnails_base_url: values.string("aws.thumbnails_base_url"),
+                sqs_queue: values.string("aws.sqs_queue"),
+                sqs_max_wait_time: Duration::from_secs(values.parse("aws.sqs_max_wait_time_secs")),
+                media_presign: values.presign("media"),
+                thumbnails_presign: values.presign("thumbnails"),
+                media_multipart_max_age: Duration::from_secs(
+                    values.parse("aws.media_multipart_max_age_secs"),
+                ),
+                s3_endpoint: values.parse_optional("aws.s3_endpoint"),
+            },
+            database: DatabaseConfig {
+                url: values.optional_secret("database.url"),
+                host: values.string("database.host"),
+                port: values.parse("database.port"),
+                name: values.string("database.name"),
+                username: values.string("database.username"),
+                password: values.secret("database.password"),
+                dialect: values.one_of("database.dialect", DIALECTS),
+                sslmode: values.one_of("database.sslmode", SSLMODES),
+                sslrootcert: values.optional("database.sslrootcert"),
+                pool_max_size: values.parse("database.pool_max_size"),
+                pool_min_idle: values.parse_optional("database.pool_min_idle"),
+                pool_connection_timeout: Duration::from_secs(
+                    values.parse("database.pool_connection_timeout_secs"),
+                ),
+                pool_idle_timeout: Duration::from_secs(
+                    values.parse("database.pool_idle_timeout_secs"),
+                ),
+                startup_timeout: Duration::from_secs(values.parse("database.startup_timeout_secs")),
+                auto_migrate: values.parse("database.auto_migrate"),
+                replica_url: values.optional_secret("database.replica_url"),
+                replica_stickiness: Duration::from_secs(
+                    values.parse("database.replica_stickiness_secs"),
+                ),
+            },
+            jwt: JwtConfig {
+                secret: values.secret("jwt.secret"),
+                token_timeout: Duration::from_secs(values.parse("jwt.token_timeout_secs")),
+            },
+            http: HttpConfig {
+                swagger_ui_enabled: values.parse("http.swagger_ui_enabled"),
+                max_payload_bytes: values.parse("http.max_payload_bytes"),
+                rate_limit_store: values.one_of("http.rate_limit_store", RATE_LIMIT_STORES),
+                trusted_proxies: values.networks("http.trusted_proxies"),
+            },
+            telemetry: TelemetryConfig {
+                otlp_endpoint: values.optional("telemetry.otlp_endpoint"),
+            },
+            imgproc: ImgprocConfig {
+                animation_max_frames: values.parse("imgproc.animation_max_frames"),
+                animation_max_duration: Duration::from_millis(
+                    values.parse("imgproc.animation_max_duration_ms"),
+                ),
+                animation_max_bytes: values.parse("imgproc.animation_max_bytes"),
+                max_in_memory_bytes: values.parse("imgproc.max_in_memory_bytes"),
+                max_input_bytes: values.parse("imgproc.max_input_bytes"),
+                http_port: values.parse("imgproc.http_port"),
+            },
+            rendered: self.render(),
+        };
+
+        // SQS rejects long polls of more than 20 seconds
+        if config.aws.sqs_max_wait_time
+            > Duration::from_secs(aws::sqs::DEFAULT_MAX_WAIT_TIME_IN_SEC)
+        {
+            values.errors.push(format!(
+                "aws.sqs_max_wait_time_secs ({}): must be at most {}",
+                AWS_SQS_MAX_WAIT_TIME_IN_SEC,
+                aws::sqs::DEFAULT_MAX_WAIT_TIME_IN_SEC
+            ));
+        }
+
+        values.check_presign("media", &config.aws.media_presign);
+        values.check_presign("thumbnails", &config.aws.thumbnails_presign);
+        if config.aws.media_multipart_max_age.is_zero() {
+            values.errors.push(format!(
+                "aws.media_multipart_max_age_secs ({}): must be at least 1",
+                AWS_MEDIA_MULTIPART_MAX_AGE
+            ));
+        }
+        if config
+            .aws
+            .s3_endpoint
+            .as_ref()
+            .is_some_and(|endpoint| endpoint.scheme().is_none() || endpoint.host().is_none())
+        {
+            values.errors.push(format!(
+                "aws.s3_endpoint ({}): must be an absolute url",
+                AWS_S3_ENDPOINT
+            ));
+        }
+
+        let database = &config.database;
+        if database.pool_max_size == 0 {
+            values.errors.push(format!(
+                "database.pool_max_size ({}): must be at least 1",
+                DATABASE_POOL_MAX_SIZE
+            ));
+        }
+        if database
+            .pool_min_idle
+            .is_some_and(|min_idle| min_idle > database.pool_max_size)
+        {
+            values.errors.push(format!(
+                "database.pool_min_idle ({}): must be at most database.pool_max_size",
+                DATABASE_POOL_MIN_IDLE
+            ));
+        }
+        if database.replica_url.is_some() && database.dialect != "postgres" {
+            values.errors.push(format!(
+                "database.replica_url ({}): only supported by the postgres dialect",
+                DATABASE_REPLICA_URL
+            ));
+        }
+
+        if values.errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(values.errors))
+        }
+    }
+
+    /// Renders the settings as a TOML config file, with secrets redacted and the files
+    /// settings were read from
+    fn render(&self) -> String {
+        let mut sections = toml::Table::new();
+        for setting in SETTINGS {
+            let Some(value) = self.values.get(setting.key) else {
+                continue;
+            };
+            let (section, key) = setting.key.split_once('.').unwrap_or(("", setting.key));
+
+            let (key, value) = if let Some(path) = self.files.get(setting.key) {
+                let path = path.display().to_string();
+                (
+                    format!("{}{}", key, FILE_KEY_SUFFIX),
+                    toml::Value::from(path),
+                )
+            } else {
+                (key.to_string(), render_value(setting, value))
+            };
+
+            sections
+                .entry(section)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .map(|section| section.insert(key, value));
+        }
+        toml::to_string(&sections).unwrap_or_default()
+    }
+}
+
+fn render_value(setting: &Setting, value: &str) -> toml::Value {
+    if setting.secret {
+        toml::Value::from(REDACTED)
+    } else if let Ok(value) = value.parse::<i64>() {
+        toml::Value::from(value)
+    } else if let Ok(value) = value.parse::<bool>() {
+        toml::Value::from(value)
+    } else {
+        toml::Value::from(value)
+    }
+}
+
+// Turns nested sections into `section.key` settings
+fn flatten(
+    prefix: &str,
+    value: serde_json::Value,
+    settings: &mut Vec<(String, Result<String, String>)>,
+) {
+    let value = match value {
+        serde_json::Value::Object(table) => {
+            for (key, value) in table {
+                let key = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(&key, value, settings);
+            }
+            return;
+        }
+        serde_json::Value::Null => return,
+        serde_json::Value::String(value) => Ok(value),
+        serde_json::Value::Bool(value) => Ok(value.to_string()),
+        serde_json::Value::Number(value) => Ok(value.to_string()),
+        serde_json::Value::Array(_) => Err("lists are not supported".to_string()),
+    };
+    settings.push((prefix.to_string(), value));
+}
+
+/// Reads typed settings, collecting every problem rather than stopping at the first
+struct Values<'a> {
+    values: &'a BTreeMap<String, String>,
+    files: &'a BTreeMap<String, PathBuf>,
+    required: Vec<&'static str>,
+    errors: Vec<String>,
+}
+
+impl Values<'_> {
+    fn env(key: &str) -> &'static str {
+        SETTINGS
+            .iter()
+            .find(|setting| setting.key == key)
+            .map(|setting| setting.env)
+            .unwrap_or_default()
+    }
+
+    fn optional(&mut self, key: &str) -> Option<String> {
+        self.values
+            .get(key)
+            .filter(|value| !value.is_empty())
+            .cloned()
+    }
+
+    fn string(&mut self, key: &str) -> String {
+        let value = self.optional(key);
+        if value.is_none() && self.required.contains(&key) {
+            self.errors
+                .push(format!("{} ({}): is required", key, Self::env(key)));
+        }
+        value.unwrap_or_default()
+    }
+
+    // Presigning settings of the bucket, named `aws.<bucket>_...`
+    fn presign(&mut self, bucket: &str) -> PresignConfig {
+        PresignConfig {
+            get_expires_in: Duration::from_secs(
+                self.parse(&format!("aws.{}_get_url_expiry_secs", bucket)),
+            ),
+            upload_expires_in: Duration::from_secs(
+                self.parse(&format!("aws.{}_upload_url_expiry_secs", bucket)),
+            ),
+            upload_content_types: self.list(&format!("aws.{}_upload_content_types", bucket)),
+            upload_max_bytes: self.parse(&format!("aws.{}_upload_max_bytes", bucket)),
+        }
+    }
+
+    fn check_presign(&mut self, bucket: &str, presign: &PresignConfig) {
+        // Responses listing presigned urls are cached for part of their minimum lifetime
+        let min_get_expiry = aws::s3::PRESIGNED_URL_MIN_LIFETIME * 2;
+        if !(min_get_expiry..=MAX_PRESIGNED_URL_EXPIRY).contains(&presign.get_expires_in) {
+            let key = format!("aws.{}_get_url_expiry_secs", bucket);
+            self.errors.push(format!(
+                "{} ({}): must be between {} and {}",
+                key,
+                Self::env(&key),
+                min_get_expiry.as_secs(),
+                MAX_PRESIGNED_URL_EXPIRY.as_secs()
+            ));
+        }
+        if !(Duration::from_secs(1)..=MAX_PRESIGNED_URL_EXPIRY).contains(&presign.upload_expires_in)
+        {
+            let key = format!("aws.{}_upload_url_expiry_secs", bucket);
+            self.errors.push(format!(
+                "{} ({}): must be between 1 and {}",
+                key,
+                Self::env(&key),
+                MAX_PRESIGNED_URL_EXPIRY.as_secs()
+            ));
+        }
+        if presign.upload_max_bytes == 0 {
+            let key = format!("aws.{}_upload_max_bytes", bucket);
+            self.errors
+                .push(format!("{} ({}): must be at least 1", key, Self::env(&key)));
+        }
+    }
+
+    // Comma separated values
+    fn list(&mut self, key: &str) -> Vec<String> {
+        self.string(key)
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    // Comma separated addresses or networks, such as `10.0.0.1,172.16.0.0/12`
+    fn networks(&mut self, key: &str) -> Vec<IpNet> {
+        let mut networks = Vec::new();
+        for value in self.list(key) {
+            match value
+                .parse::<IpNet>()
+                .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+            {
+                Ok(network) => networks.push(network),
+                Err(err) => self.errors.push(format!(
+                    "{} ({}): invalid address \"{}\": {}",
+                    key,
+                    Self::env(key),
+                    value,
+                    err
+                )),
+            }
+        }
+        networks
+    }
+
+    fn secret(&mut self, key: &str) -> Secret {
+        Secret {
+            file: self.files.get(key).cloned(),
+            ..Secret::new(self.string(key))
+        }
+    }
+
+    fn optional_secret(&mut self, key: &str) -> Option<Secret> {
+        self.optional(key)?;
+        Some(self.secret(key))
+    }
+
+    fn parse<T>(&mut self, key: &str) -> T
+    where
+        T: FromStr + Default,
+        T::Err: Display,
+    {
+        self.parse_optional(key).unwrap_or_default()
+    }
+
+    fn parse_optional<T>(&mut self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let value = self.optional(key)?;
+        match value.trim().parse() {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.errors.push(format!(
+                    "{} ({}): invalid value \"{}\": {}",
+                    key,
+                    Self::env(key),
+                    value,
+                    err
+                ));
+                None
+            }
+        }
+    }
+
+    fn one_of(&mut self, key: &str, choices: &[&str]) -> String {
+        let value = self.string(key);
+        if !choices.contains(&value.as_str()) {
+            self.errors.push(format!(
+                "{} ({}): expected one of {}, got \"{}\"",
+                key,
+                Self::env(key),
+                choices.join(", "),
+                value
+            ));
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let mut layers = Layers::new();
+        layers.file(
+            toml::from_str(
+                r#"
+                [database]
+                host = "file"
+                name = "feed"
+                username = "feed"
+                password = "secret"
+
+                [jwt]
+                secret = "secret"
+                token_timeout_secs = 60
+                "#,
+            )
+            .unwrap(),
+        );
+        layers.env(&vars(&[
+            (POSTGRESS_HOST, "env"),
+            (JWT_TOKEN_TIMEOUT, "120"),
+        ]));
+        layers.overrides(&["database.host=flag".to_string()]);
+
+        let config = layers.build(Service::Users).unwrap();
+        assert_eq!(config.database.host, "flag");
+        assert_eq!(config.database.name, "feed");
+        assert_eq!(config.jwt.token_timeout, Duration::from_secs(120));
+        assert_eq!(config.http.max_payload_bytes, DEFAULT_MAX_PAYLOAD_BYTES);
+
+        let rendered = config.render();
+        assert!(rendered.contains("host = \"flag\""));
+        assert!(rendered.contains("token_timeout_secs = 120"));
+        assert!(!rendered.contains("secret = \"secret\""));
+    }
+
+    #[test]
+    fn reads_and_reloads_settings_from_files() {
+        let path = std::env::temp_dir().join(format!("jwt-secret-{}", std::process::id()));
+        std::fs::write(&path, "first\n").unwrap();
+
+        let mut layers = Layers::new();
+        layers.env(&vars(&[
+            (POSTGRESS_HOST, "localhost"),
+            (POSTGRESS_DATABASE, "feed"),
+            (POSTGRESS_USERNAME, "feed"),
+            (POSTGRESS_PASSWORD, "password"),
+            ("JWT_SECRET_FILE", path.to_str().unwrap()),
+        ]));
+
+        let config = layers.build(Service::Users).unwrap();
+        assert_eq!(config.jwt.secret.expose(), "first");
+        assert!(layers
+            .render()
+            .contains(&format!("secret_file = \"{}\"", path.display())));
+
+        let shared = config.clone();
+        std::fs::write(&path, "second").unwrap();
+        assert!(config.jwt.secret.reload().unwrap());
+        assert_eq!(shared.jwt.secret.expose(), "second");
+
+        std::fs::remove_file(&path).unwrap();
+        layers.env(&vars(&[
+            (JWT_SECRET, "secret"),
+            ("JWT_SECRET_FILE", path.to_str().unwrap()),
+        ]));
+        let ConfigError(problems) = layers.build(Service::Users).err().unwrap();
+        assert_eq!(
+            problems,
+            vec!["environment: set either JWT_SECRET or JWT_SECRET_FILE".to_string()]
+        );
+    }
+
+    #[test]
+    fn empty_values_are_unset() {
+        let mut layers = Layers::new();
+        layers.file(
+            toml::from_str(
+                r#"
+                [database]
+                host = "file"
+                name = "feed"
+                username = "feed"
+                password = "secret"
+
+                [jwt]
+                token_timeout_secs = 60
+                "#,
+            )
+            .unwrap(),
+        );
+        layers.env(&vars(&[
+            (POSTGRESS_HOST, ""),
+            (JWT_TOKEN_TIMEOUT, ""),
+            (MAX_PAYLOAD_BYTES, ""),
+            (JWT_SECRET, "secret"),
+            ("JWT_SECRET_FILE", ""),
+        ]));
+        layers.overrides(&["database.name=".to_string()]);
+
+        let config = layers.build(Service::Users).unwrap();
+        assert_eq!(config.database.host, "file");
+        assert_eq!(config.database.name, "feed");
+        assert_eq!(config.jwt.token_timeout, Duration::from_secs(60));
+        assert_eq!(config.http.max_payload_bytes, DEFAULT_MAX_PAYLOAD_BYTES);
+        assert_eq!(config.jwt.secret.expose(), "secret");
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let mut layers = Layers::new();
+        layers.env(&vars(&[
+            (POSTGRESS_HOST, "localhost"),
+            (MAX_PAYLOAD_BYTES, "lots"),
+            (RATE_LIMIT_STORE, "redis"),
+            (TRUSTED_PROXIES, "10.0.0.1, proxy"),
+        ]));
+        layers.overrides(&["database.schema=feed".to_string()]);
+
+        let ConfigError(problems) = layers.build(Service::Users).err().unwrap();
+        assert_eq!(problems.len(), 8, "{:?}", problems);
+        assert!(problems.contains(&"--set: unknown setting \"database.schema\"".to_string()));
+        assert!(problems.contains(&"jwt.secret (JWT_SECRET): is required".to_string()));
+
+        // Users never touch AWS, while imgproc needs its queue
+        assert!(!problems.iter().any(|problem| problem.starts_with("aws.")));
+        let ConfigError(problems) = layers.build(Service::Imgproc).err().unwrap();
+        assert!(problems.contains(&"aws.sqs_queue (AWS_SQS_QUEUE): is required".to_string()));
+    }
+
+    #[test]
+    fn cleanup_only_needs_the_media_bucket() {
+        let mut layers = Layers::new();
+        layers.env(&vars(&[
+            (AWS_REGION, "us-east-1"),
+            (AWS_MEDIA_BUCKET, "media"),
+        ]));
+
+        let config = layers.build(Service::CleanupUploads).unwrap();
+        assert_eq!(config.aws.media_bucket, "media");
+
+        let ConfigError(problems) = layers.build(Service::Feed).err().unwrap();
+        assert!(problems.contains(&"jwt.secret (JWT_SECRET): is required".to_string()));
+    }
+
+    #[test]
+    fn sqlite_needs_no_server_settings() {
+        let mut layers = Layers::new();
+        layers.overrides(&[
+            "database.dialect=sqlite".to_string(),
+            "database.name=feed.db".to_string(),
+            "jwt.secret=secret".to_string(),
+        ]);
+
+        let config = layers.build(Service::Users).unwrap();
+        assert_eq!(config.database.dialect, "sqlite");
+
+        layers.overrides(&["database.dialect=mysql".to_string()]);
+        let ConfigError(problems) = layers.build(Service::Users).err().unwrap();
+        assert!(problems.contains(
+            &"database.dialect (DATABASE_DIALECT): expected one of postgres, sqlite, got \"mysql\""
+                .to_string()
+        ));
+    }
 }
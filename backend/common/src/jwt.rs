@@ -8,6 +8,7 @@ use std::{
 use jsonwebtoken as jwt;
 use tokio::task::JoinError;
 use serde::{de, Deserialize, Serialize};
+use tracing::info_span;
 
 use crate::config::Config;
 
@@ -72,8 +73,10 @@ pub async fn generate_jwt<T>(data: T, config: Arc<Config>) -> Result<String, JWT
 where
     T: Serialize + de::DeserializeOwned + Send + 'static,
 {
+    let span = info_span!("jwt.sign");
     let result =
-        tokio::task::spawn_blocking(move || generate_jwt_helper(data, config)).await??;
+        tokio::task::spawn_blocking(move || span.in_scope(|| generate_jwt_helper(data, config)))
+            .await??;
 
     Ok(result)
 }
@@ -84,7 +87,7 @@ where
 {
     let exp = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)?
-        .add(config.jwt_token_timeout)
+        .add(config.jwt.token_timeout)
         .as_secs();
 
     let exp = exp as usize;
@@ -94,7 +97,7 @@ where
     let result = jwt::encode(
         &jwt::Header::default(),
         &claim,
-        &jwt::EncodingKey::from_secret(config.jwt_secret.as_bytes()),
+        &jwt::EncodingKey::from_secret(config.jwt.secret.expose().as_bytes()),
     )?;
 
     Ok(result)
@@ -104,10 +107,12 @@ pub async fn verify_jwt<T>(token: String, config: Arc<Config>) -> Result<T, JWTE
 where
     T: Serialize + de::DeserializeOwned + Send + 'static,
 {
+    let span = info_span!("jwt.verify");
     let result = tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
         jwt::decode::<JWTClaims<T>>(
             token.as_str(),
-            &jwt::DecodingKey::from_secret(config.jwt_secret.as_bytes()),
+            &jwt::DecodingKey::from_secret(config.jwt.secret.expose().as_bytes()),
             &jwt::Validation::default(),
         )
         .map(|v| v.claims.data)
@@ -1,7 +1,11 @@
 pub mod aws;
+pub mod commands;
 pub mod config;
 pub mod jwt;
+pub mod metrics;
 pub mod passwords;
+pub mod telemetry;
+pub mod transform;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,45 @@
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use prometheus::{register_histogram_vec, Encoder, HistogramVec, TextEncoder};
+
+use tracing::{info_span, Instrument};
+
+pub use prometheus::TEXT_FORMAT;
+
+static S3_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "s3_request_duration_seconds",
+        "Duration of S3 requests",
+        &["bucket", "operation", "outcome"]
+    )
+    .expect("Failed to register s3_request_duration_seconds")
+});
+
+/// Records the duration of an S3 request, labeled with whether it succeeded, and traces
+/// it in a span
+pub async fn observe_s3<T, E>(
+    bucket: &str,
+    operation: &str,
+    request: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let start = Instant::now();
+    let result = request
+        .instrument(info_span!("s3.request", bucket, operation))
+        .await;
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+
+    S3_REQUEST_DURATION
+        .with_label_values(&[bucket, operation, outcome])
+        .observe(start.elapsed().as_secs_f64());
+
+    result
+}
+
+/// Renders every registered metric in the Prometheus text format
+pub fn render() -> Result<String, prometheus::Error> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
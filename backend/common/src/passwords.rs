@@ -1,4 +1,7 @@
-use std::{fmt::Display, sync::Arc};
+use std::{
+    fmt::Display,
+    sync::{Arc, LazyLock},
+};
 
 use argon2::{
     password_hash::{
@@ -10,6 +13,15 @@ use argon2::{
 
 use tokio::task::{spawn_blocking, JoinError};
 
+// Hash of no password in particular, made with the parameters of the stored ones
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(b"", &salt)
+        .map(|hash| hash.to_string())
+        .unwrap_or_default()
+});
+
 #[derive(Debug)]
 pub enum PasswordError {
     HashError(PasswordHashError),
@@ -71,3 +83,14 @@ pub async fn compare_with_hashed_password(
 
     Ok(())
 }
+
+/// Takes as long as comparing a password with a stored hash, for accounts without one,
+/// so the time of a failed login doesn't tell whether the account exists
+pub async fn compare_with_dummy_password(plain_text_password: Arc<String>) {
+    let _ = spawn_blocking(move || {
+        PasswordHash::new(&DUMMY_HASH).map(|parsed_hash| {
+            Argon2::default().verify_password(plain_text_password.as_bytes(), &parsed_hash)
+        })
+    })
+    .await;
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use opentelemetry::global;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::Context;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+
+/// Keeps spans exported until dropped, when pending spans are flushed
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+/// Installs the global subscriber, writing JSON logs filtered by `RUST_LOG` (default: info)
+/// and exporting spans to the OTLP/HTTP collector at `otlp_endpoint` when given. Events of
+/// the `log` crate are recorded as well.
+pub fn init(
+    service_name: &'static str,
+    otlp_endpoint: Option<&str>,
+) -> Result<Telemetry, Box<dyn Error>> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let provider = match otlp_endpoint {
+        Some(endpoint) => {
+            let exporter = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+                .build()?;
+
+            Some(
+                SdkTracerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(Resource::builder().with_service_name(service_name).build())
+                    .build(),
+            )
+        }
+        None => None,
+    };
+
+    let otel = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(service_name)));
+
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with(
+            fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_span_list(false),
+        )
+        .with(otel)
+        .try_init()?;
+
+    Ok(Telemetry { provider })
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(err) = provider.shutdown() {
+                eprintln!("Failed to flush spans: {}", err);
+            }
+        }
+    }
+}
+
+/// Trace context of the current span, to continue the trace in another service
+pub fn current_context() -> HashMap<String, String> {
+    let mut carrier = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Span::current().context(), &mut carrier)
+    });
+    carrier
+}
+
+/// Makes `span` continue the trace whose context was received in `carrier`, if any
+pub fn set_remote_parent(span: &Span, carrier: &HashMap<String, String>) {
+    if carrier.contains_key(TRACEPARENT) {
+        let context: Context =
+            global::get_text_map_propagator(|propagator| propagator.extract(carrier));
+        if let Err(err) = span.set_parent(context) {
+            tracing::debug!("{}", err);
+        }
+    }
+}
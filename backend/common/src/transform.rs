@@ -0,0 +1,92 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+pub const MAX_TRANSFORMATIONS: usize = 16;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FlipDirection {
+    Horizontal,
+    Vertical,
+}
+
+/// A single edit applied to the original media of a feed item
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Transformation {
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    Rotate {
+        degrees: u32,
+    },
+    Flip {
+        direction: FlipDirection,
+    },
+    // Added to every channel, from -255 to 255
+    Brightness {
+        value: i32,
+    },
+    // Percentage change, from -100 to 100
+    Contrast {
+        value: f32,
+    },
+    Grayscale,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidTransformation(pub &'static str);
+
+impl Transformation {
+    pub fn validate(&self) -> Result<(), InvalidTransformation> {
+        match self {
+            Transformation::Crop { width, height, .. } => {
+                if *width == 0 || *height == 0 {
+                    return Err(InvalidTransformation("Crop width and height must be positive"));
+                }
+            }
+            Transformation::Rotate { degrees } => {
+                if !matches!(degrees, 90 | 180 | 270) {
+                    return Err(InvalidTransformation("Rotation must be 90, 180 or 270 degrees"));
+                }
+            }
+            Transformation::Brightness { value } => {
+                if !(-255..=255).contains(value) {
+                    return Err(InvalidTransformation("Brightness must be between -255 and 255"));
+                }
+            }
+            Transformation::Contrast { value } => {
+                if !(-100.0..=100.0).contains(value) {
+                    return Err(InvalidTransformation("Contrast must be between -100 and 100"));
+                }
+            }
+            Transformation::Flip { .. } | Transformation::Grayscale => {}
+        }
+        Ok(())
+    }
+}
+
+pub fn validate_transformations(
+    transformations: &[Transformation],
+) -> Result<(), InvalidTransformation> {
+    if transformations.is_empty() {
+        return Err(InvalidTransformation("At least one transformation is required"));
+    }
+    if transformations.len() > MAX_TRANSFORMATIONS {
+        return Err(InvalidTransformation("Too many transformations"));
+    }
+    transformations.iter().try_for_each(Transformation::validate)
+}
+
+impl Display for InvalidTransformation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidTransformation {}
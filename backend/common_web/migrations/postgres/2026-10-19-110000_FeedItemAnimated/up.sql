@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE feeditems ADD COLUMN animated BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX feeditems_updated_at;
+
+DROP TABLE feeditems;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- SQLite only generates ids for the primary key, so email is unique instead
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    email VARCHAR UNIQUE NOT NULL,
+    password_hash VARCHAR,
+    created_at TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+)
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE login_failures;
+DROP TABLE rate_limits;
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE rate_limits (
+    key VARCHAR PRIMARY KEY NOT NULL,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE login_failures (
+    account VARCHAR PRIMARY KEY NOT NULL,
+    failures INTEGER NOT NULL,
+    locked_until TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,147 @@
+use actix_web::body::BoxBody;
+use actix_web::http::header::{
+    CacheControl, CacheDirective, ContentType, ETag, EntityTag, Header, IfNoneMatch, VARY,
+};
+use actix_web::{HttpRequest, HttpResponse, Responder, ResponseError};
+
+use log::error;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::messages::ErrMessage;
+
+pub type CachedMessage<T> = Result<Cached<T>, ErrMessage>;
+
+// Bytes of the body digest kept in ETags
+const ETAG_HASH_BYTES: usize = 16;
+
+/// A body tagged with a strong ETag of its JSON, answered with `304 Not Modified` when
+/// the `If-None-Match` of the request holds the tag
+pub struct Cached<T: Serialize> {
+    body: T,
+    version: Option<String>,
+    cache_control: Vec<CacheDirective>,
+    // Whether the body depends on the user
+    private: bool,
+}
+
+impl<T: Serialize> Cached<T> {
+    /// Cached by the client of the user only, for `max_age` seconds
+    pub fn private(body: T, max_age: u32) -> Self {
+        Cached {
+            body,
+            version: None,
+            cache_control: vec![CacheDirective::Private, CacheDirective::MaxAge(max_age)],
+            private: true,
+        }
+    }
+
+    /// Cached by clients and shared caches for `max_age` seconds
+    pub fn public(body: T, max_age: u32) -> Self {
+        Cached {
+            body,
+            version: None,
+            cache_control: vec![CacheDirective::Public, CacheDirective::MaxAge(max_age)],
+            private: false,
+        }
+    }
+
+    /// Prefixes the ETag with the version of the resource, the part preconditions of
+    /// writes compare with `etag_version`
+    pub fn versioned(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+}
+
+/// The version of the resource an ETag names, the whole tag unless it is versioned
+pub fn etag_version(etag: &EntityTag) -> &str {
+    etag.tag()
+        .split_once('.')
+        .map_or(etag.tag(), |(version, _)| version)
+}
+
+impl<T: Serialize> Responder for Cached<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match serde_json::to_vec(&self.body) {
+            Ok(body) => body,
+            Err(err) => {
+                error!("json: {}", err);
+                return ErrMessage::InternalServerError.error_response();
+            }
+        };
+
+        let hash = Sha256::digest(&body)[..ETAG_HASH_BYTES]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        let etag = EntityTag::new_strong(match self.version {
+            Some(version) => format!("{}.{}", version, hash),
+            None => hash,
+        });
+
+        // If-None-Match uses the weak comparison
+        let not_modified = match IfNoneMatch::parse(req) {
+            Ok(IfNoneMatch::Any) => true,
+            Ok(IfNoneMatch::Items(etags)) => etags.iter().any(|tag| tag.weak_eq(&etag)),
+            Err(_) => false,
+        };
+
+        let mut res = if not_modified {
+            HttpResponse::NotModified()
+        } else {
+            HttpResponse::Ok()
+        };
+        res.insert_header(ETag(etag))
+            .insert_header(CacheControl(self.cache_control));
+        if self.private {
+            res.insert_header((VARY, "Authorization"));
+        }
+
+        if not_modified {
+            res.finish()
+        } else {
+            res.content_type(ContentType::json()).body(body)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::header::{CACHE_CONTROL, ETAG, IF_NONE_MATCH};
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    #[actix_web::test]
+    async fn answers_not_modified_for_the_same_body() {
+        let req = TestRequest::default().to_http_request();
+        let res = Cached::private(vec!["item"], 30)
+            .versioned("1-2")
+            .respond_to(&req);
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(CACHE_CONTROL).unwrap(),
+            "private, max-age=30"
+        );
+        let etag = res.headers().get(ETAG).unwrap().clone();
+        assert!(etag.to_str().unwrap().starts_with("\"1-2."));
+
+        let req = TestRequest::default()
+            .insert_header((IF_NONE_MATCH, etag.clone()))
+            .to_http_request();
+        let res = Cached::private(vec!["item"], 30)
+            .versioned("1-2")
+            .respond_to(&req);
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers().get(ETAG).unwrap(), &etag);
+
+        let res = Cached::private(vec!["changed"], 30)
+            .versioned("1-2")
+            .respond_to(&req);
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -1,31 +1,601 @@
 use std::error::Error;
+use std::future::Future;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use actix_web::cookie::{time::Duration as CookieDuration, Cookie};
+use actix_web::{web, HttpRequest};
+
+use bb8::{Builder, ManageConnection, Pool, PooledConnection, RunError, State};
+#[cfg(feature = "sqlite")]
+use diesel::SqliteConnection;
 use diesel::{
-    r2d2::{ConnectionManager, Pool},
-    PgConnection
+    connection::{Connection, SimpleConnection},
+    result::ConnectionError,
+    MultiConnection, PgConnection,
 };
+use diesel_async::pooled_connection::PoolError;
+#[cfg(feature = "sqlite")]
+use diesel_async::sync_connection_wrapper::SyncConnectionWrapper;
+use diesel_async::{AsyncPgConnection, SimpleAsyncConnection};
+use futures_util::future::BoxFuture;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use postgres_openssl::MakeTlsConnector;
+use tokio_postgres::config::SslMode;
+
+use common::config::{Config, DatabaseConfig};
+
+use crate::messages::ErrMessage;
+use crate::migrations;
+
+use log::warn;
+use tracing::{info_span, Instrument};
+
+pub const POSTGRES: &str = "postgres";
+pub const SQLITE: &str = "sqlite";
+
+// Characters of url components kept as is, the unreserved ones of RFC 3986
+const COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+#[cfg(feature = "sqlite")]
+const SQLITE_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A blocking connection to the database of the dialect selected by `DATABASE_DIALECT`,
+/// used to run migrations
+#[derive(MultiConnection)]
+pub enum DbConnection {
+    Postgres(PgConnection),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteConnection),
+}
+
+/// A connection of the pool. Queries are written once for every backend with
+/// `with_connection!`, those using features of a single one get its connection with
+/// `postgres()`.
+#[allow(clippy::large_enum_variant)]
+pub enum AsyncDbConnection {
+    Postgres(AsyncPgConnection),
+    // SQLite has no async driver, its queries run on the blocking thread pool
+    #[cfg(feature = "sqlite")]
+    Sqlite(SyncConnectionWrapper<SqliteConnection>),
+}
+
+impl AsyncDbConnection {
+    /// The connection to Postgres, for row locks and upserts
+    pub fn postgres(&mut self) -> Option<&mut AsyncPgConnection> {
+        match self {
+            AsyncDbConnection::Postgres(conn) => Some(conn),
+            #[cfg(feature = "sqlite")]
+            AsyncDbConnection::Sqlite(_) => None,
+        }
+    }
+}
+
+// Runs the body with the connection of the backend as `$conn`
+macro_rules! with_connection {
+    ($db_conn:expr, |$conn:ident| $body:expr) => {
+        match $db_conn {
+            $crate::database::AsyncDbConnection::Postgres($conn) => $body,
+            #[cfg(feature = "sqlite")]
+            $crate::database::AsyncDbConnection::Sqlite($conn) => $body,
+        }
+    };
+}
+pub(crate) use with_connection;
+
+/// Opens connections with the current database password, so the pool picks up a rotated
+/// password for its new connections
+#[derive(Clone)]
+pub struct DBConnManager {
+    config: DatabaseConfig,
+}
+
+impl DBConnManager {
+    fn new(config: &DatabaseConfig) -> Result<Self, Box<dyn Error>> {
+        match config.dialect.as_str() {
+            POSTGRES => {}
+            #[cfg(feature = "sqlite")]
+            SQLITE => {}
+            dialect => {
+                return Err(format!(
+                    "Database dialect \"{}\" is not supported, it may need a cargo feature of the same name",
+                    dialect
+                )
+                .into())
+            }
+        }
+
+        Ok(DBConnManager {
+            config: config.clone(),
+        })
+    }
+
+    fn url(&self) -> String {
+        let config = &self.config;
+        if let Some(url) = &config.url {
+            return url.expose();
+        }
+        // SQLite databases are files
+        if config.dialect == SQLITE {
+            return config.name.clone();
+        }
+
+        let encode = |component: &str| utf8_percent_encode(component, COMPONENT).to_string();
+        let mut url = format!(
+            "{db_type}://{username}:{password}@{host}:{port}/{database_name}?sslmode={sslmode}",
+            db_type = config.dialect,
+            username = encode(&config.username),
+            password = encode(&config.password.expose()),
+            host = config.host,
+            port = config.port,
+            database_name = encode(&config.name),
+            sslmode = config.sslmode,
+        );
+        if let Some(sslrootcert) = &config.sslrootcert {
+            url.push_str(&format!("&sslrootcert={}", encode(sslrootcert)));
+        }
+        url
+    }
+
+    // Every connection to an in-memory SQLite database opens a database of its own
+    #[cfg(feature = "sqlite")]
+    fn in_memory(&self) -> bool {
+        self.config.dialect == SQLITE && self.url().contains(":memory:")
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn establish_sqlite(&self) -> Result<SqliteConnection, PoolError> {
+        let mut conn =
+            SqliteConnection::establish(&self.url()).map_err(PoolError::ConnectionError)?;
+        // Waits for the writes of other connections instead of failing
+        conn.batch_execute(&format!(
+            "PRAGMA busy_timeout = {}",
+            SQLITE_BUSY_TIMEOUT.as_millis()
+        ))
+        .map_err(PoolError::QueryError)?;
+        Ok(conn)
+    }
+
+    fn establish(&self) -> Result<DbConnection, PoolError> {
+        match self.config.dialect.as_str() {
+            #[cfg(feature = "sqlite")]
+            SQLITE => self.establish_sqlite().map(DbConnection::Sqlite),
+            _ => PgConnection::establish(&self.url())
+                .map(DbConnection::Postgres)
+                .map_err(PoolError::ConnectionError),
+        }
+    }
+
+    // The database may still be starting along with the service, so connecting is
+    // retried until the startup timeout
+    fn wait_for_database(&self) -> Result<DbConnection, PoolError> {
+        let deadline = Instant::now() + self.config.startup_timeout;
+        let mut delay = Duration::from_millis(250);
+
+        loop {
+            match self.establish() {
+                Ok(conn) => return Ok(conn),
+                Err(err) if Instant::now() + delay < deadline => {
+                    warn!("database is not ready, retrying in {:?}: {}", delay, err);
+                    sleep(delay);
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn connect_postgres(&self) -> Result<AsyncPgConnection, ConnectionError> {
+        let bad_connection = |err: &dyn Error| ConnectionError::BadConnection(err.to_string());
+
+        let (mut pg_config, sslmode, sslrootcert) = match &self.config.url {
+            Some(url) => {
+                let (url, sslmode, sslrootcert) = split_tls_params(&url.expose());
+                let pg_config = url
+                    .parse::<tokio_postgres::Config>()
+                    .map_err(|err| bad_connection(&err))?;
+                (
+                    pg_config,
+                    sslmode.unwrap_or("prefer".to_string()),
+                    sslrootcert,
+                )
+            }
+            None => {
+                let config = &self.config;
+                let mut pg_config = tokio_postgres::Config::new();
+                pg_config
+                    .host(&config.host)
+                    .port(config.port)
+                    .user(&config.username)
+                    .password(config.password.expose())
+                    .dbname(&config.name);
+                (
+                    pg_config,
+                    config.sslmode.clone(),
+                    config.sslrootcert.clone(),
+                )
+            }
+        };
+
+        pg_config.ssl_mode(match sslmode.as_str() {
+            "disable" => SslMode::Disable,
+            "allow" | "prefer" => SslMode::Prefer,
+            _ => SslMode::Require,
+        });
+        let tls =
+            tls_connector(&sslmode, sslrootcert.as_deref()).map_err(|err| bad_connection(&err))?;
+
+        let (client, connection) = pg_config
+            .connect(tls)
+            .await
+            .map_err(|err| bad_connection(&err))?;
+        AsyncPgConnection::try_from_client_and_connection(client, connection).await
+    }
+}
+
+impl ManageConnection for DBConnManager {
+    type Connection = AsyncDbConnection;
+    type Error = PoolError;
+
+    async fn connect(&self) -> Result<AsyncDbConnection, PoolError> {
+        match self.config.dialect.as_str() {
+            #[cfg(feature = "sqlite")]
+            SQLITE => {
+                let manager = self.clone();
+                web::block(move || manager.establish_sqlite())
+                    .await
+                    .map_err(|err| {
+                        PoolError::ConnectionError(ConnectionError::BadConnection(err.to_string()))
+                    })?
+                    .map(|conn| AsyncDbConnection::Sqlite(SyncConnectionWrapper::new(conn)))
+            }
+            _ => self
+                .connect_postgres()
+                .await
+                .map(AsyncDbConnection::Postgres)
+                .map_err(PoolError::ConnectionError),
+        }
+    }
+
+    async fn is_valid(&self, conn: &mut AsyncDbConnection) -> Result<(), PoolError> {
+        with_connection!(conn, |conn| conn.batch_execute("SELECT 1").await)
+            .map_err(PoolError::QueryError)
+    }
+
+    fn has_broken(&self, _conn: &mut AsyncDbConnection) -> bool {
+        false
+    }
+}
+
+/// Where a read is served from
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ReadFrom {
+    Primary,
+    // May lag behind the primary, which serves these reads when no replica is configured
+    Replica,
+}
+
+/// The connection pool of a service, with the pool of its read replica if any
+#[derive(Clone)]
+pub struct DBConnPool {
+    pool: Pool<DBConnManager>,
+    replica: Option<Pool<DBConnManager>>,
+    max_size: u32,
+}
+
+impl DBConnPool {
+    /// A connection to the primary
+    pub async fn get(&self) -> Result<PooledConnection<'_, DBConnManager>, RunError<PoolError>> {
+        self.pool.get().await
+    }
+
+    pub async fn read(
+        &self,
+        read_from: ReadFrom,
+    ) -> Result<PooledConnection<'_, DBConnManager>, RunError<PoolError>> {
+        match (read_from, &self.replica) {
+            (ReadFrom::Replica, Some(replica)) => replica.get().await,
+            _ => self.pool.get().await,
+        }
+    }
+
+    pub fn has_replica(&self) -> bool {
+        self.replica.is_some()
+    }
+
+    pub fn state(&self) -> State {
+        self.pool.state()
+    }
+
+    pub fn max_size(&self) -> u32 {
+        self.max_size
+    }
+}
+
+/// Connects to the database, then runs the pending migrations, or checks none is pending
+/// with auto-migration disabled, before handing out connections
+pub async fn create_db_conn_pool(config: &Config) -> Result<DBConnPool, Box<dyn Error>> {
+    let database = &config.database;
+    let manager = DBConnManager::new(database)?;
+
+    // Migrations run on a blocking connection of their own
+    let migrate = if database.auto_migrate {
+        migrations::run_pending
+    } else {
+        migrations::ensure_up_to_date
+    };
+    let _conn = web::block({
+        let manager = manager.clone();
+        move || {
+            let mut conn = manager.wait_for_database()?;
+            migrate(&mut conn)?;
+            Ok::<_, Box<dyn Error + Send + Sync>>(conn)
+        }
+    })
+    .await?
+    .map_err(|err| err as Box<dyn Error>)?;
+
+    #[cfg(feature = "sqlite")]
+    if let (true, DbConnection::Sqlite(conn)) = (manager.in_memory(), _conn) {
+        // Keeps the migrated connection, and its database, open
+        let pool = Pool::builder()
+            .max_size(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .build_unchecked(manager);
+        pool.add(AsyncDbConnection::Sqlite(SyncConnectionWrapper::new(conn)))
+            .map_err(|_| "The in-memory database could not be added to the pool")?;
+        return Ok(DBConnPool {
+            pool,
+            replica: None,
+            max_size: 1,
+        });
+    }
+
+    let pool = pool_builder(database).build(manager).await?;
+
+    // The replica is neither migrated nor waited for, the readiness checks report it
+    // until it accepts connections
+    let replica = match &database.replica_url {
+        Some(url) => {
+            let manager = DBConnManager::new(&DatabaseConfig {
+                url: Some(url.clone()),
+                ..database.clone()
+            })?;
+            Some(pool_builder(database).build_unchecked(manager))
+        }
+        None => None,
+    };
+
+    Ok(DBConnPool {
+        pool,
+        replica,
+        max_size: database.pool_max_size,
+    })
+}
+
+fn pool_builder(database: &DatabaseConfig) -> Builder<DBConnManager> {
+    Pool::builder()
+        .max_size(database.pool_max_size)
+        .min_idle(database.pool_min_idle)
+        .connection_timeout(database.pool_connection_timeout)
+        .idle_timeout(Some(database.pool_idle_timeout))
+}
+
+/// Cookie holding when the client last wrote, in milliseconds since the epoch
+pub const LAST_WRITE_COOKIE: &str = "last_write";
+
+/// Keeps the reads of clients that wrote lately on the primary until the replica has
+/// caught up with their writes. The time of the last write travels with the client, so
+/// every instance of the service routes its reads alike. The window is per cookie jar
+/// rather than per user: clients that drop cookies, such as most bearer-token API
+/// clients, or other devices of the same user may read from the replica right after a
+/// write and miss it.
+pub struct RecentWrites {
+    stickiness: Duration,
+}
+
+impl RecentWrites {
+    pub fn new(stickiness: Duration) -> Self {
+        RecentWrites { stickiness }
+    }
+
+    /// Marks the client as having just written, set on the responses of writes
+    pub fn cookie(&self) -> Cookie<'static> {
+        let written_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        Cookie::build(LAST_WRITE_COOKIE, written_at.to_string())
+            .path("/")
+            .http_only(true)
+            .max_age(CookieDuration::try_from(self.stickiness).unwrap_or(CookieDuration::MAX))
+            .finish()
+    }
+
+    /// Where the reads of a request are served from
+    pub fn read_from(&self, req: &HttpRequest) -> ReadFrom {
+        let written_at = req
+            .cookie(LAST_WRITE_COOKIE)
+            .and_then(|cookie| cookie.value().parse().ok())
+            .map(|millis| UNIX_EPOCH + Duration::from_millis(millis));
+        // The clocks of instances may disagree a little, so later writes count as well
+        let since_write = written_at.map(|written_at| {
+            SystemTime::now()
+                .duration_since(written_at)
+                .unwrap_or_else(|err| err.duration())
+        });
+
+        match since_write {
+            Some(since_write) if since_write < self.stickiness => ReadFrom::Primary,
+            _ => ReadFrom::Replica,
+        }
+    }
+}
+
+/// Connects to the database without running or checking the migrations
+pub fn connect(config: &Config) -> Result<DbConnection, Box<dyn Error>> {
+    Ok(DBConnManager::new(&config.database)?.wait_for_database()?)
+}
+
+/// Boxes the queries of a repository, run inside a span of the current request
+pub fn query<'a, T, F>(queries: F) -> BoxFuture<'a, Result<T, ErrMessage>>
+where
+    F: Future<Output = Result<T, ErrMessage>> + Send + 'a,
+{
+    Box::pin(queries.instrument(info_span!("db.query")))
+}
+
+// tokio-postgres knows neither the sslmode of verify-ca and verify-full nor sslrootcert,
+// so they are taken out of urls and set up on the TLS connector instead
+fn split_tls_params(url: &str) -> (String, Option<String>, Option<String>) {
+    let (base, params) = match url.split_once('?') {
+        Some(split) => split,
+        None => return (url.to_string(), None, None),
+    };
+
+    let mut sslmode = None;
+    let mut sslrootcert = None;
+    let params = params
+        .split('&')
+        .filter(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = percent_decode_str(value).decode_utf8_lossy().to_string();
+            match name {
+                "sslmode" => sslmode = Some(value),
+                "sslrootcert" => sslrootcert = Some(value),
+                _ => return true,
+            }
+            false
+        })
+        .collect::<Vec<_>>();
+
+    let url = if params.is_empty() {
+        base.to_string()
+    } else {
+        format!("{}?{}", base, params.join("&"))
+    };
+    (url, sslmode, sslrootcert)
+}
+
+// Checks the certificate of the server as libpq does for each sslmode
+fn tls_connector(
+    sslmode: &str,
+    sslrootcert: Option<&str>,
+) -> Result<MakeTlsConnector, openssl::error::ErrorStack> {
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    match sslrootcert {
+        Some(sslrootcert) => builder.set_ca_file(sslrootcert)?,
+        None => builder.set_default_verify_paths()?,
+    }
+    // require only verifies the certificate authority when given one
+    let verify_ca =
+        sslmode.starts_with("verify-") || (sslmode == "require" && sslrootcert.is_some());
+    if !verify_ca {
+        builder.set_verify(SslVerifyMode::NONE);
+    }
+
+    let mut connector = MakeTlsConnector::new(builder.build());
+    if sslmode != "verify-full" {
+        connector.set_callback(|connect, _| {
+            connect.set_verify_hostname(false);
+            Ok(())
+        });
+    }
+    Ok(connector)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-use common::config::Config;
+    use actix_web::test::TestRequest;
 
-embed_migrations!("./migrations");
+    #[test]
+    fn url_encodes_components() {
+        let mut config = DatabaseConfig {
+            url: None,
+            host: "db".to_string(),
+            port: 5433,
+            name: "feed".to_string(),
+            username: "feed".to_string(),
+            password: common::config::Secret::new("p@ss/w:rd"),
+            dialect: "postgres".to_string(),
+            sslmode: "verify-full".to_string(),
+            sslrootcert: Some("/etc/ssl/ca bundle.pem".to_string()),
+            pool_max_size: 1,
+            pool_min_idle: None,
+            pool_connection_timeout: Duration::from_secs(1),
+            pool_idle_timeout: Duration::from_secs(1),
+            startup_timeout: Duration::from_secs(1),
+            auto_migrate: true,
+            replica_url: None,
+            replica_stickiness: Duration::from_secs(1),
+        };
+        assert_eq!(
+            DBConnManager::new(&config).unwrap().url(),
+            "postgres://feed:p%40ss%2Fw%3Ard@db:5433/feed?sslmode=verify-full&sslrootcert=%2Fetc%2Fssl%2Fca%20bundle.pem"
+        );
 
-pub type DBConnPool = Pool<ConnectionManager<PgConnection>>;
+        config.url = Some(common::config::Secret::new("postgres://localhost/feed"));
+        assert_eq!(
+            DBConnManager::new(&config).unwrap().url(),
+            "postgres://localhost/feed"
+        );
+    }
 
-pub fn create_db_conn_pool(config: &Config) -> Result<DBConnPool, Box<dyn Error>> {
-    let db_conn_url = format!(
-        "{db_type}://{username}:{password}@{host}/{database_name}",
-        db_type = config.database_dialect,
-        username = config.database_username,
-        password = config.database_password,
-        host = config.database_host,
-        database_name = config.database_name
-    );
+    #[test]
+    fn reads_stick_to_the_primary_while_the_last_write_is_fresh() {
+        let recent_writes = RecentWrites::new(Duration::from_secs(60));
+        let request = |cookie: Option<Cookie<'static>>| {
+            let request = TestRequest::default();
+            match cookie {
+                Some(cookie) => request.cookie(cookie),
+                None => request,
+            }
+            .to_http_request()
+        };
 
-    let manager = ConnectionManager::new(db_conn_url.as_str());
+        let written = request(Some(recent_writes.cookie()));
+        assert_eq!(recent_writes.read_from(&written), ReadFrom::Primary);
+        // Read by another instance of the service
+        let other_instance = RecentWrites::new(Duration::from_secs(60));
+        assert_eq!(other_instance.read_from(&written), ReadFrom::Primary);
 
-    let pool = Pool::new(manager)?;
+        assert_eq!(recent_writes.read_from(&request(None)), ReadFrom::Replica);
+        let stale = request(Some(Cookie::new(LAST_WRITE_COOKIE, "0")));
+        assert_eq!(recent_writes.read_from(&stale), ReadFrom::Replica);
+        let malformed = request(Some(Cookie::new(LAST_WRITE_COOKIE, "yesterday")));
+        assert_eq!(recent_writes.read_from(&malformed), ReadFrom::Replica);
 
-    embedded_migrations::run_with_output(&*pool.get()?, &mut std::io::stdout())?;
+        let expired = RecentWrites::new(Duration::ZERO);
+        assert_eq!(
+            expired.read_from(&request(Some(expired.cookie()))),
+            ReadFrom::Replica
+        );
+    }
 
-    Ok(pool)
+    #[test]
+    fn splits_tls_params_out_of_urls() {
+        assert_eq!(
+            split_tls_params(
+                "postgres://feed@db/feed?sslmode=verify-full&connect_timeout=5&sslrootcert=%2Fca.pem"
+            ),
+            (
+                "postgres://feed@db/feed?connect_timeout=5".to_string(),
+                Some("verify-full".to_string()),
+                Some("/ca.pem".to_string())
+            )
+        );
+        assert_eq!(
+            split_tls_params("postgres://feed@db/feed"),
+            ("postgres://feed@db/feed".to_string(), None, None)
+        );
+    }
 }
@@ -0,0 +1,130 @@
+use actix_web::error::{JsonPayloadError, PathError, QueryPayloadError};
+use actix_web::web::{JsonConfig, PathConfig, PayloadConfig, QueryConfig, ServiceConfig};
+use actix_web::{Error, HttpRequest, HttpResponse};
+
+use common::config::Config;
+
+use log::debug;
+
+use crate::messages::{ErrMessage, ErrorCode, FieldError};
+
+/// Installs the extractor configuration shared by every service, so requests that
+/// cannot be extracted are answered with the same problem body as any other error
+pub fn configure(srv: &mut ServiceConfig, config: &Config) {
+    srv.app_data(
+        JsonConfig::default()
+            .limit(config.http.max_payload_bytes)
+            .error_handler(json_error),
+    )
+    .app_data(PayloadConfig::default().limit(config.http.max_payload_bytes))
+    .app_data(QueryConfig::default().error_handler(query_error))
+    .app_data(PathConfig::default().error_handler(path_error));
+}
+
+/// Default service answering unknown routes
+pub async fn not_found() -> Result<HttpResponse, ErrMessage> {
+    Err(ErrMessage::Generic {
+        code: ErrorCode::NotFound,
+        message: "Resource not found",
+    })
+}
+
+fn json_error(err: JsonPayloadError, _: &HttpRequest) -> Error {
+    debug!("json: {}", err);
+
+    match err {
+        JsonPayloadError::ContentType => ErrMessage::Generic {
+            code: ErrorCode::UnsupportedMediaType,
+            message: "Expected a JSON body with content type application/json",
+        },
+        JsonPayloadError::OverflowKnownLength { .. } | JsonPayloadError::Overflow { .. } => {
+            ErrMessage::Generic {
+                code: ErrorCode::PayloadTooLarge,
+                message: "Request body is too large",
+            }
+        }
+        JsonPayloadError::Deserialize(err) => malformed("body", err.to_string()),
+        _ => ErrMessage::Generic {
+            code: ErrorCode::BadRequest,
+            message: "Failed to read request body",
+        },
+    }
+    .into()
+}
+
+fn query_error(err: QueryPayloadError, _: &HttpRequest) -> Error {
+    debug!("query: {}", err);
+    malformed("query", err.to_string()).into()
+}
+
+fn path_error(err: PathError, _: &HttpRequest) -> Error {
+    debug!("path: {}", err);
+    malformed("path", err.to_string()).into()
+}
+
+fn malformed(part: &str, message: String) -> ErrMessage {
+    ErrMessage::Validation(vec![FieldError::new(part, "malformed", message)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::web::{self, Json, Path};
+    use actix_web::{test, App};
+    use serde::Deserialize;
+
+    use crate::messages::PROBLEM_JSON;
+
+    #[derive(Deserialize)]
+    struct Body {
+        #[allow(dead_code)]
+        caption: String,
+    }
+
+    async fn handler(_: Path<i32>, _: Json<Body>) -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn extractor_errors_are_problems() {
+        let app = test::init_service(
+            App::new()
+                .configure(|srv| {
+                    srv.app_data(JsonConfig::default().limit(16).error_handler(json_error))
+                        .app_data(PathConfig::default().error_handler(path_error));
+                })
+                .route("/{id}", web::post().to(handler))
+                .default_service(web::to(not_found)),
+        )
+        .await;
+
+        let cases = [
+            ("/1", r#"{"caption":"#, StatusCode::BAD_REQUEST),
+            ("/abc", r#"{"caption":"a"}"#, StatusCode::BAD_REQUEST),
+            ("/1", r#"{"caption":"far too long"}"#, StatusCode::PAYLOAD_TOO_LARGE),
+            ("/1/unknown", "", StatusCode::NOT_FOUND),
+        ];
+
+        for (uri, body, status) in cases {
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .insert_header(("content-type", "application/json"))
+                .set_payload(body)
+                .to_request();
+            let res = test::call_service(&app, req).await;
+
+            assert_eq!(res.status(), status, "{}", uri);
+            assert_eq!(res.headers().get("content-type").unwrap(), PROBLEM_JSON);
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/1")
+            .insert_header(("content-type", "text/plain"))
+            .set_payload(r#"{"caption":"a"}"#)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
@@ -2,10 +2,13 @@ use std::future::Future;
 use std::pin::Pin;
 
 use actix_web::FromRequest;
-use actix_web::{http::StatusCode, web::Data};
+use actix_web::web::Data;
 use common::{config::Config, jwt::verify_jwt};
 
-use crate::{messages::ErrMessage, models::User};
+use crate::{
+    messages::{ErrMessage, ErrorCode},
+    models::User,
+};
 
 use log::debug;
 
@@ -35,7 +38,7 @@ impl FromRequest for IsLoggedIn {
                 .headers()
                 .get("Authorization")
                 .ok_or(ErrMessage::Generic {
-                    status: StatusCode::UNAUTHORIZED,
+                    code: ErrorCode::MissingAuthorization,
                     message: "No authorization headers",
                 })?;
 
@@ -44,13 +47,13 @@ impl FromRequest for IsLoggedIn {
                 .ok_or(ErrMessage::InternalServerError)?;
 
             let auth = auth.to_str().map_err(|_| ErrMessage::Generic {
-                status: StatusCode::UNAUTHORIZED,
+                code: ErrorCode::MalformedToken,
                 message: "Malformed token",
             })?;
 
             // input of the form: Bearer xxxxxxxx
             let (_, token) = auth.split_once(" ").ok_or(ErrMessage::Generic {
-                status: StatusCode::UNAUTHORIZED,
+                code: ErrorCode::MalformedToken,
                 message: "Malformed token",
             })?;
 
@@ -59,7 +62,7 @@ impl FromRequest for IsLoggedIn {
                 .map_err(|e| { 
                     debug!("jwt_verify: {} token: {}", e, token);
                     ErrMessage::Generic {
-                        status: StatusCode::UNAUTHORIZED,
+                        code: ErrorCode::AuthenticationFailed,
                         message: "Failed to authenticate",
                     }
                 })?;
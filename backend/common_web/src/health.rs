@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::future::Future;
+use std::time::Duration;
+
+use actix_web::rt::time::timeout;
+use actix_web::web::{self, Data, ServiceConfig};
+use actix_web::HttpResponse;
+
+use common::aws::S3Bucket;
+use common::config::Config;
+
+use futures_util::future::{join_all, LocalBoxFuture};
+use log::warn;
+use serde::Serialize;
+
+use crate::database::{DBConnPool, ReadFrom};
+use crate::migrations;
+
+pub const HEALTHZ_PATH: &str = "/healthz";
+pub const READYZ_PATH: &str = "/readyz";
+
+// Probes should get an answer even when a dependency hangs
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Result of a readiness check, passing checks may report a detail such as the time
+/// of the last poll
+pub type CheckResult = Result<Option<String>, Box<dyn Error>>;
+
+type Check = Box<dyn Fn() -> LocalBoxFuture<'static, CheckResult>>;
+
+/// Checks a service must pass before receiving traffic, run concurrently on every
+/// request to `/readyz`
+#[derive(Default)]
+pub struct Readiness {
+    checks: Vec<(&'static str, Check)>,
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    checks: BTreeMap<&'static str, CheckResponse>,
+}
+
+#[derive(Serialize)]
+struct CheckResponse {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Readiness::default()
+    }
+
+    pub fn check<F, Fut>(mut self, name: &'static str, check: F) -> Self
+    where
+        F: Fn() -> Fut + 'static,
+        Fut: Future<Output = CheckResult> + 'static,
+    {
+        self.checks
+            .push((name, Box::new(move || Box::pin(check()))));
+        self
+    }
+
+    /// Checks a connection can be checked out of the pool, and of the replica pool if
+    /// configured, and every embedded migration has been run
+    pub fn database(self, pool: Data<DBConnPool>) -> Self {
+        let migrations_pool = pool.clone();
+
+        let readiness = if pool.has_replica() {
+            let pool = pool.clone();
+            self.check("replica", move || {
+                let pool = pool.clone();
+                async move {
+                    pool.read(ReadFrom::Replica).await?;
+                    Ok(None)
+                }
+            })
+        } else {
+            self
+        };
+
+        readiness
+            .check("database", move || {
+                let pool = pool.clone();
+                async move {
+                    pool.get().await?;
+                    Ok(None)
+                }
+            })
+            .check("migrations", move || {
+                let pool = migrations_pool.clone();
+                async move {
+                    let mut conn = pool.get().await?;
+                    let pending = migrations::pending(&mut conn)
+                        .await
+                        .map_err(|err| err as Box<dyn Error>)?;
+
+                    if pending.is_empty() {
+                        Ok(None)
+                    } else {
+                        Err(format!("Pending migrations: {}", pending.join(", ")).into())
+                    }
+                }
+            })
+    }
+
+    /// Checks the bucket is reachable with the current credentials
+    pub fn bucket<T: 'static>(self, name: &'static str, bucket: Data<S3Bucket<T>>) -> Self {
+        self.check(name, move || {
+            let bucket = bucket.clone();
+            async move { bucket.head_bucket().await.map(|_| None) }
+        })
+    }
+
+    /// Checks a secret is configured to sign and verify tokens
+    pub fn jwt(self, config: Data<Config>) -> Self {
+        self.check("jwt", move || {
+            let loaded = !config.jwt.secret.expose().is_empty();
+            async move {
+                if loaded {
+                    Ok(None)
+                } else {
+                    Err("JWT secret is not configured".into())
+                }
+            }
+        })
+    }
+}
+
+/// Serves `/healthz`, answering as long as the process is up, and `/readyz`, answering
+/// `503 Service Unavailable` while any readiness check fails
+pub fn configure(srv: &mut ServiceConfig, readiness: Readiness) {
+    srv.route(HEALTHZ_PATH, web::get().to(healthz)).service(
+        web::resource(READYZ_PATH)
+            .app_data(Data::new(readiness))
+            .route(web::get().to(readyz)),
+    );
+}
+
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(HealthResponse {
+        status: "ok",
+        checks: BTreeMap::new(),
+    })
+}
+
+async fn readyz(readiness: Data<Readiness>) -> HttpResponse {
+    let results = join_all(readiness.checks.iter().map(|(name, check)| async move {
+        let result = timeout(CHECK_TIMEOUT, check())
+            .await
+            .unwrap_or_else(|_| Err("Timed out".into()));
+        (*name, result)
+    }))
+    .await;
+
+    let mut ready = true;
+    let mut checks = BTreeMap::new();
+    for (name, result) in results {
+        let check = match result {
+            Ok(detail) => CheckResponse {
+                status: "ok",
+                detail,
+            },
+            Err(err) => {
+                warn!("readiness check {} failed: {}", name, err);
+                ready = false;
+                CheckResponse {
+                    status: "failed",
+                    detail: Some(err.to_string()),
+                }
+            }
+        };
+        checks.insert(name, check);
+    }
+
+    if ready {
+        HttpResponse::Ok().json(HealthResponse {
+            status: "ok",
+            checks,
+        })
+    } else {
+        HttpResponse::ServiceUnavailable().json(HealthResponse {
+            status: "failed",
+            checks,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    #[actix_web::test]
+    async fn not_ready_while_a_check_fails() {
+        let app = test::init_service(App::new().configure(|srv| {
+            let readiness = Readiness::new()
+                .check("passing", || async { Ok(Some("detail".to_string())) })
+                .check("failing", || async { Err("unreachable".into()) });
+            configure(srv, readiness);
+        }))
+        .await;
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get().uri(HEALTHZ_PATH).to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res =
+            test::call_service(&app, test::TestRequest::get().uri(READYZ_PATH).to_request()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["checks"]["passing"]["detail"], "detail");
+        assert_eq!(body["checks"]["failing"]["status"], "failed");
+        assert_eq!(body["checks"]["failing"]["detail"], "unreachable");
+    }
+}
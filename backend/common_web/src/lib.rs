@@ -1,11 +1,19 @@
 #[macro_use]
 extern crate diesel;
-#[macro_use]
-extern crate diesel_migrations;
 
+pub mod caching;
 pub mod database;
+pub mod extractors;
 pub mod guards;
+pub mod health;
 pub mod messages;
+pub mod metrics;
+pub mod migrations;
 pub mod models;
+pub mod openapi;
+pub mod rate_limit;
+pub mod repositories;
+pub mod request_id;
 pub mod router;
 pub mod schema;
+pub mod validation;
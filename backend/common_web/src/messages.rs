@@ -1,42 +1,212 @@
 use std::error::Error;
 use std::fmt::Display;
 use std::sync::PoisonError;
+use std::time::Duration;
 
 use actix_web::body::BoxBody;
+use actix_web::CustomizeResponder;
 
 use serde::Serialize;
-use serde_json::json;
+use utoipa::ToSchema;
 
+use actix_web::http::header::RETRY_AFTER;
 use actix_web::http::StatusCode;
 use actix_web::{HttpResponse, Responder, ResponseError};
 
+use bb8::RunError;
+use diesel_async::pooled_connection::PoolError;
 use log::error;
 
+use crate::request_id;
+
 pub type Message<T> = Result<OkMessage<T>, ErrMessage>;
 
+/// A message with additional response headers, such as an ETag
+pub type CustomizedMessage<T> = Result<CustomizeResponder<OkMessage<T>>, ErrMessage>;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+// Problem types are identified by URNs built from the error code
+const PROBLEM_TYPE_PREFIX: &str = "urn:c5-project:problem:";
+
 pub enum OkMessage<T: Serialize> {
     Success(T),
     Created(T),
+    Accepted(T),
+}
+
+/// Stable, machine readable error codes. Clients may rely on these, so existing codes
+/// must never be renamed or reused for a different error.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorCode {
+    BadRequest,
+    NotFound,
+    Conflict,
+    PreconditionFailed,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    ValidationFailed,
+    ServiceUnavailable,
+    InternalError,
+    MissingAuthorization,
+    MalformedToken,
+    AuthenticationFailed,
+    InvalidCredentials,
+    UserAlreadyExists,
+    FeedItemNotFound,
+    FeedItemNotEditable,
+    UploadNotFound,
+    TooManyRequests,
+    AccountLocked,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::Conflict => "conflict",
+            ErrorCode::PreconditionFailed => "precondition_failed",
+            ErrorCode::PayloadTooLarge => "payload_too_large",
+            ErrorCode::UnsupportedMediaType => "unsupported_media_type",
+            ErrorCode::ValidationFailed => "validation_failed",
+            ErrorCode::ServiceUnavailable => "service_unavailable",
+            ErrorCode::InternalError => "internal_error",
+            ErrorCode::MissingAuthorization => "missing_authorization",
+            ErrorCode::MalformedToken => "malformed_token",
+            ErrorCode::AuthenticationFailed => "authentication_failed",
+            ErrorCode::InvalidCredentials => "invalid_credentials",
+            ErrorCode::UserAlreadyExists => "user_already_exists",
+            ErrorCode::FeedItemNotFound => "feed_item_not_found",
+            ErrorCode::FeedItemNotEditable => "feed_item_not_editable",
+            ErrorCode::UploadNotFound => "upload_not_found",
+            ErrorCode::TooManyRequests => "too_many_requests",
+            ErrorCode::AccountLocked => "account_locked",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::NotFound | ErrorCode::FeedItemNotFound | ErrorCode::UploadNotFound => {
+                StatusCode::NOT_FOUND
+            }
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::BadRequest | ErrorCode::ValidationFailed => StatusCode::BAD_REQUEST,
+            ErrorCode::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::MissingAuthorization
+            | ErrorCode::MalformedToken
+            | ErrorCode::AuthenticationFailed
+            | ErrorCode::InvalidCredentials => StatusCode::UNAUTHORIZED,
+            ErrorCode::UserAlreadyExists => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::FeedItemNotEditable => StatusCode::FORBIDDEN,
+            ErrorCode::TooManyRequests | ErrorCode::AccountLocked => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    pub fn type_uri(&self) -> String {
+        format!("{}{}", PROBLEM_TYPE_PREFIX, self.as_str())
+    }
+}
+
+/// Describes why a single field of a request was rejected
+#[derive(Serialize, ToSchema, Clone, PartialEq, Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(
+        field: impl Into<String>,
+        code: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        FieldError {
+            field: field.into(),
+            code: code.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Body of every error response, as described by RFC 7807
+#[derive(Serialize, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: &'static str,
+    pub status: u16,
+    pub code: &'static str,
+    pub detail: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
 }
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum ErrMessage {
     Generic {
-        status: StatusCode,
+        code: ErrorCode,
+        message: &'static str,
+    },
+    Validation(Vec<FieldError>),
+    // Answered with a `Retry-After` header
+    RateLimited {
+        code: ErrorCode,
         message: &'static str,
+        retry_after: Duration,
     },
     InternalServerError,
 }
 
+impl ErrMessage {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ErrMessage::Generic { code, .. } | ErrMessage::RateLimited { code, .. } => *code,
+            ErrMessage::Validation(_) => ErrorCode::ValidationFailed,
+            ErrMessage::InternalServerError => ErrorCode::InternalError,
+        }
+    }
+
+    fn detail(&self) -> &'static str {
+        match self {
+            ErrMessage::Generic { message, .. } | ErrMessage::RateLimited { message, .. } => {
+                message
+            }
+            ErrMessage::Validation(_) => "The request is invalid",
+            ErrMessage::InternalServerError => "An internal error has occurred",
+        }
+    }
+}
+
 impl Display for ErrMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ErrMessage::Generic { status, message } => {
-                write!(f, "Status: {}, Message: {}", status, message)
-            }
-            ErrMessage::InternalServerError => {
-                write!(f, "Status: 500, Message: Internal Server Error")
+            ErrMessage::Validation(errors) => {
+                write!(
+                    f,
+                    "Status: {}, Code: {}, Errors:",
+                    self.status_code(),
+                    self.code().as_str()
+                )?;
+                for err in errors {
+                    write!(f, " {}: {};", err.field, err.message)?;
+                }
+                Ok(())
             }
+            _ => write!(
+                f,
+                "Status: {}, Code: {}, Message: {}",
+                self.status_code(),
+                self.code().as_str(),
+                self.detail()
+            ),
         }
     }
 }
@@ -50,15 +220,35 @@ impl From<actix_web::error::BlockingError> for ErrMessage {
 
 impl From<diesel::result::Error> for ErrMessage {
     fn from(err: diesel::result::Error) -> Self {
-        error!("diesel: {}", err);
-        ErrMessage::InternalServerError
+        use diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+        match err {
+            DieselError::NotFound => ErrMessage::Generic {
+                code: ErrorCode::NotFound,
+                message: "Resource not found",
+            },
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                ErrMessage::Generic {
+                    code: ErrorCode::Conflict,
+                    message: "Resource already exists",
+                }
+            }
+            err => {
+                error!("diesel: {}", err);
+                ErrMessage::InternalServerError
+            }
+        }
     }
 }
 
-impl From<r2d2::Error> for ErrMessage {
-    fn from(err: r2d2::Error) -> Self {
-        error!("r2d2: {}", err);
-        ErrMessage::InternalServerError
+impl From<RunError<PoolError>> for ErrMessage {
+    fn from(err: RunError<PoolError>) -> Self {
+        // Only returned when no connection could be checked out in time
+        error!("pool: {}", err);
+        ErrMessage::Generic {
+            code: ErrorCode::ServiceUnavailable,
+            message: "The service is temporarily unavailable",
+        }
     }
 }
 
@@ -73,23 +263,38 @@ impl Error for ErrMessage {}
 
 impl ResponseError for ErrMessage {
     fn status_code(&self) -> StatusCode {
-        match self {
-            ErrMessage::Generic { status, .. } => status.clone(),
-            ErrMessage::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
-        }
+        self.code().status()
     }
 
     fn error_response(&self) -> HttpResponse<BoxBody> {
-        match self {
-            ErrMessage::Generic { status, message } => {
-                error!("Generic Error: status:{}, message:{}", status, message);
-                HttpResponse::build(self.status_code()).json(json!({
-                  "code": status.as_u16(),
-                  "message": message
-                }))
-            }
-            ErrMessage::InternalServerError => internal_server_error(),
+        if let ErrMessage::Generic { .. } | ErrMessage::Validation(_) = self {
+            error!("Error: {}", self);
+        }
+
+        let status = self.status_code();
+        let code = self.code();
+
+        let problem = Problem {
+            problem_type: code.type_uri(),
+            title: status.canonical_reason().unwrap_or_default(),
+            status: status.as_u16(),
+            code: code.as_str(),
+            detail: self.detail(),
+            request_id: request_id::current(),
+            errors: match self {
+                ErrMessage::Validation(errors) => errors.clone(),
+                _ => Vec::new(),
+            },
+        };
+
+        let mut res = HttpResponse::build(status);
+        if let ErrMessage::RateLimited { retry_after, .. } = self {
+            // Rounded up, so clients never retry too early
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            res.insert_header((RETRY_AFTER, seconds.max(1)));
         }
+
+        res.content_type(PROBLEM_JSON).json(problem)
     }
 }
 
@@ -100,13 +305,45 @@ impl<T: Serialize> Responder for OkMessage<T> {
         match self {
             Self::Success(msg) => HttpResponse::Ok().json(msg),
             Self::Created(msg) => HttpResponse::Created().json(msg),
+            Self::Accepted(msg) => HttpResponse::Accepted().json(msg),
         }
     }
 }
 
-fn internal_server_error() -> HttpResponse {
-    HttpResponse::InternalServerError().json(json!({
-        "code": StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-        "message": "An internal error has occurred"
-    }))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::body::to_bytes;
+
+    #[test]
+    fn maps_diesel_errors() {
+        let err: ErrMessage = diesel::result::Error::NotFound.into();
+        assert_eq!(err.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(err.code(), ErrorCode::NotFound);
+
+        let err: ErrMessage = diesel::result::Error::RollbackTransaction.into();
+        assert_eq!(err.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn renders_problem_json() {
+        let err = ErrMessage::Validation(vec![FieldError::new(
+            "email",
+            "required",
+            "Email is required",
+        )]);
+        let res = err.error_response();
+
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers().get("content-type").unwrap(), PROBLEM_JSON);
+
+        let body = to_bytes(res.into_body()).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["type"], "urn:c5-project:problem:validation_failed");
+        assert_eq!(problem["code"], "validation_failed");
+        assert_eq!(problem["status"], 400);
+        assert_eq!(problem["errors"][0]["field"], "email");
+        assert!(problem.get("request_id").is_none());
+    }
 }
@@ -0,0 +1,149 @@
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web::{self, Data, ServiceConfig};
+use actix_web::{Error, HttpResponse};
+
+use common::metrics;
+
+use log::error;
+
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, HistogramVec,
+    IntCounterVec, IntGaugeVec,
+};
+
+use crate::database::DBConnPool;
+use crate::messages::ErrMessage;
+
+pub const METRICS_PATH: &str = "/metrics";
+
+// Requests not matching any route share a label, so unknown paths cannot grow the series
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "http_requests_total",
+        "Number of HTTP requests handled",
+        &["method", "route", "status"]
+    )
+    .expect("Failed to register http_requests_total")
+});
+
+static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "http_request_duration_seconds",
+        "Duration of HTTP requests",
+        &["method", "route"]
+    )
+    .expect("Failed to register http_request_duration_seconds")
+});
+
+static DB_POOL_CONNECTIONS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "db_pool_connections",
+        "Connections of the database pool",
+        &["state"]
+    )
+    .expect("Failed to register db_pool_connections")
+});
+
+/// Middleware recording the count, status and duration of requests per route.
+///
+/// Use with `actix_web::middleware::from_fn(metrics::middleware)`.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let start = Instant::now();
+    let method = req.method().to_string();
+
+    let res = next.call(req).await?;
+
+    let route = res
+        .request()
+        .match_pattern()
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+
+    HTTP_REQUESTS
+        .with_label_values(&[&method, &route, res.status().as_str()])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+
+    Ok(res)
+}
+
+/// Serves the metrics of the process on `/metrics`
+pub fn configure(srv: &mut ServiceConfig) {
+    srv.route(METRICS_PATH, web::get().to(get_metrics));
+}
+
+async fn get_metrics(pool: Option<Data<DBConnPool>>) -> Result<HttpResponse, ErrMessage> {
+    if let Some(pool) = pool {
+        let state = pool.state();
+        let idle = state.idle_connections as i64;
+        let active = state.connections as i64 - idle;
+
+        DB_POOL_CONNECTIONS.with_label_values(&["idle"]).set(idle);
+        DB_POOL_CONNECTIONS.with_label_values(&["active"]).set(active);
+        DB_POOL_CONNECTIONS
+            .with_label_values(&["max"])
+            .set(pool.max_size() as i64);
+    }
+
+    let body = metrics::render().map_err(|err| {
+        error!("{}", err);
+        ErrMessage::InternalServerError
+    })?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(metrics::TEXT_FORMAT)
+        .body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+
+    async fn ok() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn records_requests_by_route() {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(middleware))
+                .configure(configure)
+                .route("/items/{id}", web::get().to(ok)),
+        )
+        .await;
+
+        for uri in ["/items/1", "/items/2", "/unknown"] {
+            test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        }
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get().uri(METRICS_PATH).to_request(),
+        )
+        .await;
+        assert!(res.status().is_success());
+
+        let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+        assert!(body.contains(
+            r#"http_requests_total{method="GET",route="/items/{id}",status="200"} 2"#
+        ));
+        assert!(body.contains(
+            r#"http_requests_total{method="GET",route="unmatched",status="404"} 1"#
+        ));
+    }
+}
@@ -0,0 +1,225 @@
+use std::error::Error;
+
+use clap::Subcommand;
+use diesel::backend::Backend;
+use diesel::migration::{Migration, MigrationSource};
+use diesel::pg::Pg;
+#[cfg(feature = "sqlite")]
+use diesel::sqlite::Sqlite;
+use diesel::{Connection, QueryDsl};
+use diesel_async::RunQueryDsl;
+use diesel_migrations::{
+    embed_migrations, EmbeddedMigrations, HarnessWithOutput, MigrationHarness,
+};
+
+use common::config::Config;
+
+use crate::database::{self, with_connection, AsyncDbConnection, DbConnection};
+
+type MigrationResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Each dialect has its own migrations, under the same names
+const POSTGRES_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/postgres");
+#[cfg(feature = "sqlite")]
+const SQLITE_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/sqlite");
+
+// Kept by diesel, lists the migrations run on the database
+table! {
+    __diesel_schema_migrations (version) {
+        version -> VarChar,
+        run_on -> Timestamp,
+    }
+}
+
+// Runs the body with the connection of the backend as `$conn` and its migrations as
+// `$migrations`
+macro_rules! with_migrations {
+    ($db_conn:expr, |$conn:ident, $migrations:ident| $body:expr) => {
+        match $db_conn {
+            DbConnection::Postgres($conn) => {
+                let $migrations = POSTGRES_MIGRATIONS;
+                $body
+            }
+            #[cfg(feature = "sqlite")]
+            DbConnection::Sqlite($conn) => {
+                let $migrations = SQLITE_MIGRATIONS;
+                $body
+            }
+        }
+    };
+}
+
+/// Manages the database schema with the migrations embedded in the service
+#[derive(Subcommand, Clone, Copy, Debug)]
+pub enum MigrateCommand {
+    /// Run the pending migrations
+    Up,
+    /// Revert the latest migration
+    Down,
+    /// List the migrations and whether they have been run
+    Status,
+    /// Revert and run again the latest migration
+    Redo,
+}
+
+impl MigrateCommand {
+    pub fn run(self, config: &Config) -> Result<(), Box<dyn Error>> {
+        let mut conn = database::connect(config)?;
+
+        let result = match self {
+            MigrateCommand::Up => run_pending(&mut conn),
+            MigrateCommand::Down => revert_latest(&mut conn),
+            MigrateCommand::Status => print_status(&mut conn),
+            MigrateCommand::Redo => redo_latest(&mut conn),
+        };
+        result.map_err(|err| err as Box<dyn Error>)
+    }
+}
+
+/// Names of the embedded migrations which have not been run on the database
+pub async fn pending(conn: &mut AsyncDbConnection) -> MigrationResult<Vec<String>> {
+    let applied = with_connection!(&mut *conn, |conn| {
+        __diesel_schema_migrations::table
+            .select(__diesel_schema_migrations::version)
+            .load::<String>(conn)
+            .await
+    })?;
+
+    let embedded = match conn {
+        AsyncDbConnection::Postgres(_) => names::<Pg>(&POSTGRES_MIGRATIONS)?,
+        #[cfg(feature = "sqlite")]
+        AsyncDbConnection::Sqlite(_) => names::<Sqlite>(&SQLITE_MIGRATIONS)?,
+    };
+    Ok(embedded
+        .into_iter()
+        .filter(|(version, _)| !applied.contains(version))
+        .map(|(_, name)| name)
+        .collect())
+}
+
+fn unapplied(conn: &mut DbConnection) -> MigrationResult<Vec<String>> {
+    with_migrations!(conn, |conn, migrations| {
+        Ok(conn
+            .pending_migrations(migrations)?
+            .iter()
+            .map(|migration| migration.name().to_string())
+            .collect())
+    })
+}
+
+pub fn run_pending(conn: &mut DbConnection) -> MigrationResult<()> {
+    with_migrations!(conn, |conn, migrations| {
+        HarnessWithOutput::write_to_stdout(conn).run_pending_migrations(migrations)?;
+        Ok(())
+    })
+}
+
+/// Fails when migrations are pending, so a service never runs against an older schema
+pub fn ensure_up_to_date(conn: &mut DbConnection) -> MigrationResult<()> {
+    let pending = unapplied(conn)?;
+
+    if pending.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "The database schema is behind, run `migrate up` to apply: {}",
+            pending.join(", ")
+        )
+        .into())
+    }
+}
+
+fn revert_latest(conn: &mut DbConnection) -> MigrationResult<()> {
+    with_migrations!(conn, |conn, migrations| {
+        HarnessWithOutput::write_to_stdout(conn).revert_last_migration(migrations)?;
+        Ok(())
+    })
+}
+
+fn redo_latest(conn: &mut DbConnection) -> MigrationResult<()> {
+    with_migrations!(conn, |conn, migrations| redo(conn, migrations))
+}
+
+fn print_status(conn: &mut DbConnection) -> MigrationResult<()> {
+    with_migrations!(conn, |conn, migrations| status(conn, migrations))
+}
+
+// Versions and names of the embedded migrations
+fn names<DB: Backend>(migrations: &EmbeddedMigrations) -> MigrationResult<Vec<(String, String)>> {
+    Ok(MigrationSource::<DB>::migrations(migrations)?
+        .iter()
+        .map(|migration| {
+            let name = migration.name();
+            (name.version().to_string(), name.to_string())
+        })
+        .collect())
+}
+
+fn redo<DB, C>(conn: &mut C, migrations: EmbeddedMigrations) -> MigrationResult<()>
+where
+    DB: Backend,
+    C: Connection<Backend = DB> + MigrationHarness<DB>,
+{
+    let source = MigrationSource::<DB>::migrations(&migrations)?;
+
+    conn.transaction(|conn| {
+        let mut harness = HarnessWithOutput::write_to_stdout(conn);
+        let version = harness.revert_last_migration(migrations)?;
+        let migration = source
+            .iter()
+            .find(|migration| migration.name().version() == version)
+            .ok_or_else(|| format!("Unknown migration {}", version))?;
+        harness.run_migration(&**migration)?;
+        Ok(())
+    })
+}
+
+fn status<DB, C>(conn: &mut C, migrations: EmbeddedMigrations) -> MigrationResult<()>
+where
+    DB: Backend,
+    C: MigrationHarness<DB>,
+{
+    let mut applied = conn.applied_migrations()?;
+
+    for migration in MigrationSource::<DB>::migrations(&migrations)? {
+        let version = migration.name().version();
+        let mark = match applied.iter().position(|applied| *applied == version) {
+            Some(index) => {
+                applied.remove(index);
+                "X"
+            }
+            None => " ",
+        };
+        println!("[{}] {}", mark, migration.name());
+    }
+    // Run by a newer release of the services
+    applied.sort();
+    for version in applied {
+        println!("[?] {} (unknown)", version);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    #[test]
+    fn sqlite_migrations_match_postgres() {
+        let names = |dialect: &str| {
+            let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("migrations")
+                .join(dialect);
+            let mut names = std::fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                // Helpers diesel sets up for Postgres only
+                .filter(|name| name != "00000000000000_diesel_initial_setup")
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        assert_eq!(names("sqlite"), names("postgres"));
+    }
+}
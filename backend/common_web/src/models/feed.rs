@@ -3,7 +3,7 @@ use serde::{Serialize, Deserialize};
 use chrono::NaiveDateTime;
 
 #[derive(Queryable, Serialize, Deserialize, Debug)]
-#[diesel(table_name="feeditems")]
+#[diesel(table_name = feeditems)]
 pub struct FeedItem {
     pub id: i32,
     pub created_by: String,
@@ -11,5 +11,15 @@ pub struct FeedItem {
     pub caption: Option<String>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub animated: bool,
+    // Why imgproc gave up on the media, cleared once it is processed
+    pub processing_error: Option<String>,
 }
 
+/// A feed item to create, the database sets its id and timestamps
+#[derive(Debug)]
+pub struct NewFeedItem {
+    pub created_by: String,
+    pub image_id: String,
+    pub caption: Option<String>,
+}
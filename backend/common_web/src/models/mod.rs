@@ -1,5 +1,5 @@
 mod feed;
 mod users;
 
-pub use feed::FeedItem;
+pub use feed::{FeedItem, NewFeedItem};
 pub use users::User;
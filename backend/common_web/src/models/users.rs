@@ -4,7 +4,7 @@ use chrono::NaiveDateTime;
 use serde::{Serialize, Deserialize};
 
 #[derive(Queryable, Serialize, Deserialize, Debug)]
-#[diesel(table_name="users", primary_key("email"))]
+#[diesel(table_name = users, primary_key(email))]
 pub struct User  {
     pub id: i32,
     pub email: String,
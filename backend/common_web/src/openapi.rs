@@ -0,0 +1,47 @@
+use actix_web::web::{self, Data, ServiceConfig};
+use actix_web::HttpResponse;
+
+use common::config::Config;
+
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::{Components, OpenApi};
+use utoipa_swagger_ui::SwaggerUi;
+
+pub const OPENAPI_PATH: &str = "/api/v0/openapi.json";
+pub const SWAGGER_UI_PATH: &str = "/api/v0/docs";
+
+/// Name of the security scheme used by handlers requiring a logged in user
+pub const BEARER_AUTH: &str = "bearer_auth";
+
+/// Serves the OpenAPI document of a service, along with Swagger UI when enabled
+pub fn configure(srv: &mut ServiceConfig, mut openapi: OpenApi, config: &Config) {
+    openapi
+        .components
+        .get_or_insert_with(Components::new)
+        .add_security_scheme(
+            BEARER_AUTH,
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+
+    srv.service(
+        web::resource(OPENAPI_PATH)
+            .app_data(Data::new(openapi))
+            .route(web::get().to(get_openapi)),
+    );
+
+    if config.http.swagger_ui_enabled {
+        srv.service(
+            SwaggerUi::new(format!("{}/{{_:.*}}", SWAGGER_UI_PATH))
+                .config(utoipa_swagger_ui::Config::from(OPENAPI_PATH)),
+        );
+    }
+}
+
+async fn get_openapi(openapi: Data<OpenApi>) -> HttpResponse {
+    HttpResponse::Ok().json(openapi.as_ref())
+}
@@ -0,0 +1,750 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::X_FORWARDED_FOR;
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::rt::time::sleep;
+use actix_web::web::Data;
+use actix_web::ResponseError;
+
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use diesel::prelude::*;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use futures_util::future::{ready, LocalBoxFuture};
+use ipnet::IpNet;
+use log::error;
+
+use common::config::Config;
+
+use crate::database::{self, AsyncDbConnection, DBConnPool};
+use crate::messages::{ErrMessage, ErrorCode};
+use crate::router::ApiVersion;
+use crate::schema::{login_failures, rate_limits};
+
+// Entries untouched for this long hold full buckets or forgiven failures
+const PRUNE_AFTER: Duration = Duration::from_secs(60 * 60);
+const PRUNE_EVERY: u32 = 1000;
+
+const DEFAULT_ACCOUNT_LIMIT: Limit = Limit {
+    burst: 10,
+    period: Duration::from_secs(30),
+};
+
+/// A token bucket allowing bursts of `burst` requests, refilled at one request every
+/// `period`
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    burst: u32,
+    period: Duration,
+}
+
+impl Limit {
+    pub fn new(burst: u32, period: Duration) -> Self {
+        Limit { burst, period }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Decision {
+    Allowed,
+    Limited(Duration),
+}
+
+/// State of a token bucket, as kept by stores
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Bucket {
+    pub tokens: f64,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Bucket {
+    pub fn full(limit: Limit, now: NaiveDateTime) -> Self {
+        Bucket {
+            tokens: limit.burst as f64,
+            updated_at: now,
+        }
+    }
+
+    /// Refills the bucket up to `now`, then takes a token when one is left
+    pub fn take(&mut self, limit: Limit, now: NaiveDateTime) -> Decision {
+        let period = limit.period.as_secs_f64();
+        let elapsed = (now - self.updated_at)
+            .to_std()
+            .map(|elapsed| elapsed.as_secs_f64())
+            .unwrap_or_default();
+
+        self.tokens = (self.tokens + elapsed / period).min(limit.burst as f64);
+        self.updated_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Decision::Allowed
+        } else {
+            Decision::Limited(Duration::from_secs_f64((1.0 - self.tokens) * period))
+        }
+    }
+}
+
+/// How failed logins slow down and lock an account
+#[derive(Clone, Copy, Debug)]
+pub struct LockoutPolicy {
+    /// Consecutive failures locking the account
+    pub max_failures: i32,
+    pub lock_for: Duration,
+    /// Delay added to the response of the first failure, doubled by each following one
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy {
+            max_failures: 5,
+            lock_for: Duration::from_secs(15 * 60),
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(4),
+        }
+    }
+}
+
+/// Consecutive failed logins of an account, as kept by stores
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Failures {
+    pub count: i32,
+    pub locked_until: Option<NaiveDateTime>,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Failures {
+    pub fn new(now: NaiveDateTime) -> Self {
+        Failures {
+            count: 0,
+            locked_until: None,
+            updated_at: now,
+        }
+    }
+
+    /// Time left until the account is unlocked
+    pub fn locked_for(&self, now: NaiveDateTime) -> Option<Duration> {
+        let locked_for = (self.locked_until? - now).to_std().ok()?;
+        (!locked_for.is_zero()).then_some(locked_for)
+    }
+
+    /// Records a failed login, locking the account after too many, and returns the delay
+    /// to add to its response
+    pub fn record(&mut self, policy: &LockoutPolicy, now: NaiveDateTime) -> Duration {
+        // Failures are forgiven when a lock expires, or after as long without any
+        let forgiven = match self.locked_until {
+            Some(locked_until) => now >= locked_until,
+            None => now - self.updated_at >= delta(policy.lock_for),
+        };
+        if forgiven {
+            self.count = 0;
+            self.locked_until = None;
+        }
+
+        self.count += 1;
+        self.updated_at = now;
+        if self.count >= policy.max_failures {
+            self.locked_until = Some(now + delta(policy.lock_for));
+        }
+
+        let factor = 2u32
+            .checked_pow((self.count - 1) as u32)
+            .unwrap_or(u32::MAX);
+        policy
+            .base_delay
+            .saturating_mul(factor)
+            .min(policy.max_delay)
+    }
+}
+
+/// Keeps the state of rate limits for every worker of a service or, with
+/// [`PostgresStore`], for every replica
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a token from the bucket named `key`
+    fn take(&self, key: String, limit: Limit) -> LocalBoxFuture<'_, Result<Decision, ErrMessage>>;
+
+    fn locked_for(
+        &self,
+        account: String,
+    ) -> LocalBoxFuture<'_, Result<Option<Duration>, ErrMessage>>;
+
+    /// Records a failed login, returning the delay to add to its response
+    fn login_failed(
+        &self,
+        account: String,
+        policy: LockoutPolicy,
+    ) -> LocalBoxFuture<'_, Result<Duration, ErrMessage>>;
+
+    fn login_succeeded(&self, account: String) -> LocalBoxFuture<'_, Result<(), ErrMessage>>;
+}
+
+/// Keeps rate limits in the memory of the process
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    failures: Mutex<HashMap<String, Failures>>,
+    updates: AtomicU32,
+}
+
+impl MemoryStore {
+    fn take_sync(&self, key: String, limit: Limit) -> Result<Decision, ErrMessage> {
+        let now = now();
+        let mut buckets = self.buckets.lock()?;
+        if prune_due(&self.updates) {
+            buckets.retain(|_, bucket| now - bucket.updated_at < delta(PRUNE_AFTER));
+        }
+
+        Ok(buckets
+            .entry(key)
+            .or_insert_with(|| Bucket::full(limit, now))
+            .take(limit, now))
+    }
+
+    fn login_failed_sync(
+        &self,
+        account: String,
+        policy: LockoutPolicy,
+    ) -> Result<Duration, ErrMessage> {
+        let now = now();
+        let mut failures = self.failures.lock()?;
+        if prune_due(&self.updates) {
+            failures.retain(|_, failures| {
+                failures.locked_for(now).is_some() || now - failures.updated_at < delta(PRUNE_AFTER)
+            });
+        }
+
+        Ok(failures
+            .entry(account)
+            .or_insert_with(|| Failures::new(now))
+            .record(&policy, now))
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn take(&self, key: String, limit: Limit) -> LocalBoxFuture<'_, Result<Decision, ErrMessage>> {
+        Box::pin(ready(self.take_sync(key, limit)))
+    }
+
+    fn locked_for(
+        &self,
+        account: String,
+    ) -> LocalBoxFuture<'_, Result<Option<Duration>, ErrMessage>> {
+        let result = self.failures.lock().map(|failures| {
+            failures
+                .get(&account)
+                .and_then(|failures| failures.locked_for(now()))
+        });
+        Box::pin(ready(result.map_err(Into::into)))
+    }
+
+    fn login_failed(
+        &self,
+        account: String,
+        policy: LockoutPolicy,
+    ) -> LocalBoxFuture<'_, Result<Duration, ErrMessage>> {
+        Box::pin(ready(self.login_failed_sync(account, policy)))
+    }
+
+    fn login_succeeded(&self, account: String) -> LocalBoxFuture<'_, Result<(), ErrMessage>> {
+        let result = self.failures.lock().map(|mut failures| {
+            failures.remove(&account);
+        });
+        Box::pin(ready(result.map_err(Into::into)))
+    }
+}
+
+/// Keeps rate limits in the `rate_limits` and `login_failures` tables, shared by every
+/// replica using the database
+pub struct PostgresStore {
+    pool: DBConnPool,
+    updates: AtomicU32,
+}
+
+impl PostgresStore {
+    pub fn new(pool: DBConnPool) -> Self {
+        PostgresStore {
+            pool,
+            updates: AtomicU32::new(0),
+        }
+    }
+}
+
+impl RateLimitStore for PostgresStore {
+    fn take(&self, key: String, limit: Limit) -> LocalBoxFuture<'_, Result<Decision, ErrMessage>> {
+        let prune = prune_due(&self.updates);
+
+        database::query(async move {
+            let mut conn = self.pool.get().await?;
+            let conn = postgres(&mut conn)?;
+            let now = now();
+
+            if prune {
+                let stale = now - delta(PRUNE_AFTER);
+                diesel::delete(rate_limits::table.filter(rate_limits::updated_at.lt(stale)))
+                    .execute(conn)
+                    .await?;
+            }
+
+            conn.transaction(async |conn| {
+                let full = Bucket::full(limit, now);
+                diesel::insert_into(rate_limits::table)
+                    .values((
+                        rate_limits::key.eq(&key),
+                        rate_limits::tokens.eq(full.tokens),
+                        rate_limits::updated_at.eq(full.updated_at),
+                    ))
+                    .on_conflict_do_nothing()
+                    .execute(conn)
+                    .await?;
+
+                let (tokens, updated_at) = rate_limits::table
+                    .find(&key)
+                    .select((rate_limits::tokens, rate_limits::updated_at))
+                    .for_update()
+                    .first::<(f64, NaiveDateTime)>(conn)
+                    .await?;
+
+                let mut bucket = Bucket { tokens, updated_at };
+                let decision = bucket.take(limit, now);
+
+                diesel::update(rate_limits::table.find(&key))
+                    .set((
+                        rate_limits::tokens.eq(bucket.tokens),
+                        rate_limits::updated_at.eq(bucket.updated_at),
+                    ))
+                    .execute(conn)
+                    .await?;
+
+                Ok(decision)
+            })
+            .await
+        })
+    }
+
+    fn locked_for(
+        &self,
+        account: String,
+    ) -> LocalBoxFuture<'_, Result<Option<Duration>, ErrMessage>> {
+        database::query(async move {
+            let mut conn = self.pool.get().await?;
+            let conn = postgres(&mut conn)?;
+            let locked_until = login_failures::table
+                .find(&account)
+                .select(login_failures::locked_until)
+                .first::<Option<NaiveDateTime>>(conn)
+                .await
+                .optional()?
+                .flatten();
+
+            Ok(locked_until.and_then(|locked_until| {
+                let failures = Failures {
+                    locked_until: Some(locked_until),
+                    ..Failures::new(locked_until)
+                };
+                failures.locked_for(now())
+            }))
+        })
+    }
+
+    fn login_failed(
+        &self,
+        account: String,
+        policy: LockoutPolicy,
+    ) -> LocalBoxFuture<'_, Result<Duration, ErrMessage>> {
+        let prune = prune_due(&self.updates);
+
+        database::query(async move {
+            let mut conn = self.pool.get().await?;
+            let conn = postgres(&mut conn)?;
+            let now = now();
+
+            if prune {
+                let stale = now - delta(PRUNE_AFTER);
+                diesel::delete(
+                    login_failures::table
+                        .filter(login_failures::updated_at.lt(stale))
+                        .filter(
+                            login_failures::locked_until
+                                .is_null()
+                                .or(login_failures::locked_until.lt(now)),
+                        ),
+                )
+                .execute(conn)
+                .await?;
+            }
+
+            conn.transaction(async |conn| {
+                diesel::insert_into(login_failures::table)
+                    .values((
+                        login_failures::account.eq(&account),
+                        login_failures::failures.eq(0),
+                        login_failures::updated_at.eq(now),
+                    ))
+                    .on_conflict_do_nothing()
+                    .execute(conn)
+                    .await?;
+
+                let (count, locked_until, updated_at) = login_failures::table
+                    .find(&account)
+                    .select((
+                        login_failures::failures,
+                        login_failures::locked_until,
+                        login_failures::updated_at,
+                    ))
+                    .for_update()
+                    .first::<(i32, Option<NaiveDateTime>, NaiveDateTime)>(conn)
+                    .await?;
+
+                let mut failures = Failures {
+                    count,
+                    locked_until,
+                    updated_at,
+                };
+                let delay = failures.record(&policy, now);
+
+                diesel::update(login_failures::table.find(&account))
+                    .set((
+                        login_failures::failures.eq(failures.count),
+                        login_failures::locked_until.eq(failures.locked_until),
+                        login_failures::updated_at.eq(failures.updated_at),
+                    ))
+                    .execute(conn)
+                    .await?;
+
+                Ok(delay)
+            })
+            .await
+        })
+    }
+
+    fn login_succeeded(&self, account: String) -> LocalBoxFuture<'_, Result<(), ErrMessage>> {
+        database::query(async move {
+            let mut conn = self.pool.get().await?;
+            let conn = postgres(&mut conn)?;
+            diesel::delete(login_failures::table.find(&account))
+                .execute(conn)
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+// The queries of the store lock rows and upsert, which only Postgres supports
+fn postgres(conn: &mut AsyncDbConnection) -> Result<&mut AsyncPgConnection, ErrMessage> {
+    conn.postgres().ok_or_else(|| {
+        error!("rate limits: the postgres store needs a postgres database");
+        ErrMessage::InternalServerError
+    })
+}
+
+/// Creates the store selected by `RATE_LIMIT_STORE`
+pub fn create_store(
+    config: &Config,
+    pool: &DBConnPool,
+) -> Result<Arc<dyn RateLimitStore>, Box<dyn Error>> {
+    match config.http.rate_limit_store.as_str() {
+        "memory" => Ok(Arc::new(MemoryStore::default())),
+        "postgres" if config.database.dialect == database::POSTGRES => {
+            Ok(Arc::new(PostgresStore::new(pool.clone())))
+        }
+        "postgres" => Err("The postgres rate limit store needs a postgres database".into()),
+        store => Err(format!("Unknown rate limit store \"{}\"", store).into()),
+    }
+}
+
+/// Limits requests by client IP to the routes it is configured with, and login attempts
+/// by account. Handlers reach it as app data, to check accounts.
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+    routes: Vec<(Method, &'static str, Limit)>,
+    account_limit: Limit,
+    lockout: LockoutPolicy,
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl RateLimiter {
+    pub fn new(store: Arc<dyn RateLimitStore>) -> Self {
+        RateLimiter {
+            store,
+            routes: Vec::new(),
+            account_limit: DEFAULT_ACCOUNT_LIMIT,
+            lockout: LockoutPolicy::default(),
+            trusted_proxies: Vec::new(),
+        }
+    }
+
+    /// Limits the requests of each client IP to a route, given by its pattern within
+    /// the API versions such as `/users/auth/login`
+    pub fn route(mut self, method: Method, pattern: &'static str, limit: Limit) -> Self {
+        self.routes.push((method, pattern, limit));
+        self
+    }
+
+    /// Limits the login attempts of each account
+    pub fn account_limit(mut self, limit: Limit) -> Self {
+        self.account_limit = limit;
+        self
+    }
+
+    pub fn lockout(mut self, policy: LockoutPolicy) -> Self {
+        self.lockout = policy;
+        self
+    }
+
+    /// Identifies clients by the `X-Forwarded-For` of requests coming from these proxies,
+    /// any other request by its peer address
+    pub fn trusted_proxies(mut self, proxies: Vec<IpNet>) -> Self {
+        self.trusted_proxies = proxies;
+        self
+    }
+
+    /// Fails while the account is locked or attempts too many logins
+    pub async fn check_account(&self, account: &str) -> Result<(), ErrMessage> {
+        let account = account.to_lowercase();
+
+        if let Some(retry_after) = self.store.locked_for(account.clone()).await? {
+            return Err(ErrMessage::RateLimited {
+                code: ErrorCode::AccountLocked,
+                message: "The account is temporarily locked after too many failed logins",
+                retry_after,
+            });
+        }
+
+        match self
+            .store
+            .take(format!("account:{}", account), self.account_limit)
+            .await?
+        {
+            Decision::Allowed => Ok(()),
+            Decision::Limited(retry_after) => Err(too_many_requests(retry_after)),
+        }
+    }
+
+    /// Records a failed login, then waits for a delay growing with consecutive failures
+    pub async fn login_failed(&self, account: &str) {
+        match self
+            .store
+            .login_failed(account.to_lowercase(), self.lockout)
+            .await
+        {
+            Ok(delay) => sleep(delay).await,
+            Err(err) => error!("rate limit: {}", err),
+        }
+    }
+
+    pub async fn login_succeeded(&self, account: &str) {
+        if let Err(err) = self.store.login_succeeded(account.to_lowercase()).await {
+            error!("rate limit: {}", err);
+        }
+    }
+
+    fn route_limit(&self, req: &ServiceRequest) -> Option<(&'static str, Limit)> {
+        let pattern = req.match_pattern()?;
+        let pattern = ApiVersion::ALL
+            .iter()
+            .find_map(|version| pattern.strip_prefix(&version.prefix()))
+            .unwrap_or(&pattern);
+
+        self.routes
+            .iter()
+            .find(|(method, route, _)| method == req.method() && *route == pattern)
+            .map(|(_, route, limit)| (*route, *limit))
+    }
+
+    // The last X-Forwarded-For address is the one the trusted proxy saw, those before it
+    // come from the client
+    fn client_ip(&self, req: &ServiceRequest) -> String {
+        let Some(peer) = req.peer_addr().map(|addr| addr.ip()) else {
+            return "unknown".to_string();
+        };
+        let trusted = self
+            .trusted_proxies
+            .iter()
+            .any(|proxy| proxy.contains(&peer));
+        if !trusted {
+            return peer.to_string();
+        }
+
+        req.headers()
+            .get_all(X_FORWARDED_FOR)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()
+            .and_then(|addr| addr.trim().parse::<IpAddr>().ok())
+            .unwrap_or(peer)
+            .to_string()
+    }
+}
+
+/// Middleware limiting requests by client IP, to the routes configured in the
+/// [`RateLimiter`] of the app. Must be wrapped by `NormalizePath`, so routes are matched
+/// on normalized paths.
+///
+/// Use with `actix_web::middleware::from_fn(rate_limit::middleware)`.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let limiter = req.app_data::<Data<RateLimiter>>().cloned();
+
+    if let Some((limiter, (route, limit))) = limiter
+        .as_ref()
+        .and_then(|limiter| Some((limiter, limiter.route_limit(&req)?)))
+    {
+        let key = format!("ip:{}:{} {}", limiter.client_ip(&req), req.method(), route);
+        let decision = match limiter.store.take(key, limit).await {
+            Ok(decision) => decision,
+            Err(err) => return Ok(req.error_response(err).map_into_right_body()),
+        };
+
+        if let Decision::Limited(retry_after) = decision {
+            let res = too_many_requests(retry_after).error_response();
+            return Ok(req.into_response(res).map_into_right_body());
+        }
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+fn too_many_requests(retry_after: Duration) -> ErrMessage {
+    ErrMessage::RateLimited {
+        code: ErrorCode::TooManyRequests,
+        message: "Too many requests, retry later",
+        retry_after,
+    }
+}
+
+fn prune_due(updates: &AtomicU32) -> bool {
+    updates.fetch_add(1, Ordering::Relaxed) % PRUNE_EVERY == PRUNE_EVERY - 1
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+fn delta(duration: Duration) -> TimeDelta {
+    TimeDelta::from_std(duration).unwrap_or(TimeDelta::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_web::test]
+    async fn failures_lock_the_account() {
+        let policy = LockoutPolicy::default();
+        let start = now();
+        let mut failures = Failures::new(start);
+
+        let delays = (0..5)
+            .map(|_| failures.record(&policy, start))
+            .collect::<Vec<_>>();
+        assert_eq!(delays[0], Duration::from_millis(250));
+        assert_eq!(delays[1], Duration::from_millis(500));
+        assert_eq!(delays[4], Duration::from_secs(4));
+        assert_eq!(failures.locked_for(start), Some(policy.lock_for));
+
+        let unlocked = start + delta(policy.lock_for);
+        assert_eq!(failures.locked_for(unlocked), None);
+        assert_eq!(failures.record(&policy, unlocked), policy.base_delay);
+        assert_eq!(failures.count, 1);
+    }
+
+    #[actix_web::test]
+    async fn limits_configured_routes_by_ip() {
+        let limiter = RateLimiter::new(Arc::new(MemoryStore::default()))
+            .route(
+                Method::POST,
+                "/auth/login",
+                Limit::new(2, Duration::from_secs(60)),
+            )
+            .trusted_proxies(vec!["127.0.0.0/8".parse().unwrap()]);
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(limiter))
+                .wrap(from_fn(middleware))
+                .route("/api/v0/auth/login", web::post().to(HttpResponse::Ok))
+                .route("/api/v1/auth/login", web::post().to(HttpResponse::Ok))
+                .route("/api/v1/auth", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let call = |uri: &'static str, ip: &'static str| {
+            test::TestRequest::post()
+                .uri(uri)
+                .peer_addr("127.0.0.1:40000".parse().unwrap())
+                .insert_header(("x-forwarded-for", ip))
+                .to_request()
+        };
+
+        for uri in ["/api/v0/auth/login", "/api/v1/auth/login"] {
+            let res = test::call_service(&app, call(uri, "10.0.0.1")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        let res = test::call_service(&app, call("/api/v1/auth/login", "10.0.0.1")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("retry-after").unwrap(), "60");
+
+        let res = test::call_service(&app, call("/api/v1/auth/login", "10.0.0.2")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = test::call_service(&app, call("/api/v1/auth", "10.0.0.1")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn trusts_forwarded_addresses_only_from_proxies() {
+        let limiter = RateLimiter::new(Arc::new(MemoryStore::default()))
+            .route(
+                Method::POST,
+                "/auth/login",
+                Limit::new(1, Duration::from_secs(60)),
+            )
+            .trusted_proxies(vec!["10.0.0.1/32".parse().unwrap()]);
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(limiter))
+                .wrap(from_fn(middleware))
+                .route("/auth/login", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let call = |peer: &'static str, forwarded_for: &'static str| {
+            test::TestRequest::post()
+                .uri("/auth/login")
+                .peer_addr(peer.parse().unwrap())
+                .insert_header(("x-forwarded-for", forwarded_for))
+                .to_request()
+        };
+
+        // Clients can't pick the address they are limited by
+        let res = test::call_service(&app, call("192.168.0.1:40000", "172.16.0.1")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = test::call_service(&app, call("192.168.0.1:40000", "172.16.0.2")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Only the address the proxy saw counts, not those the client sent it
+        let proxied = "172.16.0.1, 192.168.0.2";
+        let res = test::call_service(&app, call("10.0.0.1:40000", proxied)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = test::call_service(&app, call("10.0.0.1:40000", "192.168.0.2")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let res = test::call_service(&app, call("10.0.0.1:40000", "192.168.0.1")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -0,0 +1,286 @@
+use std::future::Future;
+
+use chrono::NaiveDateTime;
+#[cfg(feature = "sqlite")]
+use diesel::SqliteConnection;
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, QueryResult};
+#[cfg(feature = "sqlite")]
+use diesel_async::sync_connection_wrapper::SyncConnectionWrapper;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use futures_util::future::BoxFuture;
+
+use crate::database::{self, with_connection, DBConnPool, ReadFrom};
+use crate::messages::ErrMessage;
+use crate::models::{FeedItem, NewFeedItem};
+use crate::schema::feeditems::dsl::*;
+
+/// Checks a feed item inside the transaction changing it, such as whether the user may
+/// edit it
+pub type FeedItemCheck = Box<dyn FnOnce(&FeedItem) -> Result<(), ErrMessage> + Send>;
+
+/// Keeps the feed items. Changes return `None` when the feed item does not exist, reads
+/// from a replica may miss the latest changes.
+pub trait FeedRepository: Send + Sync {
+    /// Feed items updated before `before`, the latest first
+    fn list(
+        &self,
+        before: NaiveDateTime,
+        limit: i64,
+        read_from: ReadFrom,
+    ) -> BoxFuture<'_, Result<Vec<FeedItem>, ErrMessage>>;
+
+    fn find(
+        &self,
+        feed_id: i32,
+        read_from: ReadFrom,
+    ) -> BoxFuture<'_, Result<Option<FeedItem>, ErrMessage>>;
+
+    fn create(&self, feed_item: NewFeedItem) -> BoxFuture<'_, Result<FeedItem, ErrMessage>>;
+
+    fn update_caption(
+        &self,
+        feed_id: i32,
+        new_caption: String,
+        check: FeedItemCheck,
+    ) -> BoxFuture<'_, Result<Option<FeedItem>, ErrMessage>>;
+
+    fn delete(
+        &self,
+        feed_id: i32,
+        check: FeedItemCheck,
+    ) -> BoxFuture<'_, Result<Option<FeedItem>, ErrMessage>>;
+
+    /// Records whether the feed item of the media has an animated thumbnail, clearing any
+    /// earlier processing error
+    fn set_animated(&self, key: String, is_animated: bool)
+        -> BoxFuture<'_, Result<(), ErrMessage>>;
+
+    /// Records why the media of the feed item can't be processed
+    fn set_processing_error(
+        &self,
+        key: String,
+        error: String,
+    ) -> BoxFuture<'_, Result<(), ErrMessage>>;
+
+    /// Media keys of the feed items created since `since`, or of the one with `key`,
+    /// the oldest first
+    fn image_ids(
+        &self,
+        since: Option<NaiveDateTime>,
+        key: Option<String>,
+    ) -> BoxFuture<'_, Result<Vec<String>, ErrMessage>>;
+}
+
+/// Keeps the feed items in the `feeditems` table
+pub struct DbFeedRepository {
+    pool: DBConnPool,
+}
+
+impl DbFeedRepository {
+    pub fn new(pool: DBConnPool) -> Self {
+        DbFeedRepository { pool }
+    }
+}
+
+impl FeedRepository for DbFeedRepository {
+    fn list(
+        &self,
+        before: NaiveDateTime,
+        limit: i64,
+        read_from: ReadFrom,
+    ) -> BoxFuture<'_, Result<Vec<FeedItem>, ErrMessage>> {
+        database::query(async move {
+            let mut conn = self.pool.read(read_from).await?;
+            let feed_items = with_connection!(&mut *conn, |conn| {
+                feeditems
+                    .filter(updated_at.lt(before))
+                    .order_by(updated_at.desc())
+                    .limit(limit)
+                    .load::<FeedItem>(conn)
+                    .await
+            })?;
+            Ok(feed_items)
+        })
+    }
+
+    fn find(
+        &self,
+        feed_id: i32,
+        read_from: ReadFrom,
+    ) -> BoxFuture<'_, Result<Option<FeedItem>, ErrMessage>> {
+        database::query(async move {
+            let mut conn = self.pool.read(read_from).await?;
+            let feed_item = with_connection!(&mut *conn, |conn| {
+                feeditems
+                    .find(feed_id)
+                    .get_result::<FeedItem>(conn)
+                    .await
+                    .optional()
+            })?;
+            Ok(feed_item)
+        })
+    }
+
+    fn create(&self, feed_item: NewFeedItem) -> BoxFuture<'_, Result<FeedItem, ErrMessage>> {
+        database::query(async move {
+            let mut conn = self.pool.get().await?;
+            let feed_item = with_connection!(&mut *conn, |conn| {
+                diesel::insert_into(feeditems)
+                    .values((
+                        caption.eq(&feed_item.caption),
+                        image_id.eq(&feed_item.image_id),
+                        created_by.eq(&feed_item.created_by),
+                        created_at.eq(diesel::dsl::now),
+                        updated_at.eq(diesel::dsl::now),
+                    ))
+                    .get_result::<FeedItem>(conn)
+                    .await
+            })?;
+            Ok(feed_item)
+        })
+    }
+
+    fn update_caption(
+        &self,
+        feed_id: i32,
+        new_caption: String,
+        check: FeedItemCheck,
+    ) -> BoxFuture<'_, Result<Option<FeedItem>, ErrMessage>> {
+        database::query(async move {
+            let mut conn = self.pool.get().await?;
+            with_connection!(&mut *conn, |conn| {
+                conn.transaction(async |conn| {
+                    let feed_item = match conn.lock_feed_item(feed_id).await? {
+                        Some(feed_item) => feed_item,
+                        None => return Ok(None),
+                    };
+                    check(&feed_item)?;
+
+                    let feed_item = diesel::update(feeditems.find(feed_id))
+                        .set((caption.eq(new_caption), updated_at.eq(diesel::dsl::now)))
+                        .get_result::<FeedItem>(conn)
+                        .await?;
+                    Ok(Some(feed_item))
+                })
+                .await
+            })
+        })
+    }
+
+    fn delete(
+        &self,
+        feed_id: i32,
+        check: FeedItemCheck,
+    ) -> BoxFuture<'_, Result<Option<FeedItem>, ErrMessage>> {
+        database::query(async move {
+            let mut conn = self.pool.get().await?;
+            with_connection!(&mut *conn, |conn| {
+                conn.transaction(async |conn| {
+                    let feed_item = match conn.lock_feed_item(feed_id).await? {
+                        Some(feed_item) => feed_item,
+                        None => return Ok(None),
+                    };
+                    check(&feed_item)?;
+
+                    let feed_item = diesel::delete(feeditems.find(feed_id))
+                        .get_result::<FeedItem>(conn)
+                        .await?;
+                    Ok(Some(feed_item))
+                })
+                .await
+            })
+        })
+    }
+
+    fn set_animated(
+        &self,
+        key: String,
+        is_animated: bool,
+    ) -> BoxFuture<'_, Result<(), ErrMessage>> {
+        database::query(async move {
+            let mut conn = self.pool.get().await?;
+            with_connection!(&mut *conn, |conn| {
+                diesel::update(feeditems.filter(image_id.eq(&key)))
+                    .set((
+                        animated.eq(is_animated),
+                        processing_error.eq(None::<String>),
+                    ))
+                    .execute(conn)
+                    .await
+            })?;
+            Ok(())
+        })
+    }
+
+    fn set_processing_error(
+        &self,
+        key: String,
+        error: String,
+    ) -> BoxFuture<'_, Result<(), ErrMessage>> {
+        database::query(async move {
+            let mut conn = self.pool.get().await?;
+            with_connection!(&mut *conn, |conn| {
+                diesel::update(feeditems.filter(image_id.eq(&key)))
+                    .set(processing_error.eq(&error))
+                    .execute(conn)
+                    .await
+            })?;
+            Ok(())
+        })
+    }
+
+    fn image_ids(
+        &self,
+        since: Option<NaiveDateTime>,
+        key: Option<String>,
+    ) -> BoxFuture<'_, Result<Vec<String>, ErrMessage>> {
+        database::query(async move {
+            let mut conn = self.pool.get().await?;
+            let keys = with_connection!(&mut *conn, |conn| {
+                let mut query = feeditems
+                    .select(image_id)
+                    .order_by(created_at.asc())
+                    .into_boxed();
+                if let Some(since) = since {
+                    query = query.filter(created_at.ge(since));
+                }
+                if let Some(key) = &key {
+                    query = query.filter(image_id.eq(key.clone()));
+                }
+                query.load::<String>(conn).await
+            })?;
+            Ok(keys)
+        })
+    }
+}
+
+// Loads a feed item, locking it until the surrounding transaction ends
+trait LockFeedItem {
+    fn lock_feed_item(
+        &mut self,
+        feed_id: i32,
+    ) -> impl Future<Output = QueryResult<Option<FeedItem>>> + Send;
+}
+
+impl LockFeedItem for AsyncPgConnection {
+    async fn lock_feed_item(&mut self, feed_id: i32) -> QueryResult<Option<FeedItem>> {
+        feeditems
+            .find(feed_id)
+            .for_update()
+            .get_result::<FeedItem>(self)
+            .await
+            .optional()
+    }
+}
+
+// SQLite has no row locks, writing locks the whole database instead
+#[cfg(feature = "sqlite")]
+impl LockFeedItem for SyncConnectionWrapper<SqliteConnection> {
+    async fn lock_feed_item(&mut self, feed_id: i32) -> QueryResult<Option<FeedItem>> {
+        feeditems
+            .find(feed_id)
+            .get_result::<FeedItem>(self)
+            .await
+            .optional()
+    }
+}
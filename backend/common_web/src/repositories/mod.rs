@@ -0,0 +1,5 @@
+mod feed;
+mod users;
+
+pub use feed::{DbFeedRepository, FeedItemCheck, FeedRepository};
+pub use users::{DbUserRepository, UserRepository};
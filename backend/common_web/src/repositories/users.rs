@@ -0,0 +1,62 @@
+use diesel::{ExpressionMethods, OptionalExtension, QueryDsl};
+use diesel_async::RunQueryDsl;
+use futures_util::future::BoxFuture;
+
+use crate::database::{self, with_connection, DBConnPool};
+use crate::messages::ErrMessage;
+use crate::models::User;
+use crate::schema::users::dsl::*;
+
+/// Keeps the users
+pub trait UserRepository: Send + Sync {
+    fn find(&self, user_email: String) -> BoxFuture<'_, Result<Option<User>, ErrMessage>>;
+
+    /// Creates a user with the hash of its password, failing with a conflict when the
+    /// email is taken
+    fn create(&self, user_email: String, hash: String) -> BoxFuture<'_, Result<User, ErrMessage>>;
+}
+
+/// Keeps the users in the `users` table
+pub struct DbUserRepository {
+    pool: DBConnPool,
+}
+
+impl DbUserRepository {
+    pub fn new(pool: DBConnPool) -> Self {
+        DbUserRepository { pool }
+    }
+}
+
+impl UserRepository for DbUserRepository {
+    fn find(&self, user_email: String) -> BoxFuture<'_, Result<Option<User>, ErrMessage>> {
+        database::query(async move {
+            let mut conn = self.pool.get().await?;
+            let user = with_connection!(&mut *conn, |conn| {
+                users
+                    .find(&user_email)
+                    .get_result::<User>(conn)
+                    .await
+                    .optional()
+            })?;
+            Ok(user)
+        })
+    }
+
+    fn create(&self, user_email: String, hash: String) -> BoxFuture<'_, Result<User, ErrMessage>> {
+        database::query(async move {
+            let mut conn = self.pool.get().await?;
+            let user = with_connection!(&mut *conn, |conn| {
+                diesel::insert_into(users)
+                    .values((
+                        email.eq(&user_email),
+                        password_hash.eq(&hash),
+                        created_at.eq(diesel::dsl::now),
+                        updated_at.eq(diesel::dsl::now),
+                    ))
+                    .get_result::<User>(conn)
+                    .await
+            })?;
+            Ok(user)
+        })
+    }
+}
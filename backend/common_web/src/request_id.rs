@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::Error;
+
+use common::telemetry::{self, TRACEPARENT, TRACESTATE};
+
+use tracing::{field, info, info_span, Instrument};
+use uuid::Uuid;
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Longer ids sent by clients are replaced, to keep them out of logs and responses
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Id of the request currently being handled, if any
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Middleware assigning every request an id, reusing the `X-Request-Id` header
+/// of the request when present and echoing it in the response. The request is
+/// handled in a span carrying that id, which continues the trace of the
+/// `traceparent` header when present.
+///
+/// Use with `actix_web::middleware::from_fn(request_id::middleware)`.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let id = req
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LEN)
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().to_simple().to_string());
+
+    let span = info_span!(
+        "http.request",
+        request_id = %id,
+        method = %req.method(),
+        path = %req.path(),
+        route = field::Empty,
+        status = field::Empty,
+    );
+    telemetry::set_remote_parent(&span, &trace_context(&req));
+
+    let start = Instant::now();
+    let mut res = REQUEST_ID
+        .scope(id.clone(), next.call(req))
+        .instrument(span.clone())
+        .await?;
+
+    if let Some(route) = res.request().match_pattern() {
+        span.record("route", route.as_str());
+    }
+    span.record("status", res.status().as_u16());
+    span.in_scope(|| {
+        info!(
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "request completed"
+        )
+    });
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut().insert(X_REQUEST_ID.clone(), value);
+    }
+
+    Ok(res)
+}
+
+fn trace_context(req: &ServiceRequest) -> HashMap<String, String> {
+    [TRACEPARENT, TRACESTATE]
+        .into_iter()
+        .filter_map(|name| {
+            let value = req.headers().get(name)?.to_str().ok()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, web, App, HttpResponse};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn propagates_request_ids() {
+        let app = test::init_service(App::new().wrap(from_fn(middleware)).route(
+            "/",
+            web::get().to(|| async { HttpResponse::Ok().body(current().unwrap()) }),
+        ))
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/")
+            .insert_header((X_REQUEST_ID.clone(), "client-id"))
+            .to_request();
+        let res = test::call_service(&app, request).await;
+        assert_eq!(res.headers().get(&X_REQUEST_ID).unwrap(), "client-id");
+        assert_eq!(test::read_body(res).await, "client-id");
+
+        for header in [None, Some("x".repeat(MAX_REQUEST_ID_LEN + 1))] {
+            let mut request = test::TestRequest::get().uri("/");
+            if let Some(header) = &header {
+                request = request.insert_header((X_REQUEST_ID.clone(), header.as_str()));
+            }
+            let res = test::call_service(&app, request.to_request()).await;
+
+            let id = res.headers().get(&X_REQUEST_ID).unwrap().clone();
+            assert_eq!(id.len(), 32);
+            assert_eq!(test::read_body(res).await, id.as_bytes());
+        }
+
+        assert!(current().is_none());
+    }
+}
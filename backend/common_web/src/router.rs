@@ -1,40 +1,150 @@
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+
 use actix_web::{
-    dev::HttpServiceFactory,
+    dev::{HttpServiceFactory, Payload},
+    middleware::DefaultHeaders,
     web::{self, ServiceConfig},
-    Scope,
+    FromRequest, HttpRequest, Scope,
+};
+
+use chrono::{DateTime, Utc};
+
+use utoipa::openapi::{
+    path::{Operation, PathItem, PathsBuilder},
+    Components, Deprecated, OpenApi, OpenApiBuilder,
 };
+use utoipa_actix_web::OpenApiFactory;
 
 pub struct RouteBuilder<'a> {
     srv_cfg: &'a mut ServiceConfig,
     scopes: Vec<Scope>,
+    // Path of each scope, used to document routes with their full path
+    bases: Vec<String>,
+    version: Option<VersionScope>,
+    openapi: OpenApi,
 }
 
 pub trait Router {
     fn build(route_builder: RouteBuilder<'_>) -> RouteBuilder<'_>;
 }
 
+/// Versions of the public API, each served under `/api/<version>`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ApiVersion {
+    V0,
+    V1,
+}
+
+impl ApiVersion {
+    pub const ALL: [ApiVersion; 2] = [ApiVersion::V0, ApiVersion::V1];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiVersion::V0 => "v0",
+            ApiVersion::V1 => "v1",
+        }
+    }
+
+    pub fn prefix(&self) -> String {
+        format!("/api/{}", self.as_str())
+    }
+}
+
+/// Handlers take the version they are served under as an argument to answer in its
+/// shape. Routes mounted outside of [`RouteBuilder::versioned`] answer as v0, which
+/// predates versioning.
+impl FromRequest for ApiVersion {
+    type Error = Infallible;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(req
+            .app_data::<ApiVersion>()
+            .copied()
+            .unwrap_or(ApiVersion::V0)))
+    }
+}
+
+/// An API version along with its deprecation schedule. Responses of deprecated versions
+/// carry the `Deprecation` header (RFC 9745), and the `Sunset` header (RFC 8594) once a
+/// removal date is known.
+#[derive(Clone, Copy, Debug)]
+pub struct VersionScope {
+    version: ApiVersion,
+    deprecated_at: Option<DateTime<Utc>>,
+    sunset_at: Option<DateTime<Utc>>,
+}
+
+impl VersionScope {
+    pub fn new(version: ApiVersion) -> Self {
+        VersionScope {
+            version,
+            deprecated_at: None,
+            sunset_at: None,
+        }
+    }
+
+    pub fn deprecated(mut self, deprecated_at: DateTime<Utc>) -> Self {
+        self.deprecated_at = Some(deprecated_at);
+        self
+    }
+
+    pub fn sunset(mut self, sunset_at: DateTime<Utc>) -> Self {
+        self.sunset_at = Some(sunset_at);
+        self
+    }
+
+    pub fn is_deprecated(&self) -> bool {
+        self.deprecated_at.is_some()
+    }
+
+    fn headers(&self) -> DefaultHeaders {
+        let mut headers = DefaultHeaders::new();
+        if let Some(deprecated_at) = self.deprecated_at {
+            headers = headers.add(("Deprecation", format!("@{}", deprecated_at.timestamp())));
+        }
+        if let Some(sunset_at) = self.sunset_at {
+            headers = headers.add((
+                "Sunset",
+                sunset_at.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            ));
+        }
+        headers
+    }
+}
+
 impl<'a> RouteBuilder<'a> {
     pub fn new<'b>(srv_cfg: &'b mut ServiceConfig) -> RouteBuilder<'b> {
         RouteBuilder {
             srv_cfg,
             scopes: vec![web::scope("")],
+            bases: vec![String::new()],
+            version: None,
+            openapi: OpenApiBuilder::new().build(),
         }
     }
 
+    /// Mounts a handler in the current scope. Handlers must be annotated with
+    /// `#[utoipa::path]` so they appear in the OpenAPI document of the service.
     pub fn mount<F>(mut self, service: F) -> Self
     where
-        F: HttpServiceFactory + 'static,
+        F: HttpServiceFactory + OpenApiFactory + 'static,
     {
+        self.document(&service);
+
         if let Some(top) = self.scopes.pop() {
             self.scopes.push(top.service(service));
         }
         self
     }
 
-    pub fn extend<R: Router>(mut self, base: &'static str) -> Self {
+    pub fn extend<R: Router>(mut self, base: &str) -> Self {
         self.scopes.push(web::scope(base));
+        self.bases.push(normalize_base(base));
         self = R::build(self);
         let last = self.scopes.pop().unwrap();
+        self.bases.pop();
 
         if let Some(top) = self.scopes.pop() {
             self.scopes.push(top.service(last));
@@ -42,8 +152,210 @@ impl<'a> RouteBuilder<'a> {
         self
     }
 
-    pub fn build(mut self) {
+    /// Mounts the routes added by `build` under the prefix of an API version. Routers
+    /// extended from `build` may use [`RouteBuilder::api_version`] to mount handlers
+    /// specific to that version, while handlers mounted unconditionally are shared.
+    pub fn versioned<F>(mut self, scope: VersionScope, build: F) -> Self
+    where
+        F: FnOnce(Self) -> Self,
+    {
+        let prefix = scope.version.prefix();
+        self.scopes
+            .push(web::scope(&prefix).app_data(scope.version));
+        self.bases.push(prefix);
+        self.version = Some(scope);
+        self = build(self);
+        let last = self.scopes.pop().unwrap();
+        self.bases.pop();
+        self.version = None;
+
+        if let Some(top) = self.scopes.pop() {
+            self.scopes.push(top.service(last.wrap(scope.headers())));
+        }
+        self
+    }
+
+    /// Version of the scope routes are currently mounted in
+    pub fn api_version(&self) -> Option<ApiVersion> {
+        self.version.map(|scope| scope.version)
+    }
+
+    /// Registers all mounted routes and returns their OpenAPI document
+    pub fn build(mut self) -> OpenApi {
+        let top = self.scopes.pop().unwrap();
+        self.srv_cfg.service(top);
+        self.openapi
+    }
+
+    /// Registers all mounted routes after the services `docs` registers with their
+    /// OpenAPI document, such as [`crate::openapi::configure`]. Services registered after
+    /// the routes are never reached, the routes are served from a scope matching any path.
+    pub fn build_with_docs<F>(mut self, docs: F)
+    where
+        F: FnOnce(&mut ServiceConfig, OpenApi),
+    {
         let top = self.scopes.pop().unwrap();
+        docs(self.srv_cfg, self.openapi);
         self.srv_cfg.service(top);
     }
+
+    fn document<F: OpenApiFactory>(&mut self, service: &F) {
+        let base = self.bases.concat();
+
+        let paths = service
+            .paths()
+            .paths
+            .into_iter()
+            .fold(PathsBuilder::new(), |paths, (path, mut item)| {
+                if let Some(scope) = self.version {
+                    // The same handler may be mounted in several versions
+                    for operation in operations_mut(&mut item) {
+                        operation.operation_id = operation
+                            .operation_id
+                            .take()
+                            .map(|id| format!("{}_{}", scope.version.as_str(), id));
+                        if scope.is_deprecated() {
+                            operation.deprecated = Some(Deprecated::True);
+                        }
+                    }
+                }
+                paths.path(format!("{}{}", base, path), item)
+            })
+            .build();
+        self.openapi.paths.merge(paths);
+
+        let mut schemas = Vec::new();
+        service.schemas(&mut schemas);
+        self.openapi
+            .components
+            .get_or_insert_with(Components::new)
+            .schemas
+            .extend(schemas);
+    }
+}
+
+// actix-web prefixes scope paths with a slash when missing
+fn normalize_base(base: &str) -> String {
+    if base.is_empty() || base.starts_with('/') {
+        base.to_string()
+    } else {
+        format!("/{}", base)
+    }
+}
+
+fn operations_mut(item: &mut PathItem) -> impl Iterator<Item = &mut Operation> {
+    [
+        &mut item.get,
+        &mut item.put,
+        &mut item.post,
+        &mut item.delete,
+        &mut item.options,
+        &mut item.head,
+        &mut item.patch,
+        &mut item.trace,
+        &mut item.query,
+    ]
+    .into_iter()
+    .flatten()
+    .chain(item.additional_operations.values_mut())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{get, test, App, HttpResponse};
+    use chrono::TimeZone;
+
+    #[utoipa::path(responses((status = 200)))]
+    #[get("/ping")]
+    async fn ping() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    struct PingRouter;
+    impl Router for PingRouter {
+        fn build(route_builder: RouteBuilder<'_>) -> RouteBuilder<'_> {
+            route_builder.mount(ping)
+        }
+    }
+
+    #[utoipa::path(responses((status = 200)))]
+    #[get("/version")]
+    async fn serving_version(version: ApiVersion) -> HttpResponse {
+        HttpResponse::Ok().body(version.as_str())
+    }
+
+    struct VersionRouter;
+    impl Router for VersionRouter {
+        fn build(route_builder: RouteBuilder<'_>) -> RouteBuilder<'_> {
+            route_builder.mount(serving_version)
+        }
+    }
+
+    #[actix_web::test]
+    async fn handlers_know_the_version_serving_them() {
+        let app = test::init_service(App::new().configure(|srv| {
+            RouteBuilder::new(srv)
+                .versioned(VersionScope::new(ApiVersion::V0), |routes| {
+                    routes.extend::<VersionRouter>("/test")
+                })
+                .versioned(VersionScope::new(ApiVersion::V1), |routes| {
+                    routes.extend::<VersionRouter>("/test")
+                })
+                .extend::<VersionRouter>("/test")
+                .build();
+        }))
+        .await;
+
+        for (uri, expected) in [
+            ("/api/v0/test/version", "v0"),
+            ("/api/v1/test/version", "v1"),
+            ("/test/version", "v0"),
+        ] {
+            let request = test::TestRequest::get().uri(uri).to_request();
+            assert_eq!(test::call_and_read_body(&app, request).await, expected);
+        }
+    }
+
+    #[actix_web::test]
+    async fn deprecated_versions_send_headers() {
+        let v0 = VersionScope::new(ApiVersion::V0)
+            .deprecated(Utc.with_ymd_and_hms(2022, 3, 1, 0, 0, 0).unwrap())
+            .sunset(Utc.with_ymd_and_hms(2022, 9, 1, 0, 0, 0).unwrap());
+
+        let app = test::init_service(App::new().configure(|srv| {
+            RouteBuilder::new(srv)
+                .versioned(v0, |routes| routes.extend::<PingRouter>("/test"))
+                .versioned(VersionScope::new(ApiVersion::V1), |routes| {
+                    routes.extend::<PingRouter>("/test")
+                })
+                .build();
+        }))
+        .await;
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/api/v0/test/ping")
+                .to_request(),
+        )
+        .await;
+        assert!(res.status().is_success());
+        assert_eq!(res.headers().get("Deprecation").unwrap(), "@1646092800");
+        assert_eq!(
+            res.headers().get("Sunset").unwrap(),
+            "Thu, 01 Sep 2022 00:00:00 GMT"
+        );
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/api/v1/test/ping")
+                .to_request(),
+        )
+        .await;
+        assert!(res.status().is_success());
+        assert!(res.headers().get("Deprecation").is_none());
+    }
 }
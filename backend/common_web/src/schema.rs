@@ -6,6 +6,25 @@ table! {
         caption -> Nullable<Varchar>,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        animated -> Bool,
+        processing_error -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    login_failures (account) {
+        account -> Varchar,
+        failures -> Int4,
+        locked_until -> Nullable<Timestamp>,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    rate_limits (key) {
+        key -> Varchar,
+        tokens -> Float8,
+        updated_at -> Timestamp,
     }
 }
 
@@ -21,5 +40,7 @@ table! {
 
 allow_tables_to_appear_in_same_query!(
     feeditems,
+    login_failures,
+    rate_limits,
     users,
 );
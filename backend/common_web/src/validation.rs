@@ -0,0 +1,151 @@
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest};
+
+use validator::{Validate, ValidationErrors, ValidationErrorsKind};
+
+use crate::messages::{ErrMessage, FieldError};
+
+/// Extractor running the `validator::Validate` checks of a request after extracting it
+/// with `T`, such as `Valid<Json<CreateFeedItemRequest>>`. Every failing check is reported
+/// at once, as field errors of a validation problem.
+pub struct Valid<T>(pub T);
+
+impl<T> Valid<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Valid<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T, V> FromRequest for Valid<T>
+where
+    T: FromRequest + Deref<Target = V> + 'static,
+    V: Validate,
+{
+    type Error = actix_web::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let extracted = T::from_request(req, payload);
+
+        Box::pin(async move {
+            let extracted = extracted.await.map_err(Into::into)?;
+
+            extracted
+                .validate()
+                .map_err(|errors| ErrMessage::Validation(field_errors(&errors)))?;
+
+            Ok(Valid(extracted))
+        })
+    }
+}
+
+/// Flattens validation errors, naming nested fields like `transformations[0].degrees`
+pub fn field_errors(errors: &ValidationErrors) -> Vec<FieldError> {
+    let mut result = Vec::new();
+    collect_field_errors(errors, "", &mut result);
+    result
+}
+
+fn collect_field_errors(errors: &ValidationErrors, prefix: &str, result: &mut Vec<FieldError>) {
+    for (field, kind) in errors.errors() {
+        // Errors of struct level checks are reported on "__all__"
+        let field = match (prefix.is_empty(), field.as_ref()) {
+            (true, field) => field.to_string(),
+            (false, "__all__") => prefix.to_string(),
+            (false, field) => format!("{}.{}", prefix, field),
+        };
+
+        match kind {
+            ValidationErrorsKind::Field(errors) => {
+                for error in errors {
+                    let message = error
+                        .message
+                        .as_ref()
+                        .map(|message| message.to_string())
+                        .unwrap_or_else(|| format!("Invalid {}", field));
+                    result.push(FieldError::new(field.clone(), error.code.clone(), message));
+                }
+            }
+            ValidationErrorsKind::Struct(errors) => collect_field_errors(errors, &field, result),
+            ValidationErrorsKind::List(errors) => {
+                for (index, errors) in errors {
+                    collect_field_errors(errors, &format!("{}[{}]", field, index), result);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{http::StatusCode, test, web::Json};
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Validate)]
+    struct Inner {
+        #[validate(range(min = 1, max = 10, message = "Out of range"))]
+        value: i32,
+    }
+
+    #[derive(Deserialize, Validate)]
+    struct Request {
+        #[validate(length(min = 1, max = 5))]
+        name: String,
+        #[validate(required, email)]
+        email: Option<String>,
+        #[validate(nested)]
+        items: Vec<Inner>,
+    }
+
+    #[actix_web::test]
+    async fn reports_all_errors() {
+        let (req, mut payload) = test::TestRequest::post()
+            .set_json(serde_json::json!({
+                "name": "too long",
+                "items": [{ "value": 1 }, { "value": 11 }]
+            }))
+            .to_http_parts();
+
+        let err = match Valid::<Json<Request>>::from_request(&req, &mut payload).await {
+            Ok(_) => panic!("request should be invalid"),
+            Err(err) => err,
+        };
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::BAD_REQUEST
+        );
+
+        let mut errors = match err.as_error::<ErrMessage>() {
+            Some(ErrMessage::Validation(errors)) => errors.clone(),
+            _ => panic!("expected validation errors"),
+        };
+        errors.sort_by(|a, b| a.field.cmp(&b.field));
+
+        assert_eq!(errors.len(), 3);
+        assert_eq!(
+            (errors[0].field.as_str(), errors[0].code.as_str()),
+            ("email", "required")
+        );
+        assert_eq!(errors[1].field, "items[1].value");
+        assert_eq!(errors[1].message, "Out of range");
+        assert_eq!(
+            (errors[2].field.as_str(), errors[2].code.as_str()),
+            ("name", "length")
+        );
+    }
+}
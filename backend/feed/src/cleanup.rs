@@ -0,0 +1,228 @@
+use std::error::Error;
+use std::fmt::Display;
+
+use chrono::Utc;
+use clap::Args;
+
+use common::aws::s3::{Media, MultipartError};
+use common::aws::S3Bucket;
+use common::config::Config;
+
+#[derive(Args)]
+pub struct CleanupUploadsArgs {
+    /// Only list the multipart uploads that would be aborted
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Debug)]
+enum CleanupError {
+    Failed { failed: usize, total: usize },
+}
+
+/// Aborts the multipart uploads of media started longer ago than
+/// `aws.media_multipart_max_age_secs`, whose parts S3 keeps until then
+pub async fn run(config: &Config, args: CleanupUploadsArgs) -> Result<(), Box<dyn Error>> {
+    let media_bucket = S3Bucket::<Media>::new(config).await;
+    let started_before =
+        Utc::now() - chrono::Duration::from_std(config.aws.media_multipart_max_age)?;
+
+    let uploads = media_bucket
+        .list_multipart_uploads()
+        .await?
+        .into_iter()
+        .filter(|upload| upload.initiated < started_before)
+        .collect::<Vec<_>>();
+    let total = uploads.len();
+    log::info!(
+        "Found {} multipart uploads started before {}",
+        total,
+        started_before
+    );
+
+    let mut failed = 0;
+    for (index, upload) in uploads.iter().enumerate() {
+        if args.dry_run {
+            log::info!(
+                "[{}/{}] would abort the upload of {} started at {}",
+                index + 1,
+                total,
+                upload.key,
+                upload.initiated
+            );
+            continue;
+        }
+
+        let result = media_bucket
+            .abort_multipart_upload(&upload.key, &upload.upload_id)
+            .await;
+
+        match result {
+            // Completed or aborted since it was listed
+            Ok(()) | Err(MultipartError::NoSuchUpload) => {
+                log::info!(
+                    "[{}/{}] aborted the upload of {}",
+                    index + 1,
+                    total,
+                    upload.key
+                )
+            }
+            Err(err) => {
+                failed += 1;
+                log::error!(
+                    "[{}/{}] failed to abort the upload of {}: {}",
+                    index + 1,
+                    total,
+                    upload.key,
+                    err
+                );
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(CleanupError::Failed { failed, total }.into());
+    }
+
+    Ok(())
+}
+
+impl Display for CleanupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CleanupError::Failed { failed, total } => {
+                write!(
+                    f,
+                    "Failed to abort {} of {} multipart uploads",
+                    failed, total
+                )
+            }
+        }
+    }
+}
+
+impl Error for CleanupError {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use aws_sdk_s3::{Client, Credentials, Endpoint, Region};
+    use hyper::{Body, Method, Request};
+
+    use common::aws::s3::MIN_UPLOAD_PART_BYTES;
+    use common::config::{ConfigArgs, Service};
+
+    use super::*;
+
+    // MinIO of the compose `minio` profile, unless MINIO_ENDPOINT names another server
+    const MINIO_ENDPOINT: &str = "http://localhost:9000";
+    const MINIO_USER: &str = "minioadmin";
+
+    async fn put(url: &str, body: Vec<u8>) {
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri(url)
+            .body(Body::from(body))
+            .unwrap();
+        let response = hyper::Client::new().request(request).await.unwrap();
+        assert!(response.status().is_success(), "{}", response.status());
+    }
+
+    async fn pending_keys(media_bucket: &S3Bucket<Media>) -> Vec<String> {
+        let uploads = media_bucket.list_multipart_uploads().await.unwrap();
+        uploads.into_iter().map(|upload| upload.key).collect()
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs MinIO, started with `docker compose --profile minio up minio`"]
+    async fn uploads_in_parts_and_aborts_stale_uploads() {
+        let endpoint =
+            std::env::var("MINIO_ENDPOINT").unwrap_or_else(|_| MINIO_ENDPOINT.to_string());
+        // Read by the config and the AWS SDK, like the services deployed next to MinIO
+        std::env::set_var("AWS_S3_ENDPOINT", &endpoint);
+        std::env::set_var("AWS_ACCESS_KEY_ID", MINIO_USER);
+        std::env::set_var("AWS_SECRET_ACCESS_KEY", MINIO_USER);
+
+        let bucket = format!("cleanup-uploads-{}", std::process::id());
+        let args = ConfigArgs {
+            overrides: vec![
+                "aws.region=us-east-1".to_string(),
+                format!("aws.media_bucket={}", bucket),
+                "aws.media_multipart_max_age_secs=1".to_string(),
+            ],
+            ..ConfigArgs::default()
+        };
+        let config = Config::load(Service::CleanupUploads, &args).await.unwrap();
+
+        // MinIO starts without buckets
+        let client = Client::from_conf(
+            aws_sdk_s3::Config::builder()
+                .region(Region::new("us-east-1"))
+                .credentials_provider(Credentials::new(MINIO_USER, MINIO_USER, None, None, "test"))
+                .endpoint_resolver(Endpoint::immutable(endpoint.parse().unwrap()))
+                .build(),
+        );
+        client.create_bucket().bucket(&bucket).send().await.unwrap();
+        let media_bucket = S3Bucket::<Media>::new(&config).await;
+
+        // Create, upload parts, complete
+        let key = "parts".to_string();
+        let upload_id = media_bucket
+            .create_multipart_upload(&key, "image/png")
+            .await
+            .unwrap();
+        for (part_number, size) in [(1, MIN_UPLOAD_PART_BYTES as usize), (2, 3)] {
+            let url = media_bucket
+                .upload_part_presigned_url(&key, &upload_id, part_number)
+                .await
+                .unwrap();
+            put(&url, vec![0; size]).await;
+        }
+        let parts = media_bucket.list_parts(&key, &upload_id).await.unwrap();
+        assert_eq!(parts.len(), 2);
+        media_bucket
+            .complete_multipart_upload(&key, &upload_id)
+            .await
+            .unwrap();
+        assert_eq!(
+            media_bucket.object_size(&key).await.unwrap(),
+            Some(MIN_UPLOAD_PART_BYTES + 3)
+        );
+
+        // Abort
+        let upload_id = media_bucket
+            .create_multipart_upload("aborted", "image/png")
+            .await
+            .unwrap();
+        media_bucket
+            .abort_multipart_upload("aborted", &upload_id)
+            .await
+            .unwrap();
+        assert!(matches!(
+            media_bucket
+                .abort_multipart_upload("aborted", &upload_id)
+                .await,
+            Err(MultipartError::NoSuchUpload)
+        ));
+
+        // Cleanup of uploads older than aws.media_multipart_max_age_secs
+        media_bucket
+            .create_multipart_upload("stale", "image/png")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        run(&config, CleanupUploadsArgs { dry_run: true })
+            .await
+            .unwrap();
+        assert_eq!(pending_keys(&media_bucket).await, vec!["stale".to_string()]);
+        run(&config, CleanupUploadsArgs { dry_run: false })
+            .await
+            .unwrap();
+        assert!(pending_keys(&media_bucket).await.is_empty());
+
+        media_bucket.delete_object(&key).await.unwrap();
+        client.delete_bucket().bucket(&bucket).send().await.unwrap();
+    }
+}
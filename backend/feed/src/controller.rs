@@ -1,7 +1,6 @@
-use std::sync::Arc;
-
-use actix_web::http::StatusCode;
-use actix_web::web::{block, Data, Json, Path, Query};
+use actix_web::http::header::{ETag, EntityTag, IfMatch, IF_MATCH, SET_COOKIE};
+use actix_web::web::{Data, Json, Path, Query};
+use actix_web::{HttpRequest, Responder};
 
 use actix_web::{delete, get, patch, post};
 
@@ -9,73 +8,122 @@ use common::config::Config;
 use uuid::Uuid;
 
 use chrono::Utc;
-use common::aws::s3::Media;
-use common::aws::S3Bucket;
+use common::aws::s3::{
+    animated_thumbnail_key, Media, MultipartError, UploadedPart, MAX_UPLOAD_PARTS,
+    MIN_UPLOAD_PART_BYTES, PRESIGNED_URL_MIN_LIFETIME,
+};
+use common::aws::{S3Bucket, SQSQueue};
+use common::commands::ImageCommand;
 
-use common_web::database::DBConnPool;
+use common_web::caching::{etag_version, Cached, CachedMessage};
+use common_web::database::{ReadFrom, RecentWrites};
 use common_web::guards::IsLoggedIn;
-use common_web::messages::{ErrMessage, Message, OkMessage};
-use common_web::models::FeedItem;
-use common_web::router::{RouteBuilder, Router};
-
-use common_web::schema::feeditems::dsl::*;
-use diesel::prelude::*;
+use common_web::messages::{
+    CustomizedMessage, ErrMessage, ErrorCode, FieldError, Message, OkMessage, Problem,
+};
+use common_web::models::{FeedItem, NewFeedItem};
+use common_web::repositories::FeedRepository;
+use common_web::router::{ApiVersion, RouteBuilder, Router};
+use common_web::validation::Valid;
 
 use crate::requests::{
-    CreateFeedItemRequest, ItemPageRequest, UpdateFeedItemRequest, DEFAULT_ITEMS_PER_PAGE,
+    CreateFeedItemRequest, CreateUploadRequest, ItemPageRequest, SignedUrlRequest,
+    TransformFeedItemRequest, UpdateFeedItemRequest, UploadPartPath, DEFAULT_ITEMS_PER_PAGE,
 };
-use crate::responses::FeedItemResponse;
+use crate::responses::{FeedItemResponse, UploadResponse, VersionedFeedItem};
 
 use log::error;
 
+const FEED_ITEM_NOT_FOUND: ErrMessage = ErrMessage::Generic {
+    code: ErrorCode::FeedItemNotFound,
+    message: "Feed item not found",
+};
+
+const FEED_ITEM_NOT_EDITABLE: ErrMessage = ErrMessage::Generic {
+    code: ErrorCode::FeedItemNotEditable,
+    message: "Feed item not editable by user",
+};
+
+const UPLOAD_NOT_FOUND: ErrMessage = ErrMessage::Generic {
+    code: ErrorCode::UploadNotFound,
+    message: "Upload not found",
+};
+
+const UPLOAD_TOO_LARGE: ErrMessage = ErrMessage::Generic {
+    code: ErrorCode::PayloadTooLarge,
+    message: "Upload is larger than allowed",
+};
+
+const FEED_ITEM_MODIFIED: ErrMessage = ErrMessage::Generic {
+    code: ErrorCode::PreconditionFailed,
+    message: "Feed item has been modified",
+};
+
+// Seconds responses may be cached for. Those with presigned urls go stale well before
+// the urls expire, thumbnail urls never do.
+const PRESIGNED_MAX_AGE: u32 = PRESIGNED_URL_MIN_LIFETIME.as_secs() as u32 / 2;
+const THUMBNAILS_MAX_AGE: u32 = 30;
+const THUMBNAIL_MAX_AGE: u32 = 5 * 60;
+
 pub struct FeedRouter;
 impl Router for FeedRouter {
     fn build(route_builder: RouteBuilder) -> RouteBuilder {
-        route_builder
+        let route_builder = route_builder
             .mount(get_all_feeds)
             .mount(get_all_thumbnails)
             .mount(get_feed)
             .mount(get_feed_thumbnail)
             .mount(update_feed)
+            .mount(transform_feed)
+            .mount(revert_feed_transform)
             .mount(create_feed)
             .mount(delete_feed)
-            .mount(get_signed_url)
+            .mount(create_upload)
+            .mount(get_upload)
+            .mount(get_upload_part_url)
+            .mount(complete_upload)
+            .mount(abort_upload);
+
+        // Presigned PUTs can't limit the size of uploads, v1 only offers POST forms and
+        // multipart uploads
+        match route_builder.api_version() {
+            Some(ApiVersion::V1) => route_builder,
+            _ => route_builder.mount(get_signed_url),
+        }
     }
 }
 
+#[utoipa::path(
+    tag = "feed",
+    params(ItemPageRequest),
+    responses(
+        (status = 200, body = Vec<VersionedFeedItem>, headers(("ETag"), ("Cache-Control"))),
+        (status = 304, description = "Not modified since the ETag of If-None-Match")
+    ),
+    security((), ("bearer_auth" = []))
+)]
 #[get("")]
 async fn get_all_feeds(
+    req: HttpRequest,
+    version: ApiVersion,
     auth: Option<IsLoggedIn>,
-    conn: Data<DBConnPool>,
+    repository: Data<dyn FeedRepository>,
+    recent_writes: Data<RecentWrites>,
     media_bucket: Data<S3Bucket<Media>>,
-    query: Option<Query<ItemPageRequest>>,
-) -> Message<Vec<FeedItemResponse>> {
+    query: Valid<Query<ItemPageRequest>>,
+) -> CachedMessage<Vec<VersionedFeedItem>> {
     let user = auth.map(IsLoggedIn::get_user);
+    let read_from = recent_writes.read_from(&req);
 
-    let conn = conn.get()?;
-
-    // Get UTC timestamp
-    let (limit, timestamp) = if let Some(query) = query {
-        let ItemPageRequest { before, limit } = query.into_inner();
-        // If no timestamp specified, use current time in UTC
-        let before = before.unwrap_or(Utc::now());
-        (limit, before.naive_utc())
-    } else {
-        // If none specified, use current time in UTC
-        (DEFAULT_ITEMS_PER_PAGE, Utc::now().naive_utc())
-    };
+    let ItemPageRequest { before, limit } = query.into_inner().into_inner();
+    let limit = limit.unwrap_or(DEFAULT_ITEMS_PER_PAGE);
+    // If no timestamp specified, use current time in UTC
+    let timestamp = before.unwrap_or(Utc::now()).naive_utc();
 
     // Return items older than provided timestamp
-    let feed_items = block(move || {
-        feeditems
-            .filter(updated_at.lt(timestamp))
-            .order_by(updated_at.desc())
-            .limit(limit)
-            .load::<FeedItem>(&conn)
-    })
-    .await??;
-
-    let mut returned_feeds = Vec::<FeedItemResponse>::new();
+    let feed_items = repository.list(timestamp, limit, read_from).await?;
+
+    let mut returned_feeds = Vec::<VersionedFeedItem>::new();
     for feed_item in feed_items {
         let result = media_bucket
             .get_object_presigned_url(&feed_item.image_id)
@@ -85,91 +133,91 @@ async fn get_all_feeds(
                 if let Some(ref user) = user {
                     // If the user is logged in we update the editable field in the response to
                     // true only if the user was also the creator of the feed item.
-                    returned_feeds.push((user, presigned_url, feed_item).into());
+                    let response = FeedItemResponse::from((user, presigned_url, feed_item));
+                    returned_feeds.push(response.versioned(version));
                 } else {
                     // Otherwise the feed item is not editable
-                    returned_feeds.push((presigned_url, feed_item).into());
+                    let response = FeedItemResponse::from((presigned_url, feed_item));
+                    returned_feeds.push(response.versioned(version));
                 }
             }
             Err(err) => error!("s3: {}", err),
         }
     }
 
-    Ok(OkMessage::Success(returned_feeds))
+    Ok(Cached::private(returned_feeds, PRESIGNED_MAX_AGE))
 }
 
+#[utoipa::path(
+    tag = "feed",
+    params(ItemPageRequest),
+    responses(
+        (status = 200, body = Vec<VersionedFeedItem>, headers(("ETag"), ("Cache-Control"))),
+        (status = 304, description = "Not modified since the ETag of If-None-Match")
+    )
+)]
 #[get("/thumbnails")]
 async fn get_all_thumbnails(
-    conn: Data<DBConnPool>,
+    version: ApiVersion,
+    repository: Data<dyn FeedRepository>,
     config: Data<Config>,
-    query: Option<Query<ItemPageRequest>>,
-) -> Message<Vec<FeedItemResponse>> {
-    let conn = conn.get()?;
-
-    // Get UTC timestamp
-    let (limit, timestamp) = if let Some(query) = query {
-        let ItemPageRequest { before, limit } = query.into_inner();
-        // If no timestamp specified, use current time in UTC
-        let before = before.unwrap_or(Utc::now());
-        (limit, before.naive_utc())
-    } else {
-        // If none specified, use current time in UTC
-        (DEFAULT_ITEMS_PER_PAGE, Utc::now().naive_utc())
-    };
+    query: Valid<Query<ItemPageRequest>>,
+) -> CachedMessage<Vec<VersionedFeedItem>> {
+    let ItemPageRequest { before, limit } = query.into_inner().into_inner();
+    let limit = limit.unwrap_or(DEFAULT_ITEMS_PER_PAGE);
+    // If no timestamp specified, use current time in UTC
+    let timestamp = before.unwrap_or(Utc::now()).naive_utc();
 
     // Return items older than provided timestamp
-    let feed_items = block(move || {
-        feeditems
-            .filter(updated_at.lt(timestamp))
-            .order_by(updated_at.desc())
-            .limit(limit)
-            .load::<FeedItem>(&conn)
-    })
-    .await??;
-
-    let mut returned_feeds = Vec::<FeedItemResponse>::new();
-    for feed_item in feed_items {
-        let url = format!(
-            "{}/{}",
-            config.aws_thumbnails_base_url,
-            feed_item.image_id
-        );
+    let feed_items = repository.list(timestamp, limit, ReadFrom::Replica).await?;
 
-        returned_feeds.push((url, feed_item).into());
+    let mut returned_feeds = Vec::<VersionedFeedItem>::new();
+    for feed_item in feed_items {
+        returned_feeds.push(thumbnail_response(&config, feed_item).versioned(version));
     }
 
-    Ok(OkMessage::Success(returned_feeds))
+    Ok(Cached::public(returned_feeds, THUMBNAILS_MAX_AGE))
 }
 
+#[utoipa::path(
+    tag = "feed",
+    responses(
+        (status = 200, body = VersionedFeedItem, headers(("ETag"), ("Cache-Control"))),
+        (status = 304, description = "Not modified since the ETag of If-None-Match"),
+        (status = 404, body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
 #[get("/{feed_id}")]
 async fn get_feed(
+    req: HttpRequest,
+    api_version: ApiVersion,
     auth: IsLoggedIn,
-    conn: Data<DBConnPool>,
+    repository: Data<dyn FeedRepository>,
+    recent_writes: Data<RecentWrites>,
     media_bucket: Data<S3Bucket<Media>>,
     feed_id: Path<i32>,
-) -> Message<FeedItemResponse> {
+) -> CachedMessage<VersionedFeedItem> {
     let user = auth.get_user();
+    let read_from = recent_writes.read_from(&req);
 
-    let conn = conn.get()?;
-
-    let feed_id = feed_id.into_inner();
-
-    let feed_item = block(move || feeditems.find(feed_id).get_result::<FeedItem>(&conn))
+    let feed_item = repository
+        .find(feed_id.into_inner(), read_from)
         .await?
-        .map_err(|err| {
-            error!("diesel: {}", err);
-            ErrMessage::Generic {
-                status: StatusCode::NOT_FOUND,
-                message: "Feed item not found",
-            }
-        })?;
+        .ok_or(FEED_ITEM_NOT_FOUND)?;
+
+    let version = feed_item_version(&feed_item);
 
     let result = media_bucket
         .get_object_presigned_url(&feed_item.image_id)
         .await;
     match result {
         Ok(presigned_url) => {
-            return Ok(OkMessage::Success((&user, presigned_url, feed_item).into()));
+            let response = FeedItemResponse::from((&user, presigned_url, feed_item));
+            return Ok(
+                Cached::private(response.versioned(api_version), PRESIGNED_MAX_AGE)
+                    .versioned(version),
+            );
         }
         Err(err) => error!("s3: {}", err),
     }
@@ -177,78 +225,111 @@ async fn get_feed(
     Err(ErrMessage::InternalServerError)
 }
 
+#[utoipa::path(
+    tag = "feed",
+    responses(
+        (status = 200, body = VersionedFeedItem, headers(("ETag"), ("Cache-Control"))),
+        (status = 304, description = "Not modified since the ETag of If-None-Match"),
+        (status = 404, body = Problem, content_type = "application/problem+json")
+    )
+)]
 #[get("/{feed_id}/thumbnail")]
 async fn get_feed_thumbnail(
-    conn: Data<DBConnPool>,
+    api_version: ApiVersion,
+    repository: Data<dyn FeedRepository>,
     config: Data<Config>,
     feed_id: Path<i32>,
-) -> Message<FeedItemResponse> {
-    let conn = conn.get()?;
+) -> CachedMessage<VersionedFeedItem> {
+    let feed_item = repository
+        .find(feed_id.into_inner(), ReadFrom::Replica)
+        .await?
+        .ok_or(FEED_ITEM_NOT_FOUND)?;
 
-    let feed_id = feed_id.into_inner();
+    let version = feed_item_version(&feed_item);
+    let response = thumbnail_response(&config, feed_item).versioned(api_version);
+    Ok(Cached::public(response, THUMBNAIL_MAX_AGE).versioned(version))
+}
 
-    let feed_item = block(move || feeditems.find(feed_id).get_result::<FeedItem>(&conn))
-        .await?
-        .map_err(|err| {
-            error!("diesel: {}", err);
-            ErrMessage::Generic {
-                status: StatusCode::NOT_FOUND,
-                message: "Feed item not found",
-            }
-        })?;
+fn thumbnail_response(config: &Config, feed_item: FeedItem) -> FeedItemResponse {
+    let url = format!("{}/{}", config.aws.thumbnails_base_url, feed_item.image_id);
 
-    let url = format!(
-        "{}/{}",
-        config.aws_thumbnails_base_url,
-        feed_item.image_id
-    );
+    // Animated media additionally gets an animated thumbnail, the url above is its poster frame
+    let animated_url = if feed_item.animated {
+        Some(format!(
+            "{}/{}",
+            config.aws.thumbnails_base_url,
+            animated_thumbnail_key(&feed_item.image_id)
+        ))
+    } else {
+        None
+    };
 
-    Ok(OkMessage::Success((url, feed_item).into()))
+    FeedItemResponse {
+        animated_url,
+        ..(url, feed_item).into()
+    }
 }
 
+#[utoipa::path(
+    tag = "feed",
+    request_body = UpdateFeedItemRequest,
+    params(("If-Match" = Option<String>, Header, description = "ETag the feed item must still have")),
+    responses(
+        (status = 200, body = VersionedFeedItem, headers(("ETag"))),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json"),
+        (status = 412, body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
 #[patch("/{feed_id}")]
+#[allow(clippy::too_many_arguments)]
 async fn update_feed(
+    version: ApiVersion,
     auth: IsLoggedIn,
-    conn: Data<DBConnPool>,
+    repository: Data<dyn FeedRepository>,
+    recent_writes: Data<RecentWrites>,
     media_bucket: Data<S3Bucket<Media>>,
     feed_id: Path<i32>,
-    feed: Json<UpdateFeedItemRequest>,
-) -> Message<FeedItemResponse> {
-    let conn = conn.get()?;
-
-    let Json(feed) = feed;
+    req: HttpRequest,
+    feed: Valid<Json<UpdateFeedItemRequest>>,
+) -> CustomizedMessage<VersionedFeedItem> {
+    let Json(feed) = feed.into_inner();
 
-    let feed_id = feed_id.into_inner();
+    let user = auth.get_user();
+    let if_match = parse_if_match(&req)?;
 
-    let user = Arc::new(auth.get_user());
+    let feed_item = repository
+        .update_caption(
+            feed_id.into_inner(),
+            feed.caption,
+            Box::new({
+                let email = user.email.clone();
+                move |feed_item| {
+                    check_editable(feed_item, &email)?;
+                    check_if_match(feed_item, if_match.as_ref())
+                }
+            }),
+        )
+        .await?
+        .ok_or(FEED_ITEM_NOT_FOUND)?;
 
-    let feed_item = block({
-        let user = user.clone();
-        move || {
-            diesel::update(feeditems)
-                .filter(id.eq(feed_id))
-                .filter(created_by.eq(&*user.email))
-                .set((caption.eq(feed.caption), updated_at.eq(diesel::dsl::now)))
-                .get_result::<FeedItem>(&conn)
-        }
-    })
-    .await?
-    .map_err(|err| {
-        error!("diesel: {}", err);
-        ErrMessage::Generic {
-            status: StatusCode::BAD_REQUEST,
-            message: "Feed item not found or item not editable by user",
-        }
-    })?;
+    let etag = feed_item_etag(&feed_item);
 
     let result = media_bucket
         .get_object_presigned_url(&feed_item.image_id)
         .await;
     match result {
         Ok(presigned_url) => {
-            return Ok(OkMessage::Success(
-                (&*user, presigned_url, feed_item).into(),
-            ));
+            return Ok(
+                OkMessage::Success(
+                    FeedItemResponse::from((&user, presigned_url, feed_item)).versioned(version),
+                )
+                .customize()
+                .insert_header(ETag(etag))
+                .append_header((SET_COOKIE, recent_writes.cookie().to_string())),
+            );
         }
         Err(err) => error!("s3: {}", err),
     }
@@ -256,82 +337,307 @@ async fn update_feed(
     Err(ErrMessage::InternalServerError)
 }
 
+#[utoipa::path(
+    tag = "feed",
+    request_body = TransformFeedItemRequest,
+    responses(
+        (status = 202, description = "Transformation queued"),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
+#[post("/{feed_id}/transform")]
+async fn transform_feed(
+    auth: IsLoggedIn,
+    repository: Data<dyn FeedRepository>,
+    queue: Data<SQSQueue>,
+    feed_id: Path<i32>,
+    request: Valid<Json<TransformFeedItemRequest>>,
+) -> Message<serde_json::Value> {
+    let Json(request) = request.into_inner();
+
+    let feed_item = find_editable_feed(auth, repository, feed_id.into_inner()).await?;
+
+    let command = ImageCommand::Transform {
+        key: feed_item.image_id,
+        transformations: request.transformations,
+    };
+    enqueue_image_command(&queue, &command).await?;
+
+    Ok(OkMessage::Accepted(serde_json::json!({
+        "id": feed_item.id
+    })))
+}
+
+#[utoipa::path(
+    tag = "feed",
+    responses(
+        (status = 202, description = "Revert queued"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
+#[delete("/{feed_id}/transform")]
+async fn revert_feed_transform(
+    auth: IsLoggedIn,
+    repository: Data<dyn FeedRepository>,
+    queue: Data<SQSQueue>,
+    feed_id: Path<i32>,
+) -> Message<serde_json::Value> {
+    let feed_item = find_editable_feed(auth, repository, feed_id.into_inner()).await?;
+
+    let command = ImageCommand::Revert {
+        key: feed_item.image_id,
+    };
+    enqueue_image_command(&queue, &command).await?;
+
+    Ok(OkMessage::Accepted(serde_json::json!({
+        "id": feed_item.id
+    })))
+}
+
+async fn find_editable_feed(
+    auth: IsLoggedIn,
+    repository: Data<dyn FeedRepository>,
+    feed_id: i32,
+) -> Result<FeedItem, ErrMessage> {
+    let user = auth.get_user();
+
+    let feed_item = repository
+        .find(feed_id, ReadFrom::Primary)
+        .await?
+        .ok_or(FEED_ITEM_NOT_FOUND)?;
+    check_editable(&feed_item, &user.email)?;
+
+    Ok(feed_item)
+}
+
+fn check_editable(feed_item: &FeedItem, email: &str) -> Result<(), ErrMessage> {
+    if feed_item.created_by != email {
+        return Err(FEED_ITEM_NOT_EDITABLE);
+    }
+
+    Ok(())
+}
+
+// Changes whenever the feed item is updated
+fn feed_item_version(feed_item: &FeedItem) -> String {
+    format!(
+        "{}-{}",
+        feed_item.id,
+        feed_item.updated_at.and_utc().timestamp_micros()
+    )
+}
+
+// Strong validator of the feed item, reads extend it with a digest of their body
+fn feed_item_etag(feed_item: &FeedItem) -> EntityTag {
+    EntityTag::new_strong(feed_item_version(feed_item))
+}
+
+// Reads If-Match strictly, a value that isn't `*` or a list of entity tags would
+// otherwise be dropped and let the write through, or fail it as stale
+fn parse_if_match(req: &HttpRequest) -> Result<Option<IfMatch>, ErrMessage> {
+    if !req.headers().contains_key(IF_MATCH) {
+        return Ok(None);
+    }
+
+    let malformed = || {
+        ErrMessage::Validation(vec![FieldError::new(
+            "If-Match",
+            "malformed",
+            "If-Match must be * or a list of entity tags",
+        )])
+    };
+
+    let mut etags = Vec::new();
+    for value in req.headers().get_all(IF_MATCH) {
+        let value = value.to_str().map_err(|_| malformed())?.trim();
+        if value == "*" {
+            return Ok(Some(IfMatch::Any));
+        }
+
+        for tag in value.split(',').map(str::trim).filter(|tag| !tag.is_empty()) {
+            etags.push(tag.parse::<EntityTag>().map_err(|_| malformed())?);
+        }
+    }
+
+    if etags.is_empty() {
+        return Err(malformed());
+    }
+
+    Ok(Some(IfMatch::Items(etags)))
+}
+
+fn check_if_match(feed_item: &FeedItem, if_match: Option<&IfMatch>) -> Result<(), ErrMessage> {
+    let matches = match if_match {
+        None | Some(IfMatch::Any) => true,
+        Some(IfMatch::Items(etags)) => {
+            let version = feed_item_version(feed_item);
+            etags.iter().any(|tag| !tag.weak && etag_version(tag) == version)
+        }
+    };
+
+    if !matches {
+        return Err(FEED_ITEM_MODIFIED);
+    }
+
+    Ok(())
+}
+
+async fn enqueue_image_command(queue: &SQSQueue, command: &ImageCommand) -> Result<(), ErrMessage> {
+    let body = serde_json::to_string(command).map_err(|err| {
+        error!("serde: {}", err);
+        ErrMessage::InternalServerError
+    })?;
+
+    queue.send(&body, None).await.map_err(|err| {
+        error!("sqs: {}", err);
+        ErrMessage::InternalServerError
+    })?;
+
+    Ok(())
+}
+
+#[utoipa::path(
+    tag = "feed",
+    request_body = CreateFeedItemRequest,
+    responses(
+        (status = 201, body = VersionedFeedItem, headers(("ETag")),
+            description = "Feed item created with `upload`, a presigned POST form limiting the \
+                size of its media. On v0 `url` is a presigned PUT url for its media instead."),
+        (status = 400, body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
 #[post("")]
 async fn create_feed(
+    version: ApiVersion,
     auth: IsLoggedIn,
-    conn: Data<DBConnPool>,
-    feed: Json<CreateFeedItemRequest>,
+    repository: Data<dyn FeedRepository>,
+    recent_writes: Data<RecentWrites>,
+    feed: Valid<Json<CreateFeedItemRequest>>,
     media_bucket: Data<S3Bucket<Media>>,
-) -> Message<FeedItemResponse> {
-    let conn = conn.get()?;
+) -> CustomizedMessage<VersionedFeedItem> {
+    let user = auth.get_user();
 
-    let user = Arc::new(auth.get_user());
+    let Json(feed) = feed.into_inner();
+    // v0 clients may not know the content type, they are sent an unpinned PUT url
+    let content_type = match (version, feed.content_type) {
+        (_, Some(content_type)) => {
+            check_content_type(&media_bucket, &content_type)?;
+            Some(content_type)
+        }
+        (ApiVersion::V0, None) => None,
+        (ApiVersion::V1, None) => {
+            return Err(ErrMessage::Validation(vec![FieldError::new(
+                "content_type",
+                "required",
+                "Content type is required",
+            )]))
+        }
+    };
 
-    let Json(feed) = feed;
+    let feed_item = repository
+        .create(NewFeedItem {
+            created_by: user.email.clone(),
+            image_id: Uuid::new_v4().to_simple().to_string(),
+            caption: feed.caption,
+        })
+        .await?;
 
-    if feed.caption.is_none() {
-        return Err(ErrMessage::Generic {
-            status: StatusCode::BAD_REQUEST,
-            message: "Caption is required or malformed",
-        });
+    let feed_url = match version {
+        ApiVersion::V0 => {
+            media_bucket
+                .put_object_presigned_url(&feed_item.image_id, content_type.as_deref())
+                .await
+        }
+        ApiVersion::V1 => media_bucket.get_object_presigned_url(&feed_item.image_id).await,
     }
+    .map_err(|err| {
+        error!("s3: {}", err);
+        ErrMessage::InternalServerError
+    })?;
+    let upload = match &content_type {
+        Some(content_type) => Some(
+            media_bucket
+                .post_object_presigned_form(&feed_item.image_id, content_type)
+                .await
+                .map_err(|err| {
+                    error!("s3: {}", err);
+                    ErrMessage::InternalServerError
+                })?,
+        ),
+        None => None,
+    };
 
-    let feed_image_id = Uuid::new_v4().to_simple().to_string();
-
-    let feed_item = block({
-        let user = user.clone();
-        move || {
-            diesel::insert_into(feeditems)
-                .values(&vec![(
-                    caption.eq(feed.caption),
-                    image_id.eq(feed_image_id),
-                    created_by.eq(&*user.email),
-                    created_at.eq(diesel::dsl::now),
-                    updated_at.eq(diesel::dsl::now),
-                )])
-                .get_result::<FeedItem>(&conn)
-        }
-    })
-    .await??;
+    let etag = feed_item_etag(&feed_item);
 
-    let feed_url = media_bucket
-        .put_object_presigned_url(&feed_item.image_id)
-        .await
-        .map_err(|err| {
-            error!("s3: {}", err);
-            ErrMessage::InternalServerError
-        })?;
+    let response = FeedItemResponse {
+        upload,
+        ..(&user, feed_url, feed_item).into()
+    };
+    Ok(OkMessage::Created(response.versioned(version))
+        .customize()
+        .insert_header(ETag(etag))
+        .append_header((SET_COOKIE, recent_writes.cookie().to_string())))
+}
 
-    Ok(OkMessage::Created((&*user, feed_url, feed_item).into()))
+// Uploads declaring other content types would be rejected by S3
+fn check_content_type(
+    media_bucket: &S3Bucket<Media>,
+    content_type: &str,
+) -> Result<(), ErrMessage> {
+    if media_bucket.accepts_upload(content_type) {
+        Ok(())
+    } else {
+        Err(ErrMessage::Validation(vec![FieldError::new(
+            "content_type",
+            "unsupported",
+            "Content type is not accepted for uploads",
+        )]))
+    }
 }
 
+#[utoipa::path(
+    tag = "feed",
+    params(("If-Match" = Option<String>, Header, description = "ETag the feed item must still have")),
+    responses(
+        (status = 200, description = "Feed item deleted"),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json"),
+        (status = 412, body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
 #[delete("/{feed_id}")]
 async fn delete_feed(
     auth: IsLoggedIn,
-    conn: Data<DBConnPool>,
+    repository: Data<dyn FeedRepository>,
+    recent_writes: Data<RecentWrites>,
     media_bucket: Data<S3Bucket<Media>>,
     feed_id: Path<i32>,
-) -> Message<serde_json::Value> {
-    let conn = conn.get()?;
-
-    let feed_id = feed_id.into_inner();
-
+    req: HttpRequest,
+) -> CustomizedMessage<serde_json::Value> {
     let user = auth.get_user();
+    let if_match = parse_if_match(&req)?;
 
-    let feed_item = block(move || {
-        diesel::dsl::delete(feeditems)
-            .filter(id.eq(feed_id))
-            .filter(created_by.eq(&user.email))
-            .get_result::<FeedItem>(&conn)
-    })
-    .await?
-    .map_err(|err| {
-        error!("diesel: {}", err);
-        ErrMessage::Generic {
-            status: StatusCode::BAD_REQUEST,
-            message: "Feed item not found or item not editable by user",
-        }
-    })?;
+    let feed_item = repository
+        .delete(
+            feed_id.into_inner(),
+            Box::new({
+                let email = user.email.clone();
+                move |feed_item| {
+                    check_editable(feed_item, &email)?;
+                    check_if_match(feed_item, if_match.as_ref())
+                }
+            }),
+        )
+        .await?
+        .ok_or(FEED_ITEM_NOT_FOUND)?;
 
     media_bucket
         .delete_object(&feed_item.image_id)
@@ -343,19 +649,36 @@ async fn delete_feed(
 
     Ok(OkMessage::Success(serde_json::json!({
         "id": feed_item.id
-    })))
+    }))
+    .customize()
+    .append_header((SET_COOKIE, recent_writes.cookie().to_string())))
 }
 
+#[utoipa::path(
+    tag = "feed",
+    params(SignedUrlRequest),
+    responses(
+        (status = 200, description = "Presigned upload url, to PUT with the content type given \
+            if any. Only served by v0."),
+        (status = 400, body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
 #[get("/signed-url/{file_name}")]
 async fn get_signed_url(
     _auth: IsLoggedIn,
     file_name: Path<String>,
+    query: Valid<Query<SignedUrlRequest>>,
     media_bucket: Data<S3Bucket<Media>>,
 ) -> Message<serde_json::Value> {
     let file_name = file_name.into_inner();
+    let SignedUrlRequest { content_type } = query.into_inner().into_inner();
+    if let Some(content_type) = &content_type {
+        check_content_type(&media_bucket, content_type)?;
+    }
 
     let presigned_url = media_bucket
-        .put_object_presigned_url(&file_name)
+        .put_object_presigned_url(&file_name, content_type.as_deref())
         .await
         .map_err(|err| {
             error!("s3: {}", err);
@@ -366,3 +689,540 @@ async fn get_signed_url(
         "url": presigned_url
     })))
 }
+
+#[utoipa::path(
+    tag = "feed",
+    request_body = CreateUploadRequest,
+    responses(
+        (status = 201, body = UploadResponse,
+            description = "Multipart upload of the media started, its parts are PUT to the \
+                urls of `/parts/{part_number}`"),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
+#[post("/{feed_id}/uploads")]
+async fn create_upload(
+    auth: IsLoggedIn,
+    repository: Data<dyn FeedRepository>,
+    media_bucket: Data<S3Bucket<Media>>,
+    config: Data<Config>,
+    feed_id: Path<i32>,
+    request: Valid<Json<CreateUploadRequest>>,
+) -> Message<UploadResponse> {
+    let Json(request) = request.into_inner();
+    let content_type = request.content_type.unwrap_or_default();
+    check_content_type(&media_bucket, &content_type)?;
+
+    let feed_item = find_editable_feed(auth, repository, feed_id.into_inner()).await?;
+
+    let upload_id = media_bucket
+        .create_multipart_upload(&feed_item.image_id, &content_type)
+        .await
+        .map_err(|err| {
+            error!("s3: {}", err);
+            ErrMessage::InternalServerError
+        })?;
+
+    Ok(OkMessage::Created(upload_response(
+        &config,
+        feed_item.id,
+        upload_id,
+        Vec::new(),
+    )))
+}
+
+#[utoipa::path(
+    tag = "feed",
+    responses(
+        (status = 200, body = UploadResponse, description = "Parts received so far"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
+#[get("/{feed_id}/uploads/{upload_id}")]
+async fn get_upload(
+    auth: IsLoggedIn,
+    repository: Data<dyn FeedRepository>,
+    media_bucket: Data<S3Bucket<Media>>,
+    config: Data<Config>,
+    path: Path<(i32, String)>,
+) -> Message<UploadResponse> {
+    let (feed_id, upload_id) = path.into_inner();
+
+    let feed_item = find_editable_feed(auth, repository, feed_id).await?;
+
+    let parts = media_bucket
+        .list_parts(&feed_item.image_id, &upload_id)
+        .await
+        .map_err(multipart_error)?;
+
+    Ok(OkMessage::Success(upload_response(
+        &config,
+        feed_item.id,
+        upload_id,
+        parts,
+    )))
+}
+
+#[utoipa::path(
+    tag = "feed",
+    params(UploadPartPath),
+    responses(
+        (status = 200, description = "Presigned url to PUT the part to"),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
+#[get("/{feed_id}/uploads/{upload_id}/parts/{part_number}")]
+async fn get_upload_part_url(
+    auth: IsLoggedIn,
+    repository: Data<dyn FeedRepository>,
+    media_bucket: Data<S3Bucket<Media>>,
+    path: Valid<Path<UploadPartPath>>,
+) -> Message<serde_json::Value> {
+    let UploadPartPath {
+        feed_id,
+        upload_id,
+        part_number,
+    } = path.into_inner().into_inner();
+
+    let feed_item = find_editable_feed(auth, repository, feed_id).await?;
+
+    let presigned_url = media_bucket
+        .upload_part_presigned_url(&feed_item.image_id, &upload_id, part_number)
+        .await
+        .map_err(|err| {
+            error!("s3: {}", err);
+            ErrMessage::InternalServerError
+        })?;
+
+    Ok(OkMessage::Success(serde_json::json!({
+        "part_number": part_number,
+        "url": presigned_url
+    })))
+}
+
+#[utoipa::path(
+    tag = "feed",
+    responses(
+        (status = 200, description = "Upload completed from every part received"),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json"),
+        (status = 413, body = Problem, content_type = "application/problem+json",
+            description = "Upload larger than allowed, it has been aborted")
+    ),
+    security(("bearer_auth" = []))
+)]
+#[post("/{feed_id}/uploads/{upload_id}/complete")]
+async fn complete_upload(
+    auth: IsLoggedIn,
+    repository: Data<dyn FeedRepository>,
+    media_bucket: Data<S3Bucket<Media>>,
+    path: Path<(i32, String)>,
+) -> Message<serde_json::Value> {
+    let (feed_id, upload_id) = path.into_inner();
+
+    let feed_item = find_editable_feed(auth, repository, feed_id).await?;
+
+    media_bucket
+        .complete_multipart_upload(&feed_item.image_id, &upload_id)
+        .await
+        .map_err(multipart_error)?;
+
+    Ok(OkMessage::Success(serde_json::json!({
+        "id": feed_item.id
+    })))
+}
+
+#[utoipa::path(
+    tag = "feed",
+    responses(
+        (status = 200, description = "Upload aborted, the parts received are deleted"),
+        (status = 403, body = Problem, content_type = "application/problem+json"),
+        (status = 404, body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
+#[delete("/{feed_id}/uploads/{upload_id}")]
+async fn abort_upload(
+    auth: IsLoggedIn,
+    repository: Data<dyn FeedRepository>,
+    media_bucket: Data<S3Bucket<Media>>,
+    path: Path<(i32, String)>,
+) -> Message<serde_json::Value> {
+    let (feed_id, upload_id) = path.into_inner();
+
+    let feed_item = find_editable_feed(auth, repository, feed_id).await?;
+
+    media_bucket
+        .abort_multipart_upload(&feed_item.image_id, &upload_id)
+        .await
+        .map_err(multipart_error)?;
+
+    Ok(OkMessage::Success(serde_json::json!({
+        "id": feed_item.id
+    })))
+}
+
+fn upload_response(
+    config: &Config,
+    feed_id: i32,
+    upload_id: String,
+    parts: Vec<UploadedPart>,
+) -> UploadResponse {
+    UploadResponse {
+        id: feed_id,
+        upload_id,
+        min_part_bytes: MIN_UPLOAD_PART_BYTES,
+        max_parts: MAX_UPLOAD_PARTS,
+        max_bytes: config.aws.media_presign.upload_max_bytes,
+        parts,
+    }
+}
+
+fn multipart_error(err: MultipartError) -> ErrMessage {
+    match err {
+        MultipartError::NoSuchUpload => UPLOAD_NOT_FOUND,
+        MultipartError::TooLarge { .. } => UPLOAD_TOO_LARGE,
+        MultipartError::InvalidParts(message) => {
+            ErrMessage::Validation(vec![FieldError::new("parts", "invalid", message)])
+        }
+        MultipartError::S3(err) => {
+            error!("s3: {}", err);
+            ErrMessage::InternalServerError
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::http::header::{AUTHORIZATION, ETAG, IF_MATCH, IF_NONE_MATCH};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web::Data, App, ResponseError};
+    use serde_json::{json, Value};
+
+    use common::config::{Config, ConfigArgs, Service};
+    use common::jwt::generate_jwt;
+    use common_web::database;
+    use common_web::models::User;
+    use common_web::openapi;
+    use common_web::repositories::DbFeedRepository;
+    use common_web::router::VersionScope;
+
+    use super::*;
+
+    // Feed settings with an in-memory SQLite database, and the repository of its feed items
+    async fn test_repository() -> (Data<Config>, Arc<dyn FeedRepository>) {
+        let args = ConfigArgs {
+            overrides: [
+                "database.dialect=sqlite",
+                "database.name=:memory:",
+                "jwt.secret=secret",
+                "aws.region=us-east-1",
+                "aws.media_bucket=media",
+                "aws.sqs_queue=queue",
+                "aws.thumbnails_base_url=https://thumbnails.example.com",
+            ]
+            .map(String::from)
+            .to_vec(),
+            ..ConfigArgs::default()
+        };
+        let config = Config::load(Service::Feed, &args).await.unwrap();
+        let pool = database::create_db_conn_pool(&config).await.unwrap();
+
+        (Data::new(config), Arc::new(DbFeedRepository::new(pool)))
+    }
+
+    #[actix_web::test]
+    async fn lists_thumbnails_with_sqlite() {
+        let (config, repository) = test_repository().await;
+
+        let feed_item = repository
+            .create(NewFeedItem {
+                created_by: "user@example.com".to_string(),
+                image_id: "image".to_string(),
+                caption: None,
+            })
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository))
+                .app_data(config)
+                .configure(|srv| {
+                    RouteBuilder::new(srv).extend::<FeedRouter>("/feed").build();
+                }),
+        )
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/feed/thumbnails")
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(response.as_array().unwrap().len(), 1);
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/feed/{}/thumbnail", feed_item.id))
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(response["url"], "https://thumbnails.example.com/image");
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/feed/{}/thumbnail", feed_item.id))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        let etag = response.headers().get(ETAG).unwrap().clone();
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/feed/{}/thumbnail", feed_item.id))
+            .insert_header((IF_NONE_MATCH, etag))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[actix_web::test]
+    async fn versions_serve_different_shapes() {
+        let (config, repository) = test_repository().await;
+
+        let feed_item = repository
+            .create(NewFeedItem {
+                created_by: "user@example.com".to_string(),
+                image_id: "image".to_string(),
+                caption: None,
+            })
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository))
+                .app_data(config)
+                .configure(|srv| {
+                    RouteBuilder::new(srv)
+                        .versioned(VersionScope::new(ApiVersion::V0), |routes| {
+                            routes.extend::<FeedRouter>("/feed")
+                        })
+                        .versioned(VersionScope::new(ApiVersion::V1), |routes| {
+                            routes.extend::<FeedRouter>("/feed")
+                        })
+                        .build();
+                }),
+        )
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/api/v0/feed/{}/thumbnail", feed_item.id))
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(response["url"], "https://thumbnails.example.com/image");
+        assert!(response.get("media").is_none());
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/api/v1/feed/{}/thumbnail", feed_item.id))
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(response["media"]["url"], "https://thumbnails.example.com/image");
+        assert!(response.get("url").is_none());
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/feed/thumbnails")
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(response[0]["media"]["animated"], false);
+    }
+
+    #[actix_web::test]
+    async fn v1_only_offers_uploads_limiting_sizes() {
+        let (config, repository) = test_repository().await;
+        let media_bucket = S3Bucket::<Media>::new(&config).await;
+
+        let now = Utc::now().naive_utc();
+        let user = User {
+            id: 1,
+            email: "user@example.com".to_string(),
+            password_hash: None,
+            created_at: now,
+            updated_at: now,
+        };
+        let token = generate_jwt(user, config.clone().into_inner()).await.unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository))
+                .app_data(config)
+                .app_data(Data::new(media_bucket))
+                .app_data(Data::new(RecentWrites::new(std::time::Duration::ZERO)))
+                .configure(|srv| {
+                    RouteBuilder::new(srv)
+                        .versioned(VersionScope::new(ApiVersion::V1), |routes| {
+                            routes.extend::<FeedRouter>("/feed")
+                        })
+                        .build();
+                }),
+        )
+        .await;
+
+        let request = test::TestRequest::post()
+            .uri("/api/v1/feed")
+            .insert_header((AUTHORIZATION, format!("Bearer {}", token)))
+            .set_json(json!({ "caption": "caption" }))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/feed/signed-url/image?content_type=image/png")
+            .insert_header((AUTHORIZATION, format!("Bearer {}", token)))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn guards_updates_and_deletes() {
+        let (config, repository) = test_repository().await;
+        let media_bucket = S3Bucket::<Media>::new(&config).await;
+
+        let feed_item = repository
+            .create(NewFeedItem {
+                created_by: "user@example.com".to_string(),
+                image_id: "image".to_string(),
+                caption: None,
+            })
+            .await
+            .unwrap();
+
+        let now = Utc::now().naive_utc();
+        let mut tokens = Vec::new();
+        for (id, email) in [(1, "user@example.com"), (2, "other@example.com")] {
+            let user = User {
+                id,
+                email: email.to_string(),
+                password_hash: None,
+                created_at: now,
+                updated_at: now,
+            };
+            let token = generate_jwt(user, config.clone().into_inner()).await.unwrap();
+            tokens.push(format!("Bearer {}", token));
+        }
+        let (owner, other) = (&tokens[0], &tokens[1]);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository))
+                .app_data(config)
+                .app_data(Data::new(media_bucket))
+                .app_data(Data::new(RecentWrites::new(std::time::Duration::ZERO)))
+                .configure(|srv| {
+                    RouteBuilder::new(srv).extend::<FeedRouter>("/feed").build();
+                }),
+        )
+        .await;
+
+        let item = format!("/feed/{}", feed_item.id);
+        let cases = [
+            ("/feed/0", owner, None, StatusCode::NOT_FOUND),
+            (item.as_str(), other, None, StatusCode::FORBIDDEN),
+            (item.as_str(), owner, Some("\"stale\""), StatusCode::PRECONDITION_FAILED),
+            (item.as_str(), owner, Some("stale"), StatusCode::BAD_REQUEST),
+        ];
+        for (uri, token, if_match, status) in cases {
+            let update = test::TestRequest::patch().set_json(json!({ "caption": "caption" }));
+            for request in [update, test::TestRequest::delete()] {
+                let mut request = request
+                    .uri(uri)
+                    .insert_header((AUTHORIZATION, token.as_str()));
+                if let Some(if_match) = if_match {
+                    request = request.insert_header((IF_MATCH, if_match));
+                }
+                let response = test::call_service(&app, request.to_request()).await;
+                assert_eq!(response.status(), status, "{} with If-Match {:?}", uri, if_match);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn reads_if_match_strictly() {
+        let parse = |values: &[&str]| {
+            let mut request = test::TestRequest::default();
+            for value in values {
+                request = request.append_header((IF_MATCH, *value));
+            }
+            parse_if_match(&request.to_http_request()).map_err(|err| err.status_code())
+        };
+
+        assert!(matches!(parse(&[]), Ok(None)));
+        assert!(matches!(parse(&["*"]), Ok(Some(IfMatch::Any))));
+        assert!(matches!(
+            parse(&["\"1\", \"2\"", "\"3\""]),
+            Ok(Some(IfMatch::Items(etags))) if etags.len() == 3
+        ));
+        assert_eq!(parse(&["1"]).unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(parse(&["\"1\", 2"]).unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(parse(&[""]).unwrap_err(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn documents_every_version() {
+        let (config, _) = test_repository().await;
+
+        let app = test::init_service(App::new().configure(|srv| {
+            RouteBuilder::new(srv)
+                .versioned(VersionScope::new(ApiVersion::V0), |routes| {
+                    routes.extend::<FeedRouter>("/feed")
+                })
+                .versioned(VersionScope::new(ApiVersion::V1), |routes| {
+                    routes.extend::<FeedRouter>("/feed")
+                })
+                .build_with_docs(|srv, api| openapi::configure(srv, api, &config));
+        }))
+        .await;
+
+        let request = test::TestRequest::get().uri(openapi::OPENAPI_PATH).to_request();
+        let document: Value = test::call_and_read_body_json(&app, request).await;
+        let paths = &document["paths"];
+        for version in ["v0", "v1"] {
+            let item = &paths[format!("/api/{}/feed/{{feed_id}}", version)];
+            for method in ["get", "patch", "delete"] {
+                assert!(item[method].is_object(), "{} {} undocumented", version, method);
+            }
+            for path in ["", "/thumbnails", "/{feed_id}/transform", "/{feed_id}/uploads"] {
+                let path = format!("/api/{}/feed{}", version, path);
+                assert!(paths[&path].is_object(), "{} undocumented", path);
+            }
+        }
+        assert!(paths["/api/v0/feed/signed-url/{file_name}"]["get"].is_object());
+        assert!(paths["/api/v1/feed/signed-url/{file_name}"].is_null());
+        assert_eq!(
+            paths["/api/v1/feed/{feed_id}"]["patch"]["responses"]["412"]["content"]
+                ["application/problem+json"]["schema"]["$ref"],
+            "#/components/schemas/Problem"
+        );
+
+        let components = &document["components"];
+        for schema in [
+            "FeedItemResponse",
+            "FeedItemResponseV1",
+            "VersionedFeedItem",
+            "CreateFeedItemRequest",
+            "UpdateFeedItemRequest",
+            "TransformFeedItemRequest",
+            "UploadResponse",
+            "Problem",
+        ] {
+            assert!(components["schemas"][schema].is_object(), "{} undocumented", schema);
+        }
+        assert_eq!(components["securitySchemes"]["bearer_auth"]["scheme"], "bearer");
+    }
+}
@@ -1,45 +1,123 @@
-use actix_web::{web::Data, App, HttpServer};
+use std::sync::Arc;
 
 use actix_cors::Cors;
+use actix_web::middleware::from_fn;
 use actix_web::middleware::Compress;
-use actix_web::middleware::Logger;
 use actix_web::middleware::NormalizePath;
+use actix_web::{web, web::Data, App, HttpServer};
+
+use clap::{Parser, Subcommand};
 
 use common::aws::s3::Media;
-use common::aws::S3Bucket;
-use common::config::Config;
+use common::aws::{S3Bucket, SQSQueue};
+use common::config::{Config, ConfigArgs, Service};
+use common::telemetry;
 
-use common_web::database;
-use common_web::router::RouteBuilder;
+use common_web::database::{self, RecentWrites};
+use common_web::extractors;
+use common_web::health::{self, Readiness};
+use common_web::metrics;
+use common_web::migrations::MigrateCommand;
+use common_web::openapi;
+use common_web::repositories::{DbFeedRepository, FeedRepository};
+use common_web::request_id;
+use common_web::router::{ApiVersion, RouteBuilder, VersionScope};
+use utoipa::openapi::Info;
 
+mod cleanup;
 mod controller;
 mod requests;
 mod responses;
+use cleanup::CleanupUploadsArgs;
 use controller::FeedRouter;
 
+#[derive(Parser)]
+#[command(about = "Serves the feed API")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the API (default)
+    Serve,
+    /// Manage the database schema
+    #[command(subcommand)]
+    Migrate(MigrateCommand),
+    /// Abort the multipart uploads of media abandoned for longer than
+    /// AWS_MEDIA_MULTIPART_MAX_AGE
+    CleanupUploads(CleanupUploadsArgs),
+}
+
 #[actix_web::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-
-    let config = Config::load_dotenv().await?;
-    let db_conn = Data::new(database::create_db_conn_pool(&config)?);
+    let cli = Cli::parse();
+    let service = match cli.command {
+        Some(Command::Migrate(_)) => Service::Migrate,
+        Some(Command::CleanupUploads(_)) => Service::CleanupUploads,
+        _ => Service::Feed,
+    };
+    let config = Config::load(service, &cli.config).await?;
+    if cli.config.print_config {
+        print!("{}", config.render());
+        return Ok(());
+    }
+    let _telemetry = telemetry::init("feed", config.telemetry.otlp_endpoint.as_deref())?;
+    match cli.command {
+        Some(Command::Migrate(command)) => return command.run(&config),
+        Some(Command::CleanupUploads(args)) => return cleanup::run(&config, args).await,
+        Some(Command::Serve) | None => (),
+    }
+    config.watch_secrets()?;
+    let db_conn = Data::new(database::create_db_conn_pool(&config).await?);
+    let feeds: Arc<dyn FeedRepository> = Arc::new(DbFeedRepository::new(db_conn.get_ref().clone()));
+    let feeds = Data::from(feeds);
+    let recent_writes = Data::new(RecentWrites::new(config.database.replica_stickiness));
     let s3_media = Data::new(S3Bucket::<Media>::new(&config).await);
+    let sqs = Data::new(SQSQueue::new(&config).await?);
     let config = Data::new(config);
 
     HttpServer::new(move || {
         App::new()
-            .wrap(Logger::default())
             .wrap(Cors::permissive())
             .wrap(Compress::default())
             .wrap(NormalizePath::trim())
+            .wrap(from_fn(metrics::middleware))
+            .wrap(from_fn(request_id::middleware))
             .app_data(db_conn.clone())
+            .app_data(feeds.clone())
+            .app_data(recent_writes.clone())
             .app_data(s3_media.clone())
+            .app_data(sqs.clone())
             .app_data(config.clone())
+            .configure(|srv| extractors::configure(srv, &config))
+            .configure(metrics::configure)
+            .configure(|srv| {
+                health::configure(
+                    srv,
+                    Readiness::new()
+                        .database(db_conn.clone())
+                        .bucket("s3_media", s3_media.clone())
+                        .jwt(config.clone()),
+                )
+            })
             .configure(|srv| {
                 RouteBuilder::new(srv)
-                    .extend::<FeedRouter>("/api/v0/feed")
-                    .build();
+                    .versioned(VersionScope::new(ApiVersion::V0), |routes| {
+                        routes.extend::<FeedRouter>("/feed")
+                    })
+                    .versioned(VersionScope::new(ApiVersion::V1), |routes| {
+                        routes.extend::<FeedRouter>("/feed")
+                    })
+                    .build_with_docs(|srv, mut api| {
+                        api.info = Info::new("feed", env!("CARGO_PKG_VERSION"));
+                        openapi::configure(srv, api, &config);
+                    });
             })
+            .default_service(web::to(extractors::not_found))
     })
     .bind(("0.0.0.0", 8080))?
     .run()
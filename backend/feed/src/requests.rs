@@ -1,20 +1,88 @@
+use std::borrow::Cow;
+
 use chrono::{DateTime, Utc};
+use common::aws::s3::MAX_UPLOAD_PARTS;
+use common::transform::{validate_transformations, Transformation};
 use serde::Deserialize;
+use utoipa::{IntoParams, ToSchema};
+use validator::{Validate, ValidationError};
 
 pub static DEFAULT_ITEMS_PER_PAGE: i64 = 20;
+pub const MAX_ITEMS_PER_PAGE: i64 = 100;
+pub const MAX_CAPTION_LENGTH: u64 = 1000;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema, Validate)]
 pub struct UpdateFeedItemRequest {
+    #[validate(length(
+        min = 1,
+        max = MAX_CAPTION_LENGTH,
+        message = "Caption must be between 1 and 1000 characters"
+    ))]
     pub caption: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema, Validate)]
 pub struct CreateFeedItemRequest {
+    #[validate(
+        required(message = "Caption is required"),
+        length(
+            min = 1,
+            max = MAX_CAPTION_LENGTH,
+            message = "Caption must be between 1 and 1000 characters"
+        )
+    )]
     pub caption: Option<String>,
+    /// MIME type of the media, which the upload must be sent with. Required by v1, v0
+    /// presigns a `url` accepting any content type without it.
+    pub content_type: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams, Validate)]
+#[into_params(parameter_in = Query)]
+pub struct SignedUrlRequest {
+    /// MIME type of the file, which the upload must be sent with if given
+    pub content_type: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema, Validate)]
+pub struct CreateUploadRequest {
+    /// MIME type of the media, which the completed upload gets
+    #[validate(required(message = "Content type is required"))]
+    pub content_type: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams, Validate)]
+#[into_params(parameter_in = Path)]
+pub struct UploadPartPath {
+    pub feed_id: i32,
+    pub upload_id: String,
+    #[validate(range(
+        min = 1,
+        max = MAX_UPLOAD_PARTS,
+        message = "Part number must be between 1 and 10000"
+    ))]
+    pub part_number: i32,
+}
+
+#[derive(Deserialize, ToSchema, Validate)]
+pub struct TransformFeedItemRequest {
+    #[validate(custom(function = "validate_transformation_list"))]
+    pub transformations: Vec<Transformation>,
+}
+
+#[derive(Deserialize, IntoParams, Validate)]
+#[into_params(parameter_in = Query)]
 pub struct ItemPageRequest {
     pub before: Option<DateTime<Utc>>,
-    pub limit: i64
+    #[validate(range(
+        min = 1,
+        max = MAX_ITEMS_PER_PAGE,
+        message = "Limit must be between 1 and 100"
+    ))]
+    pub limit: Option<i64>,
+}
+
+fn validate_transformation_list(transformations: &[Transformation]) -> Result<(), ValidationError> {
+    validate_transformations(transformations)
+        .map_err(|err| ValidationError::new("invalid").with_message(Cow::Borrowed(err.0)))
 }
@@ -1,14 +1,28 @@
+use common::aws::s3::{PresignedPost, UploadedPart};
 use common_web::models::{FeedItem, User};
+use common_web::router::ApiVersion;
 use serde::Serialize;
+use utoipa::ToSchema;
 
 use chrono::{DateTime, Utc};
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, ToSchema, Debug)]
 pub struct FeedItemResponse {
     pub id: i32,
     pub caption: Option<String>,
     pub url: String,
     pub editable: bool,
+    pub animated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub animated_url: Option<String>,
+    /// Why the media can't be processed, such as an undecodable upload or an edit
+    /// cropping outside of it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub processing_error: Option<String>,
+    /// Form uploading the media of a new feed item, unlike `url` it limits the size.
+    /// Only sent when the item was created with a `content_type`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload: Option<PresignedPost>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -20,6 +34,8 @@ impl From<(String, FeedItem)> for FeedItemResponse {
             caption,
             created_at,
             updated_at,
+            animated,
+            processing_error,
             ..
         } = item;
 
@@ -28,6 +44,10 @@ impl From<(String, FeedItem)> for FeedItemResponse {
             caption,
             url,
             editable: false,
+            animated,
+            animated_url: None,
+            processing_error,
+            upload: None,
             created_at: DateTime::<Utc>::from_utc(created_at, Utc),
             updated_at: DateTime::<Utc>::from_utc(updated_at, Utc),
         }
@@ -42,6 +62,8 @@ impl<'a> From<(&'a User, String, FeedItem)> for FeedItemResponse {
             created_at,
             updated_at,
             created_by,
+            animated,
+            processing_error,
             ..
         } = item;
 
@@ -50,8 +72,88 @@ impl<'a> From<(&'a User, String, FeedItem)> for FeedItemResponse {
             caption,
             url,
             editable: user.email.eq(&created_by),
+            animated,
+            animated_url: None,
+            processing_error,
+            upload: None,
             created_at: DateTime::<Utc>::from_utc(created_at, Utc),
             updated_at: DateTime::<Utc>::from_utc(updated_at, Utc),
         }
     }
 }
+
+/// Media of a feed item in v1 responses
+#[derive(Serialize, ToSchema, Debug)]
+pub struct MediaResponse {
+    pub url: String,
+    pub animated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub animated_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub processing_error: Option<String>,
+}
+
+/// A feed item as v1 answers it, with its media grouped
+#[derive(Serialize, ToSchema, Debug)]
+pub struct FeedItemResponseV1 {
+    pub id: i32,
+    pub caption: Option<String>,
+    pub editable: bool,
+    pub media: MediaResponse,
+    /// Form uploading the media of a new feed item, the only way v1 offers besides
+    /// multipart uploads
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload: Option<PresignedPost>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<FeedItemResponse> for FeedItemResponseV1 {
+    fn from(item: FeedItemResponse) -> Self {
+        FeedItemResponseV1 {
+            id: item.id,
+            caption: item.caption,
+            editable: item.editable,
+            media: MediaResponse {
+                url: item.url,
+                animated: item.animated,
+                animated_url: item.animated_url,
+                processing_error: item.processing_error,
+            },
+            upload: item.upload,
+            created_at: item.created_at,
+            updated_at: item.updated_at,
+        }
+    }
+}
+
+/// A feed item in the shape of the API version serving it
+#[derive(Serialize, ToSchema, Debug)]
+#[serde(untagged)]
+pub enum VersionedFeedItem {
+    V0(FeedItemResponse),
+    V1(FeedItemResponseV1),
+}
+
+impl FeedItemResponse {
+    pub fn versioned(self, version: ApiVersion) -> VersionedFeedItem {
+        match version {
+            ApiVersion::V0 => VersionedFeedItem::V0(self),
+            ApiVersion::V1 => VersionedFeedItem::V1(self.into()),
+        }
+    }
+}
+
+/// A multipart upload of the media of a feed item
+#[derive(Serialize, ToSchema, Debug)]
+pub struct UploadResponse {
+    pub id: i32,
+    pub upload_id: String,
+    /// Smallest size of the parts, but the last
+    pub min_part_bytes: u64,
+    pub max_parts: i32,
+    /// Largest size of the completed upload
+    pub max_bytes: u64,
+    /// Parts received so far, which a resumed upload skips
+    pub parts: Vec<UploadedPart>,
+}
@@ -0,0 +1,24 @@
+use actix_web::dev::Server;
+use actix_web::{App, HttpServer};
+
+use common::config::Config;
+use common_web::health::{self, Readiness};
+use common_web::metrics;
+
+/// Serves the metrics and health probes of the worker, which has no other HTTP endpoint
+pub fn serve<F>(config: &Config, readiness: F) -> std::io::Result<Server>
+where
+    F: Fn() -> Readiness + Send + Clone + 'static,
+{
+    let server = HttpServer::new(move || {
+        let readiness = readiness.clone();
+        App::new()
+            .configure(metrics::configure)
+            .configure(|srv| health::configure(srv, readiness()))
+    })
+    .workers(1)
+    .bind(("0.0.0.0", config.imgproc.http_port))?
+    .run();
+
+    Ok(server)
+}
@@ -1,10 +1,17 @@
+use std::fmt::Display;
 use std::sync::Arc;
 
-use serde_json::{Value, Map};
+use common::commands::ImageCommand;
+use common::transform::Transformation;
+
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
 
 pub enum EventType {
     ObjectCreated,
     ObjectRemoved,
+    Transform(Vec<Transformation>),
+    Revert,
 }
 
 pub struct Message {
@@ -12,41 +19,265 @@ pub struct Message {
     pub key: String,
 }
 
-pub fn to_messages(value: &Value) -> Option<Vec<Arc<Message>>> {
-    let object = value.as_object()?;
-    let records = object.get("Records")?.as_array()?;
+impl EventType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventType::ObjectCreated => "object_created",
+            EventType::ObjectRemoved => "object_removed",
+            EventType::Transform(_) => "transform",
+            EventType::Revert => "revert",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MessageError {
+    Malformed(serde_json::Error),
+    InvalidKey(String),
+}
+
+// Every queue message body imgproc understands
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Body {
+    // Notifications delivered through an SNS topic subscribed by the queue
+    Sns(SnsEnvelope),
+    // AWS S3 and MinIO bucket notifications
+    Notification(BucketNotification),
+    // Sent by S3 when the notification configuration is saved
+    S3Test(S3TestEvent),
+    Command(ImageCommand),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SnsEnvelope {
+    #[serde(rename = "Type")]
+    _type: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BucketNotification {
+    records: Vec<BucketRecord>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BucketRecord {
+    event_source: String,
+    event_name: String,
+    s3: BucketEntity,
+}
+
+#[derive(Deserialize)]
+struct BucketEntity {
+    object: BucketObject,
+}
+
+#[derive(Deserialize)]
+struct BucketObject {
+    key: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct S3TestEvent {
+    event: String,
+}
 
-    let mut result = Vec::new();
-    for rec in records {
-        if let Some(message) = to_message(rec.as_object()?) {
-            result.push(message);
+/// Parses a queue message body into the events it carries. Bodies without any event
+/// imgproc acts on, such as S3 test events, result in an empty list.
+pub fn to_messages(body: &str) -> Result<Vec<Arc<Message>>, MessageError> {
+    match serde_json::from_str::<Body>(body).map_err(MessageError::Malformed)? {
+        Body::Sns(envelope) => to_messages(&envelope.message),
+        Body::Notification(notification) => {
+            let mut result = Vec::new();
+            for record in notification.records {
+                if let Some(message) = to_message(record)? {
+                    result.push(Arc::new(message));
+                }
+            }
+            Ok(result)
+        }
+        Body::S3Test(test) => {
+            log::info!("Ignoring {}", test.event);
+            Ok(Vec::new())
         }
+        Body::Command(command) => Ok(vec![Arc::new(command.into())]),
     }
-    Some(result)
 }
 
-fn to_message(value: &Map<String, Value>) -> Option<Arc<Message>> {
-    let event_source = value.get("eventSource")?.as_str()?;
-    if event_source != "aws:s3" {
-        return None;
+fn to_message(record: BucketRecord) -> Result<Option<Message>, MessageError> {
+    if record.event_source != "aws:s3" && record.event_source != "minio:s3" {
+        log::debug!("Ignoring event from {}", record.event_source);
+        return Ok(None);
     }
 
-    let event_name = value.get("eventName")?.as_str()?;
+    // MinIO prefixes event names with "s3:"
+    let event_name = record
+        .event_name
+        .strip_prefix("s3:")
+        .unwrap_or(&record.event_name);
 
     let event_type = if event_name.starts_with("ObjectCreated") {
         EventType::ObjectCreated
     } else if event_name.starts_with("ObjectRemoved") {
         EventType::ObjectRemoved
     } else {
-        return None;
+        log::debug!("Ignoring event {}", record.event_name);
+        return Ok(None);
     };
 
-    let s3 = value.get("s3")?.as_object()?;
-    let object = s3.get("object")?.as_object()?;
-    let key = object.get("key")?.as_str()?;
-
-    Some(Arc::new(Message {
+    Ok(Some(Message {
         event_type,
-        key: key.into(),
+        key: decode_key(&record.s3.object.key)?,
     }))
 }
+
+// Keys in bucket notifications are form URL encoded
+fn decode_key(key: &str) -> Result<String, MessageError> {
+    let key = key.replace('+', " ");
+    percent_decode_str(&key)
+        .decode_utf8()
+        .map(|key| key.into_owned())
+        .map_err(|_| MessageError::InvalidKey(key.clone()))
+}
+
+impl From<ImageCommand> for Message {
+    fn from(command: ImageCommand) -> Self {
+        match command {
+            ImageCommand::Process { key } => Message {
+                event_type: EventType::ObjectCreated,
+                key,
+            },
+            ImageCommand::Transform {
+                key,
+                transformations,
+            } => Message {
+                event_type: EventType::Transform(transformations),
+                key,
+            },
+            ImageCommand::Revert { key } => Message {
+                event_type: EventType::Revert,
+                key,
+            },
+        }
+    }
+}
+
+impl Display for MessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageError::Malformed(e) => write!(f, "Unrecognized message: {}", e),
+            MessageError::InvalidKey(key) => write!(f, "Invalid object key: {}", key),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s3_record(source: &str, name: &str, key: &str) -> String {
+        format!(
+            r#"{{"eventSource":"{}","eventName":"{}","s3":{{"bucket":{{"name":"media"}},"object":{{"key":"{}","size":10}}}}}}"#,
+            source, name, key
+        )
+    }
+
+    #[test]
+    fn parses_s3_notification() {
+        let body = format!(
+            r#"{{"Records":[{},{}]}}"#,
+            s3_record("aws:s3", "ObjectCreated:Put", "abc"),
+            s3_record("aws:s3", "ObjectRemoved:Delete", "def")
+        );
+
+        let messages = to_messages(&body).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[0].event_type, EventType::ObjectCreated));
+        assert_eq!(messages[0].key, "abc");
+        assert!(matches!(messages[1].event_type, EventType::ObjectRemoved));
+        assert_eq!(messages[1].key, "def");
+    }
+
+    #[test]
+    fn parses_sns_envelope() {
+        let inner = format!(
+            r#"{{"Records":[{}]}}"#,
+            s3_record("aws:s3", "ObjectCreated:Put", "abc")
+        );
+        let body = serde_json::json!({
+            "Type": "Notification",
+            "MessageId": "1",
+            "TopicArn": "arn:aws:sns:us-east-1:0:media",
+            "Message": inner,
+        })
+        .to_string();
+
+        let messages = to_messages(&body).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].key, "abc");
+    }
+
+    #[test]
+    fn ignores_s3_test_event() {
+        let body = r#"{"Service":"Amazon S3","Event":"s3:TestEvent","Time":"2022-03-01T00:00:00.000Z","Bucket":"media","RequestId":"1","HostId":"2"}"#;
+
+        assert!(to_messages(body).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parses_minio_notification() {
+        let body = format!(
+            r#"{{"EventName":"s3:ObjectCreated:Put","Key":"media/abc","Records":[{}]}}"#,
+            s3_record("minio:s3", "s3:ObjectCreated:Put", "abc")
+        );
+
+        let messages = to_messages(&body).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(messages[0].event_type, EventType::ObjectCreated));
+    }
+
+    #[test]
+    fn skips_unknown_sources() {
+        let body = format!(
+            r#"{{"Records":[{}]}}"#,
+            s3_record("aws:sqs", "ObjectCreated:Put", "abc")
+        );
+
+        assert!(to_messages(&body).unwrap().is_empty());
+    }
+
+    #[test]
+    fn decodes_keys() {
+        let body = format!(
+            r#"{{"Records":[{}]}}"#,
+            s3_record("aws:s3", "ObjectCreated:Put", "my+photo%281%29.jpg")
+        );
+
+        let messages = to_messages(&body).unwrap();
+        assert_eq!(messages[0].key, "my photo(1).jpg");
+    }
+
+    #[test]
+    fn parses_commands() {
+        let body = r#"{"process":{"key":"abc"}}"#;
+        let messages = to_messages(body).unwrap();
+        assert!(matches!(messages[0].event_type, EventType::ObjectCreated));
+
+        let body = r#"{"transform":{"key":"abc","transformations":[{"op":"rotate","degrees":90}]}}"#;
+        let messages = to_messages(body).unwrap();
+        assert!(matches!(messages[0].event_type, EventType::Transform(_)));
+    }
+
+    #[test]
+    fn rejects_unknown_bodies() {
+        assert!(to_messages(r#"{"hello":"world"}"#).is_err());
+        assert!(to_messages("not json").is_err());
+    }
+}
@@ -0,0 +1,42 @@
+use std::sync::LazyLock;
+
+use prometheus::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
+
+// Label of queue messages that could not be parsed into events
+pub const MALFORMED: &str = "malformed";
+
+pub static EVENTS_RECEIVED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "imgproc_events_received_total",
+        "Number of events received from the queue",
+        &["event"]
+    )
+    .expect("Failed to register imgproc_events_received_total")
+});
+
+pub static EVENTS_PROCESSED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "imgproc_events_processed_total",
+        "Number of events processed successfully",
+        &["event"]
+    )
+    .expect("Failed to register imgproc_events_processed_total")
+});
+
+pub static EVENTS_FAILED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "imgproc_events_failed_total",
+        "Number of events which failed to be processed",
+        &["event"]
+    )
+    .expect("Failed to register imgproc_events_failed_total")
+});
+
+pub static PROCESSING_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "imgproc_processing_duration_seconds",
+        "Duration of event processing",
+        &["event"]
+    )
+    .expect("Failed to register imgproc_processing_duration_seconds")
+});
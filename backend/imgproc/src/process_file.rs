@@ -0,0 +1,45 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+use clap::Args;
+use tokio::fs;
+
+use common::aws::s3::animated_thumbnail_key;
+
+use crate::source::Source;
+use crate::thumbnail::{process_image, ThumbnailSettings};
+
+#[derive(Args)]
+pub struct ProcessFileArgs {
+    /// Image to generate thumbnails for
+    input: PathBuf,
+    /// Path of the JPEG thumbnail, an animated thumbnail is written next to it with a `.webp` suffix
+    output: PathBuf,
+    /// Only report the thumbnails that would be written
+    #[arg(long)]
+    dry_run: bool,
+}
+
+/// Runs the thumbnail pipeline used by the worker on a local file
+pub async fn run(settings: &ThumbnailSettings, args: ProcessFileArgs) -> Result<(), Box<dyn Error>> {
+    log::info!("Processing {}", args.input.to_string_lossy());
+    let source = Source::File(args.input.clone());
+    let thumbnail = tokio::task::block_in_place(|| process_image(&source, settings))?;
+
+    let mut outputs = vec![(args.output.clone(), thumbnail.poster)];
+    if let Some(animation) = thumbnail.animation {
+        let animated_output = animated_thumbnail_key(&args.output.to_string_lossy());
+        outputs.push((PathBuf::from(animated_output), animation));
+    }
+
+    for (path, data) in outputs {
+        if args.dry_run {
+            log::info!("would write {} ({} bytes)", path.to_string_lossy(), data.len());
+        } else {
+            fs::write(&path, &data).await?;
+            log::info!("wrote {} ({} bytes)", path.to_string_lossy(), data.len());
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use clap::{ArgGroup, Args};
+
+use common::aws::s3::{Media, Thumbnails};
+use common::aws::S3Bucket;
+use common::config::Config;
+
+use common_web::database;
+use common_web::repositories::{DbFeedRepository, FeedRepository};
+
+use crate::thumbnail::ThumbnailSettings;
+use crate::worker::{handle_object_created, Processed};
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("selection").required(true).args(["all", "since", "key"])))]
+pub struct ReprocessArgs {
+    /// Reprocess every feed item
+    #[arg(long)]
+    all: bool,
+    /// Reprocess feed items created at or after this RFC 3339 timestamp
+    #[arg(long)]
+    since: Option<DateTime<Utc>>,
+    /// Reprocess the feed item with this image key
+    #[arg(long)]
+    key: Option<String>,
+    /// Only list the feed items that would be reprocessed
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Debug)]
+enum ReprocessError {
+    Failed { failed: usize, total: usize },
+}
+
+/// Regenerates the thumbnails of the selected feed items, one at a time
+pub async fn run(config: &Config, args: ReprocessArgs) -> Result<(), Box<dyn Error>> {
+    let db_conn = database::create_db_conn_pool(config).await?;
+    let feeds: Arc<dyn FeedRepository> = Arc::new(DbFeedRepository::new(db_conn));
+
+    let keys = select_keys(&*feeds, &args).await?;
+    let total = keys.len();
+    log::info!("Found {} feed items to reprocess", total);
+
+    if args.dry_run {
+        for (index, key) in keys.iter().enumerate() {
+            log::info!("[{}/{}] would reprocess {}", index + 1, total, key);
+        }
+        return Ok(());
+    }
+
+    let media_bucket = Arc::new(S3Bucket::<Media>::new(config).await);
+    let thumbs_bucket = Arc::new(S3Bucket::<Thumbnails>::new(config).await);
+    let settings = Arc::new(ThumbnailSettings::from(config));
+
+    let max_bytes = media_bucket.upload_max_bytes();
+
+    let mut failed = 0;
+    for (index, key) in keys.iter().enumerate() {
+        let result = handle_object_created(
+            key,
+            &*media_bucket,
+            thumbs_bucket.clone(),
+            feeds.clone(),
+            settings.clone(),
+            max_bytes,
+        )
+        .await;
+
+        match result {
+            Ok(Processed::TooLarge) => log::warn!(
+                "[{}/{}] skipped {}, larger than uploads allow",
+                index + 1,
+                total,
+                key
+            ),
+            Ok(_) => log::info!("[{}/{}] reprocessed {}", index + 1, total, key),
+            Err(err) => {
+                failed += 1;
+                log::error!("[{}/{}] failed to reprocess {}: {}", index + 1, total, key, err);
+            }
+        }
+    }
+
+    log::info!(
+        "Reprocessed {} of {} feed items",
+        total - failed,
+        total
+    );
+
+    if failed > 0 {
+        return Err(ReprocessError::Failed { failed, total }.into());
+    }
+
+    Ok(())
+}
+
+async fn select_keys(
+    feeds: &dyn FeedRepository,
+    args: &ReprocessArgs,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let since = args.since.map(|since| since.naive_utc());
+
+    Ok(feeds.image_ids(since, args.key.clone()).await?)
+}
+
+impl Display for ReprocessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReprocessError::Failed { failed, total } => {
+                write!(f, "Failed to reprocess {} of {} feed items", failed, total)
+            }
+        }
+    }
+}
+
+impl Error for ReprocessError {}
@@ -0,0 +1,211 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Seek};
+use std::path::PathBuf;
+
+use common::aws::S3Bucket;
+
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+use tokio_stream::{Stream, StreamExt};
+
+pub trait ReadSeek: BufRead + Seek {}
+impl<T: BufRead + Seek> ReadSeek for T {}
+
+/// Media to be processed
+pub enum Source {
+    Memory(Vec<u8>),
+    // Downloads larger than the in memory cap, removed once dropped
+    TempFile(NamedTempFile),
+    // Local files processed from the command line
+    File(PathBuf),
+}
+
+impl Source {
+    pub fn reader(&self) -> io::Result<Box<dyn ReadSeek + '_>> {
+        match self {
+            Source::Memory(data) => Ok(Box::new(Cursor::new(data.as_slice()))),
+            Source::TempFile(file) => Ok(Box::new(BufReader::new(file.reopen()?))),
+            Source::File(path) => Ok(Box::new(BufReader::new(File::open(path)?))),
+        }
+    }
+}
+
+/// Media larger than the input limit, nothing past the limit is read
+#[derive(Debug)]
+pub struct InputTooLarge {
+    // Announced length of the media, or the bytes read when crossing the limit
+    pub size: usize,
+    pub max: usize,
+}
+
+impl Display for InputTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Media of {} bytes is larger than the {} bytes allowed",
+            self.size, self.max
+        )
+    }
+}
+
+impl Error for InputTooLarge {}
+
+/// Downloads an object into memory, switching to a uniquely named temp file as soon as
+/// it turns out to be larger than `max_in_memory_bytes`. Objects larger than
+/// `max_input_bytes` are rejected.
+pub async fn download<T>(
+    bucket: &S3Bucket<T>,
+    key: &String,
+    max_in_memory_bytes: usize,
+    max_input_bytes: usize,
+) -> Result<Source, Box<dyn Error>> {
+    log::debug!("downloading media {}", key);
+    let object = bucket.get_object(key).await?;
+
+    let content_length = usize::try_from(object.content_length).unwrap_or(0);
+    let source = buffer(
+        object.body,
+        content_length,
+        max_in_memory_bytes,
+        max_input_bytes,
+    )
+    .await?;
+
+    if let Source::TempFile(temp) = &source {
+        log::debug!("media {} stored at {}", key, temp.path().to_string_lossy());
+    }
+    Ok(source)
+}
+
+// Reads a body announced to be `content_length` bytes long, 0 if unknown
+async fn buffer<S, B, E>(
+    mut body: S,
+    content_length: usize,
+    max_in_memory_bytes: usize,
+    max_input_bytes: usize,
+) -> Result<Source, Box<dyn Error>>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: Error + 'static,
+{
+    if content_length > max_input_bytes {
+        return Err(InputTooLarge {
+            size: content_length,
+            max: max_input_bytes,
+        }
+        .into());
+    }
+
+    let mut buffer = Vec::new();
+    let mut temp_file = if content_length > max_in_memory_bytes {
+        Some(create_temp_file()?)
+    } else {
+        buffer.reserve(content_length);
+        None
+    };
+
+    let mut size = 0;
+    while let Some(chunk) = body.try_next().await? {
+        let chunk = chunk.as_ref();
+
+        size += chunk.len();
+        if size > max_input_bytes {
+            return Err(InputTooLarge {
+                size,
+                max: max_input_bytes,
+            }
+            .into());
+        }
+
+        if temp_file.is_none() && buffer.len() + chunk.len() > max_in_memory_bytes {
+            let (temp, mut file) = create_temp_file()?;
+            file.write_all(&buffer).await?;
+            buffer = Vec::new();
+            temp_file = Some((temp, file));
+        }
+
+        match &mut temp_file {
+            Some((_, file)) => file.write_all(chunk).await?,
+            None => buffer.extend_from_slice(chunk),
+        }
+    }
+
+    match temp_file {
+        Some((temp, mut file)) => {
+            file.flush().await?;
+            Ok(Source::TempFile(temp))
+        }
+        None => Ok(Source::Memory(buffer)),
+    }
+}
+
+fn create_temp_file() -> io::Result<(NamedTempFile, tokio::fs::File)> {
+    let temp = tempfile::Builder::new().prefix("imgproc_").tempfile()?;
+    let file = tokio::fs::File::from_std(temp.reopen()?);
+    Ok((temp, file))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn body(chunks: &[&'static str]) -> impl Stream<Item = Result<&'static [u8], io::Error>> {
+        tokio_stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(chunk.as_bytes()))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn contents(source: &Source) -> String {
+        let mut contents = String::new();
+        source
+            .reader()
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    #[tokio::test]
+    async fn keeps_small_media_in_memory() {
+        let source = buffer(body(&["ab", "cd"]), 4, 4, 8).await.unwrap();
+
+        assert!(matches!(source, Source::Memory(_)));
+        assert_eq!(contents(&source), "abcd");
+    }
+
+    #[tokio::test]
+    async fn spills_media_announced_larger_than_memory() {
+        let source = buffer(body(&["ab", "cd", "ef"]), 6, 4, 8).await.unwrap();
+
+        assert!(matches!(source, Source::TempFile(_)));
+        assert_eq!(contents(&source), "abcdef");
+    }
+
+    #[tokio::test]
+    async fn spills_media_outgrowing_memory() {
+        let source = buffer(body(&["ab", "cd", "ef"]), 0, 4, 8).await.unwrap();
+
+        assert!(matches!(source, Source::TempFile(_)));
+        assert_eq!(contents(&source), "abcdef");
+    }
+
+    #[tokio::test]
+    async fn rejects_media_larger_than_allowed() {
+        let err = buffer(body(&[]), 9, 4, 8).await.err().unwrap();
+        assert_eq!(err.downcast_ref::<InputTooLarge>().unwrap().size, 9);
+
+        let err = buffer(body(&["abcd", "efgh", "ij"]), 0, 4, 8)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.downcast_ref::<InputTooLarge>().unwrap().size, 10);
+    }
+}
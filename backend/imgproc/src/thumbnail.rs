@@ -0,0 +1,342 @@
+use std::error::Error;
+use std::io::Cursor;
+use std::time::Duration;
+
+use common::config::{
+    Config, DEFAULT_ANIMATION_MAX_BYTES, DEFAULT_ANIMATION_MAX_DURATION_MS,
+    DEFAULT_ANIMATION_MAX_FRAMES, DEFAULT_MAX_INPUT_BYTES, DEFAULT_MAX_IN_MEMORY_BYTES,
+};
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::io::Reader as ImageReader;
+use image::{AnimationDecoder, DynamicImage, Frame, ImageFormat, RgbaImage};
+
+use webp::{AnimEncoder, AnimFrame, WebPConfig};
+
+use crate::source::Source;
+
+pub const THUMBNAIL_WIDTH: u32 = 300;
+pub const THUMBNAIL_HEIGHT: u32 = 240;
+
+const ANIMATION_QUALITY: f32 = 75.0;
+
+pub struct ThumbnailSettings {
+    pub max_frames: usize,
+    pub max_duration: Duration,
+    pub max_bytes: usize,
+    pub max_in_memory_bytes: usize,
+    pub max_input_bytes: usize,
+}
+
+impl From<&Config> for ThumbnailSettings {
+    fn from(config: &Config) -> Self {
+        ThumbnailSettings {
+            max_frames: config.imgproc.animation_max_frames,
+            max_duration: config.imgproc.animation_max_duration,
+            max_bytes: config.imgproc.animation_max_bytes,
+            max_in_memory_bytes: config.imgproc.max_in_memory_bytes,
+            max_input_bytes: config.imgproc.max_input_bytes,
+        }
+    }
+}
+
+impl Default for ThumbnailSettings {
+    fn default() -> Self {
+        ThumbnailSettings {
+            max_frames: DEFAULT_ANIMATION_MAX_FRAMES,
+            max_duration: Duration::from_millis(DEFAULT_ANIMATION_MAX_DURATION_MS),
+            max_bytes: DEFAULT_ANIMATION_MAX_BYTES,
+            max_in_memory_bytes: DEFAULT_MAX_IN_MEMORY_BYTES,
+            max_input_bytes: DEFAULT_MAX_INPUT_BYTES,
+        }
+    }
+}
+
+pub struct Thumbnail {
+    // JPEG thumbnail, the poster frame of animated media
+    pub poster: Vec<u8>,
+    // Animated WebP thumbnail of animated media
+    pub animation: Option<Vec<u8>>,
+}
+
+struct EncodedAnimation {
+    poster: DynamicImage,
+    data: Vec<u8>,
+}
+
+struct ThumbnailFrame {
+    image: RgbaImage,
+    delay: Duration,
+}
+
+#[derive(Debug)]
+enum ThumbnailError {
+    AnimationEncodingFailed(String),
+}
+
+/// Generates the JPEG thumbnail of the media. Animated GIF and WebP inputs additionally
+/// produce an animated WebP thumbnail.
+pub fn process_image(
+    source: &Source,
+    settings: &ThumbnailSettings,
+) -> Result<Thumbnail, Box<dyn Error>> {
+    let format = ImageReader::new(source.reader()?)
+        .with_guessed_format()?
+        .format();
+
+    let frames = match format {
+        Some(format @ (ImageFormat::Gif | ImageFormat::WebP)) => {
+            decode_frames(source, format, settings)?
+        }
+        _ => Vec::new(),
+    };
+
+    if frames.len() >= 2 {
+        if let Some(EncodedAnimation { poster, data }) = encode_animation(frames, settings)? {
+            return Ok(Thumbnail {
+                poster: encode_poster(poster)?,
+                animation: Some(data),
+            });
+        }
+
+        // Even a two frame animation exceeds the size limit, keep only the poster frame
+        log::warn!("animated thumbnail too large, falling back to static");
+    }
+
+    let img = ImageReader::new(source.reader()?)
+        .with_guessed_format()?
+        .decode()?;
+
+    let resize_img = img.thumbnail(THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT);
+
+    Ok(Thumbnail {
+        poster: encode_poster(resize_img)?,
+        animation: None,
+    })
+}
+
+fn encode_poster(poster: DynamicImage) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut data = Cursor::new(Vec::new());
+
+    // JPEG has no alpha channel
+    DynamicImage::ImageRgb8(poster.to_rgb8()).write_to(&mut data, ImageFormat::Jpeg)?;
+
+    Ok(data.into_inner())
+}
+
+// Decodes at most `max_frames` frames, stopping early once `max_duration` is reached.
+// Returns fewer than two frames for still images, even when stored in an animation capable format.
+fn decode_frames(
+    source: &Source,
+    format: ImageFormat,
+    settings: &ThumbnailSettings,
+) -> Result<Vec<ThumbnailFrame>, Box<dyn Error>> {
+    let reader = source.reader()?;
+
+    let frames = match format {
+        ImageFormat::Gif => GifDecoder::new(reader)?.into_frames(),
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(reader)?;
+            if !decoder.has_animation() {
+                return Ok(Vec::new());
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut result = Vec::new();
+    let mut total = Duration::ZERO;
+    for frame in frames.take(settings.max_frames) {
+        let frame: Frame = frame?;
+        let delay = Duration::from(frame.delay());
+
+        let image = DynamicImage::ImageRgba8(frame.into_buffer())
+            .thumbnail(THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT)
+            .to_rgba8();
+
+        result.push(ThumbnailFrame { image, delay });
+
+        total += delay;
+        if total >= settings.max_duration {
+            break;
+        }
+    }
+
+    Ok(result)
+}
+
+// Encodes the frames as an animated WebP, halving the frame rate until the result fits in
+// `max_bytes`. Returns the poster frame and the encoded data, or None if it never fits.
+fn encode_animation(
+    mut frames: Vec<ThumbnailFrame>,
+    settings: &ThumbnailSettings,
+) -> Result<Option<EncodedAnimation>, Box<dyn Error>> {
+    let poster = DynamicImage::ImageRgba8(frames[0].image.clone());
+
+    while frames.len() >= 2 {
+        let data = encode_webp(&frames)?;
+        log::debug!("animated thumbnail: {} frames, {} bytes", frames.len(), data.len());
+
+        if data.len() <= settings.max_bytes {
+            return Ok(Some(EncodedAnimation { poster, data }));
+        }
+
+        frames = drop_every_other_frame(frames);
+    }
+
+    Ok(None)
+}
+
+fn encode_webp(frames: &[ThumbnailFrame]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (width, height) = frames[0].image.dimensions();
+
+    let mut config = WebPConfig::new()
+        .map_err(|_| ThumbnailError::AnimationEncodingFailed("Invalid WebP config".into()))?;
+    config.quality = ANIMATION_QUALITY;
+
+    let mut encoder = AnimEncoder::new(width, height, &config);
+    encoder.set_loop_count(0);
+
+    let mut timestamp = Duration::ZERO;
+    for frame in frames {
+        // Decoded frames span the whole canvas, but the encoder must never read past a buffer
+        if frame.image.dimensions() != (width, height) {
+            continue;
+        }
+        encoder.add_frame(AnimFrame::from_rgba(
+            frame.image.as_raw(),
+            width,
+            height,
+            timestamp.as_millis() as i32,
+        ));
+        timestamp += frame.delay;
+    }
+
+    let data = encoder
+        .try_encode()
+        .map_err(|e| ThumbnailError::AnimationEncodingFailed(format!("{:?}", e)))?;
+
+    Ok(data.to_vec())
+}
+
+// Merges each pair of frames into one, keeping the total duration
+fn drop_every_other_frame(frames: Vec<ThumbnailFrame>) -> Vec<ThumbnailFrame> {
+    let mut result: Vec<ThumbnailFrame> = Vec::with_capacity(frames.len() / 2 + 1);
+
+    for (index, frame) in frames.into_iter().enumerate() {
+        if index % 2 == 0 {
+            result.push(frame);
+        } else if let Some(last) = result.last_mut() {
+            last.delay += frame.delay;
+        }
+    }
+
+    result
+}
+
+impl std::fmt::Display for ThumbnailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ThumbnailError::AnimationEncodingFailed(msg) => {
+                write!(f, "Failed to encode animated thumbnail: {}", msg)
+            }
+        }
+    }
+}
+
+impl Error for ThumbnailError {}
+
+#[cfg(test)]
+mod tests {
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, Rgba};
+
+    use super::*;
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+
+    // GIF whose first frame is red and the others blue, each shown for `delay_ms`
+    fn gif(frames: usize, delay_ms: u64) -> Source {
+        let mut data = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut data);
+            for index in 0..frames {
+                let color = if index == 0 { RED } else { BLUE };
+                let buffer = RgbaImage::from_pixel(40, 30, Rgba(color));
+                let delay = Delay::from_saturating_duration(Duration::from_millis(delay_ms));
+                encoder
+                    .encode_frame(Frame::from_parts(buffer, 0, 0, delay))
+                    .unwrap();
+            }
+        }
+        Source::Memory(data)
+    }
+
+    fn is_red(jpeg: &[u8]) -> bool {
+        let poster = image::load_from_memory_with_format(jpeg, ImageFormat::Jpeg)
+            .unwrap()
+            .to_rgb8();
+        let [r, g, b] = poster.get_pixel(poster.width() / 2, poster.height() / 2).0;
+        r > 200 && g < 60 && b < 60
+    }
+
+    #[test]
+    fn animates_thumbnails_of_animated_gifs() {
+        let thumbnail = process_image(&gif(3, 100), &ThumbnailSettings::default()).unwrap();
+
+        let animation = thumbnail.animation.unwrap();
+        assert_eq!(&animation[..4], b"RIFF");
+        assert_eq!(&animation[8..12], b"WEBP");
+        assert!(is_red(&thumbnail.poster));
+    }
+
+    #[test]
+    fn keeps_still_gifs_still() {
+        let thumbnail = process_image(&gif(1, 100), &ThumbnailSettings::default()).unwrap();
+
+        assert!(thumbnail.animation.is_none());
+        assert!(is_red(&thumbnail.poster));
+    }
+
+    #[test]
+    fn limits_decoded_frames() {
+        let settings = ThumbnailSettings {
+            max_frames: 4,
+            ..ThumbnailSettings::default()
+        };
+        let frames = decode_frames(&gif(10, 100), ImageFormat::Gif, &settings).unwrap();
+        assert_eq!(frames.len(), 4);
+
+        let settings = ThumbnailSettings {
+            max_duration: Duration::from_millis(300),
+            ..ThumbnailSettings::default()
+        };
+        let frames = decode_frames(&gif(10, 100), ImageFormat::Gif, &settings).unwrap();
+        assert_eq!(frames.len(), 3);
+    }
+
+    #[test]
+    fn halves_frame_rate_keeping_duration() {
+        let settings = ThumbnailSettings::default();
+        let frames = decode_frames(&gif(5, 100), ImageFormat::Gif, &settings).unwrap();
+
+        let frames = drop_every_other_frame(frames);
+        let delays: Vec<_> = frames.iter().map(|frame| frame.delay.as_millis()).collect();
+        assert_eq!(delays, [200, 200, 100]);
+    }
+
+    #[test]
+    fn falls_back_to_the_poster_frame_when_too_large() {
+        let settings = ThumbnailSettings {
+            max_bytes: 1,
+            ..ThumbnailSettings::default()
+        };
+        let thumbnail = process_image(&gif(3, 100), &settings).unwrap();
+
+        assert!(thumbnail.animation.is_none());
+        assert!(is_red(&thumbnail.poster));
+    }
+}
@@ -0,0 +1,489 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::future::Future;
+use std::io::Cursor;
+
+use common::aws::s3::{original_media_key, ByteStream, Media};
+use common::aws::S3Bucket;
+use common::transform::{FlipDirection, Transformation};
+
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::io::Reader as ImageReader;
+use image::{AnimationDecoder, DynamicImage, Frame, GenericImageView, ImageFormat};
+
+use crate::source::{download, Source};
+
+// Quantization speed of GIF frames, from 1 (best) to 30 (fastest)
+const GIF_SPEED: i32 = 10;
+
+#[derive(Debug)]
+pub enum TransformError {
+    CropOutOfBounds,
+    TooLarge { size: u64, max: u64 },
+}
+
+/// Objects of the media bucket edits go through
+#[allow(clippy::ptr_arg)]
+pub trait MediaStore {
+    fn exists(&self, key: &String) -> impl Future<Output = Result<bool, Box<dyn Error>>> + Send;
+
+    /// Size of the object in bytes, `None` when it does not exist
+    fn size(
+        &self,
+        key: &String,
+    ) -> impl Future<Output = Result<Option<u64>, Box<dyn Error>>> + Send;
+
+    fn copy(
+        &self,
+        from: &String,
+        to: &String,
+    ) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+
+    fn read(
+        &self,
+        key: &String,
+        max_in_memory_bytes: usize,
+        max_input_bytes: usize,
+    ) -> impl Future<Output = Result<Source, Box<dyn Error>>> + Send;
+
+    fn write(
+        &self,
+        key: &String,
+        content_type: &'static str,
+        data: Vec<u8>,
+    ) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+
+    fn delete(&self, key: &String) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+}
+
+impl MediaStore for S3Bucket<Media> {
+    async fn exists(&self, key: &String) -> Result<bool, Box<dyn Error>> {
+        self.object_exists(key).await
+    }
+
+    async fn size(&self, key: &String) -> Result<Option<u64>, Box<dyn Error>> {
+        self.object_size(key).await
+    }
+
+    async fn copy(&self, from: &String, to: &String) -> Result<(), Box<dyn Error>> {
+        self.copy_object(from, to).await
+    }
+
+    async fn read(
+        &self,
+        key: &String,
+        max_in_memory_bytes: usize,
+        max_input_bytes: usize,
+    ) -> Result<Source, Box<dyn Error>> {
+        download(self, key, max_in_memory_bytes, max_input_bytes).await
+    }
+
+    async fn write(
+        &self,
+        key: &String,
+        content_type: &'static str,
+        data: Vec<u8>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.put_object(key, content_type, ByteStream::from(data))
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &String) -> Result<(), Box<dyn Error>> {
+        self.delete_object(key).await
+    }
+}
+
+/// Replaces the media with its original transformed in order, keeping the untouched
+/// original the first time the media is edited so every later edit starts over from it.
+/// Thumbnails follow from the event of the object written.
+pub async fn edit_media<M: MediaStore>(
+    store: &M,
+    key: &String,
+    transformations: &[Transformation],
+    max_in_memory_bytes: usize,
+    max_input_bytes: usize,
+    max_bytes: u64,
+) -> Result<(), Box<dyn Error>> {
+    let original_key = original_media_key(key);
+    if !store.exists(&original_key).await? {
+        log::debug!("keeping original of {}", key);
+        store.copy(key, &original_key).await?;
+    }
+
+    let source = store
+        .read(&original_key, max_in_memory_bytes, max_input_bytes)
+        .await?;
+    let (data, content_type) =
+        tokio::task::block_in_place(|| transform_image(&source, transformations))?;
+    drop(source);
+
+    // Would be deleted as soon as stored
+    let size = data.len() as u64;
+    if size > max_bytes {
+        return Err(TransformError::TooLarge {
+            size,
+            max: max_bytes,
+        }
+        .into());
+    }
+
+    log::debug!("uploading transformed media");
+    store.write(key, content_type, data).await
+}
+
+/// Restores the original of edited media, thumbnails follow from the event of the object
+/// copied back
+pub async fn revert_media<M: MediaStore>(store: &M, key: &String) -> Result<(), Box<dyn Error>> {
+    let original_key = original_media_key(key);
+
+    if !store.exists(&original_key).await? {
+        log::info!("media {} was never edited", key);
+        return Ok(());
+    }
+
+    store.copy(&original_key, key).await?;
+    store.delete(&original_key).await
+}
+
+/// Applies the transformations in order to the media, to every frame of animated GIFs.
+/// Returns the encoded image along with its content type.
+pub fn transform_image(
+    source: &Source,
+    transformations: &[Transformation],
+) -> Result<(Vec<u8>, &'static str), Box<dyn Error>> {
+    let reader = ImageReader::new(source.reader()?).with_guessed_format()?;
+
+    // Keep the format of the original where it can be encoded
+    let format = match reader.format() {
+        Some(format @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Gif)) => format,
+        _ => ImageFormat::Png,
+    };
+
+    if format == ImageFormat::Gif {
+        let frames = GifDecoder::new(source.reader()?)?
+            .into_frames()
+            .collect_frames()?;
+        if frames.len() >= 2 {
+            let data = transform_frames(frames, transformations)?;
+            return Ok((data, format.to_mime_type()));
+        }
+    }
+
+    let mut img = reader.decode()?;
+    for transformation in transformations {
+        img = apply(img, transformation)?;
+    }
+
+    if format == ImageFormat::Jpeg {
+        // JPEG has no alpha channel
+        img = DynamicImage::ImageRgb8(img.to_rgb8());
+    }
+
+    let mut data = Cursor::new(Vec::new());
+    img.write_to(&mut data, format)?;
+
+    Ok((data.into_inner(), format.to_mime_type()))
+}
+
+// Decoded frames span the whole canvas, so each one is transformed alike. The loop count
+// isn't decoded, animations loop forever like most GIFs.
+fn transform_frames(
+    frames: Vec<Frame>,
+    transformations: &[Transformation],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut data = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut data, GIF_SPEED);
+        encoder.set_repeat(Repeat::Infinite)?;
+
+        for frame in frames {
+            let delay = frame.delay();
+            let mut img = DynamicImage::ImageRgba8(frame.into_buffer());
+            for transformation in transformations {
+                img = apply(img, transformation)?;
+            }
+            encoder.encode_frame(Frame::from_parts(img.to_rgba8(), 0, 0, delay))?;
+        }
+    }
+
+    Ok(data)
+}
+
+fn apply(img: DynamicImage, transformation: &Transformation) -> Result<DynamicImage, TransformError> {
+    let img = match *transformation {
+        Transformation::Crop {
+            x,
+            y,
+            width,
+            height,
+        } => {
+            let (img_width, img_height) = img.dimensions();
+            let fits_x = x.checked_add(width).is_some_and(|right| right <= img_width);
+            let fits_y = y.checked_add(height).is_some_and(|bottom| bottom <= img_height);
+            if !fits_x || !fits_y {
+                return Err(TransformError::CropOutOfBounds);
+            }
+            img.crop_imm(x, y, width, height)
+        }
+        Transformation::Rotate { degrees: 90 } => img.rotate90(),
+        Transformation::Rotate { degrees: 180 } => img.rotate180(),
+        Transformation::Rotate { degrees: 270 } => img.rotate270(),
+        // Validated by the feed service, ignore anything else
+        Transformation::Rotate { .. } => img,
+        Transformation::Flip {
+            direction: FlipDirection::Horizontal,
+        } => img.fliph(),
+        Transformation::Flip {
+            direction: FlipDirection::Vertical,
+        } => img.flipv(),
+        Transformation::Brightness { value } => img.brighten(value),
+        Transformation::Contrast { value } => img.adjust_contrast(value),
+        Transformation::Grayscale => img.grayscale(),
+    };
+
+    Ok(img)
+}
+
+impl Display for TransformError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransformError::CropOutOfBounds => write!(f, "Crop rectangle is outside the image"),
+            TransformError::TooLarge { size, max } => write!(
+                f,
+                "Transformed media of {} bytes is larger than the {} bytes allowed",
+                size, max
+            ),
+        }
+    }
+}
+
+impl Error for TransformError {}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use image::{Delay, Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    // 4x3 image, black but for a red top left and a blue bottom right pixel
+    fn image() -> DynamicImage {
+        let mut img = RgbImage::new(4, 3);
+        img.put_pixel(0, 0, Rgb([255, 0, 0]));
+        img.put_pixel(3, 2, Rgb([0, 0, 255]));
+        DynamicImage::ImageRgb8(img)
+    }
+
+    fn png(img: &DynamicImage) -> Vec<u8> {
+        let mut data = Cursor::new(Vec::new());
+        img.write_to(&mut data, ImageFormat::Png).unwrap();
+        data.into_inner()
+    }
+
+    fn decode(data: &[u8]) -> DynamicImage {
+        image::load_from_memory(data).unwrap()
+    }
+
+    fn pixel(img: &DynamicImage, x: u32, y: u32) -> [u8; 3] {
+        img.to_rgb8().get_pixel(x, y).0
+    }
+
+    // Media bucket kept in memory
+    #[derive(Default)]
+    pub(crate) struct MemoryStore(Mutex<HashMap<String, Vec<u8>>>);
+
+    impl MemoryStore {
+        pub(crate) fn get(&self, key: &String) -> Option<Vec<u8>> {
+            self.0.lock().unwrap().get(key).cloned()
+        }
+
+        pub(crate) fn insert(&self, key: &String, data: Vec<u8>) {
+            self.0.lock().unwrap().insert(key.to_string(), data);
+        }
+    }
+
+    impl MediaStore for MemoryStore {
+        async fn exists(&self, key: &String) -> Result<bool, Box<dyn Error>> {
+            Ok(self.get(key).is_some())
+        }
+
+        async fn size(&self, key: &String) -> Result<Option<u64>, Box<dyn Error>> {
+            Ok(self.get(key).map(|data| data.len() as u64))
+        }
+
+        async fn copy(&self, from: &String, to: &String) -> Result<(), Box<dyn Error>> {
+            let data = self.get(from).ok_or("no such key")?;
+            self.insert(to, data);
+            Ok(())
+        }
+
+        async fn read(&self, key: &String, _: usize, _: usize) -> Result<Source, Box<dyn Error>> {
+            Ok(Source::Memory(self.get(key).ok_or("no such key")?))
+        }
+
+        async fn write(
+            &self,
+            key: &String,
+            _: &'static str,
+            data: Vec<u8>,
+        ) -> Result<(), Box<dyn Error>> {
+            self.insert(key, data);
+            Ok(())
+        }
+
+        async fn delete(&self, key: &String) -> Result<(), Box<dyn Error>> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn crops() {
+        let crop = Transformation::Crop {
+            x: 2,
+            y: 1,
+            width: 2,
+            height: 2,
+        };
+        let img = apply(image(), &crop).unwrap();
+        assert_eq!(img.dimensions(), (2, 2));
+        assert_eq!(pixel(&img, 1, 1), [0, 0, 255]);
+
+        let outside = Transformation::Crop {
+            x: 3,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        assert!(matches!(
+            apply(image(), &outside),
+            Err(TransformError::CropOutOfBounds)
+        ));
+    }
+
+    #[test]
+    fn rotates_and_flips() {
+        let img = apply(image(), &Transformation::Rotate { degrees: 90 }).unwrap();
+        assert_eq!(img.dimensions(), (3, 4));
+        assert_eq!(pixel(&img, 2, 0), [255, 0, 0]);
+
+        let img = apply(image(), &Transformation::Rotate { degrees: 180 }).unwrap();
+        assert_eq!(pixel(&img, 3, 2), [255, 0, 0]);
+
+        let img = apply(image(), &Transformation::Rotate { degrees: 270 }).unwrap();
+        assert_eq!(img.dimensions(), (3, 4));
+        assert_eq!(pixel(&img, 0, 3), [255, 0, 0]);
+
+        let horizontal = Transformation::Flip {
+            direction: FlipDirection::Horizontal,
+        };
+        assert_eq!(
+            pixel(&apply(image(), &horizontal).unwrap(), 3, 0),
+            [255, 0, 0]
+        );
+
+        let vertical = Transformation::Flip {
+            direction: FlipDirection::Vertical,
+        };
+        assert_eq!(
+            pixel(&apply(image(), &vertical).unwrap(), 0, 2),
+            [255, 0, 0]
+        );
+    }
+
+    #[test]
+    fn adjusts_colors() {
+        let img = apply(image(), &Transformation::Brightness { value: 10 }).unwrap();
+        assert_eq!(pixel(&img, 0, 0), [255, 10, 10]);
+        assert_eq!(pixel(&img, 1, 0), [10, 10, 10]);
+
+        let gray = DynamicImage::ImageRgb8(RgbImage::from_fn(2, 1, |x, _| {
+            if x == 0 {
+                Rgb([64, 64, 64])
+            } else {
+                Rgb([192, 192, 192])
+            }
+        }));
+        let img = apply(gray, &Transformation::Contrast { value: 100.0 }).unwrap();
+        assert_eq!(pixel(&img, 0, 0), [0, 0, 0]);
+        assert_eq!(pixel(&img, 1, 0), [255, 255, 255]);
+
+        let img = apply(image(), &Transformation::Grayscale).unwrap();
+        let [r, g, b] = pixel(&img, 0, 0);
+        assert!(r == g && g == b && r > 0);
+    }
+
+    #[test]
+    fn keeps_gif_animations() {
+        let mut data = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut data);
+            for color in [[255, 0, 0, 255], [0, 0, 255, 255]] {
+                let buffer = RgbaImage::from_pixel(4, 3, Rgba(color));
+                let delay = Delay::from_saturating_duration(Duration::from_millis(100));
+                encoder
+                    .encode_frame(Frame::from_parts(buffer, 0, 0, delay))
+                    .unwrap();
+            }
+        }
+
+        let (data, content_type) = transform_image(
+            &Source::Memory(data),
+            &[Transformation::Rotate { degrees: 90 }],
+        )
+        .unwrap();
+        assert_eq!(content_type, "image/gif");
+
+        let frames = GifDecoder::new(Cursor::new(data))
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].buffer().dimensions(), (3, 4));
+        assert_eq!(frames[1].buffer().get_pixel(0, 0), &Rgba([0, 0, 255, 255]));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn edits_start_over_from_the_original_until_reverted() {
+        let store = MemoryStore::default();
+        let key = "media".to_string();
+        let original = png(&image());
+        store.insert(&key, original.clone());
+
+        let rotate = [Transformation::Rotate { degrees: 90 }];
+        edit_media(&store, &key, &rotate, 1024, 1024, 1024)
+            .await
+            .unwrap();
+        assert_eq!(store.get(&original_media_key(&key)), Some(original.clone()));
+        assert_eq!(decode(&store.get(&key).unwrap()).dimensions(), (3, 4));
+
+        // Not rotated twice
+        let grayscale = [Transformation::Grayscale];
+        edit_media(&store, &key, &grayscale, 1024, 1024, 1024)
+            .await
+            .unwrap();
+        assert_eq!(store.get(&original_media_key(&key)), Some(original.clone()));
+        assert_eq!(decode(&store.get(&key).unwrap()).dimensions(), (4, 3));
+
+        assert!(matches!(
+            edit_media(&store, &key, &rotate, 1024, 1024, 1)
+                .await
+                .unwrap_err()
+                .downcast_ref::<TransformError>(),
+            Some(TransformError::TooLarge { .. })
+        ));
+
+        revert_media(&store, &key).await.unwrap();
+        assert_eq!(store.get(&key), Some(original.clone()));
+        assert_eq!(store.get(&original_media_key(&key)), None);
+
+        // Nothing to revert to
+        revert_media(&store, &key).await.unwrap();
+        assert_eq!(store.get(&key), Some(original));
+    }
+}
@@ -0,0 +1,496 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::web::Data;
+use chrono::{TimeZone, Utc};
+
+use common::aws::s3::{
+    animated_thumbnail_key, is_original_media_key, original_media_key, ByteStream, Media,
+    Thumbnails,
+};
+use common::aws::S3Bucket;
+use common::aws::{sqs, SQSQueue};
+use common::config::Config;
+use common::telemetry;
+use common::transform::Transformation;
+
+use common_web::database;
+use common_web::health::{CheckResult, Readiness};
+use common_web::repositories::{DbFeedRepository, FeedRepository};
+
+use image::ImageError;
+use tracing::{info_span, Instrument};
+
+use crate::http;
+use crate::message::{to_messages, EventType, Message};
+use crate::metrics::{
+    EVENTS_FAILED, EVENTS_PROCESSED, EVENTS_RECEIVED, MALFORMED, PROCESSING_DURATION,
+};
+use crate::source::InputTooLarge;
+use crate::thumbnail::{process_image, ThumbnailSettings};
+use crate::transform::{edit_media, revert_media, MediaStore, TransformError};
+
+#[derive(Debug)]
+enum ProcessingError {
+    GenericError,
+    // Recorded on the feed item, retrying can't help
+    PermanentError,
+}
+
+// Handling the messages of a poll delays the next one, readiness allows for it
+const MAX_POLL_AGE: Duration = Duration::from_secs(5 * 60);
+
+// Time of the last successful poll in milliseconds since the epoch, 0 before the first one
+static LAST_POLL: AtomicI64 = AtomicI64::new(0);
+
+/// Handles S3 events and image commands from the queue until an error occurs, while
+/// serving metrics and health probes
+pub async fn run(config: &Config) -> Result<(), Box<dyn Error>> {
+    let max_wait_time = config.aws.sqs_max_wait_time;
+    config.watch_secrets()?;
+
+    let sqs = SQSQueue::new(config).await?;
+    let media_bucket = Arc::new(S3Bucket::<Media>::new(config).await);
+    let thumbs_bucket = Arc::new(S3Bucket::<Thumbnails>::new(config).await);
+    let db_conn = Arc::new(database::create_db_conn_pool(config).await?);
+    let feeds: Arc<dyn FeedRepository> = Arc::new(DbFeedRepository::new((*db_conn).clone()));
+    let settings = Arc::new(ThumbnailSettings::from(config));
+
+    let server = http::serve(config, {
+        let db_conn = Data::from(db_conn.clone());
+        let media_bucket = Data::from(media_bucket.clone());
+        let thumbs_bucket = Data::from(thumbs_bucket.clone());
+        move || {
+            Readiness::new()
+                .database(db_conn.clone())
+                .bucket("s3_media", media_bucket.clone())
+                .bucket("s3_thumbnails", thumbs_bucket.clone())
+                .check("queue", || async { check_last_poll() })
+        }
+    })?;
+
+    tokio::select! {
+        res = receive_messages(
+            &sqs,
+            max_wait_time,
+            &media_bucket,
+            &thumbs_bucket,
+            &feeds,
+            &settings,
+        ) => res,
+        res = server => Ok(res?),
+    }
+}
+
+fn check_last_poll() -> CheckResult {
+    let last_poll = match LAST_POLL.load(Ordering::Relaxed) {
+        0 => return Err("The queue has not been polled yet".into()),
+        millis => Utc.timestamp_millis_opt(millis).unwrap(),
+    };
+
+    let detail = format!("Last poll at {}", last_poll.to_rfc3339());
+    if Utc::now() - last_poll > chrono::Duration::from_std(MAX_POLL_AGE)? {
+        Err(detail.into())
+    } else {
+        Ok(Some(detail))
+    }
+}
+
+async fn receive_messages(
+    sqs: &SQSQueue,
+    max_wait_time: Duration,
+    media_bucket: &Arc<S3Bucket<Media>>,
+    thumbs_bucket: &Arc<S3Bucket<Thumbnails>>,
+    feeds: &Arc<dyn FeedRepository>,
+    settings: &Arc<ThumbnailSettings>,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        let result = sqs.receive(max_wait_time).await?;
+        LAST_POLL.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+
+        if let Some(messages) = result.messages() {
+            log::info!("Received {} messages", messages.len());
+            log::debug!("{:?}", messages);
+            for message in messages {
+                if let Some(body) = message.body() {
+                    let parsed_messages = match to_messages(body) {
+                        Ok(parsed_messages) => parsed_messages,
+                        Err(err) => {
+                            // Left on the queue, to end up in its dead-letter queue
+                            log::warn!("{}", err);
+                            EVENTS_RECEIVED.with_label_values(&[MALFORMED]).inc();
+                            EVENTS_FAILED.with_label_values(&[MALFORMED]).inc();
+                            continue;
+                        }
+                    };
+
+                    log::info!("Found {} events", parsed_messages.len());
+
+                    let span = info_span!("sqs.message", message_id = message.message_id());
+                    telemetry::set_remote_parent(&span, &sqs::trace_context(message));
+
+                    let res = handle_messages(
+                        media_bucket,
+                        thumbs_bucket,
+                        feeds,
+                        settings,
+                        &parsed_messages,
+                    )
+                    .instrument(span)
+                    .await;
+
+                    if res.is_ok() {
+                        if let Some(handle) = message.receipt_handle() {
+                            sqs.delete_message(handle).await?;
+                            log::info!("Completed handling message");
+                        } else {
+                            log::warn!("Did not find handle to clear message from queue");
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn handle_messages(
+    media_bucket: &Arc<S3Bucket<Media>>,
+    thumbs_bucket: &Arc<S3Bucket<Thumbnails>>,
+    feeds: &Arc<dyn FeedRepository>,
+    settings: &Arc<ThumbnailSettings>,
+    messages: &[Arc<Message>],
+) -> Result<(), Box<dyn Error>> {
+    let mut results = Vec::new();
+
+    for message in messages {
+        let message = message.clone();
+        let media_bucket = media_bucket.clone();
+        let thumbs_bucket = thumbs_bucket.clone();
+        let feeds = feeds.clone();
+        let settings = settings.clone();
+        let span = info_span!(
+            "imgproc.event",
+            event = message.event_type.name(),
+            key = %message.key
+        );
+        let res = tokio::spawn(async move {
+            let event = message.event_type.name();
+            EVENTS_RECEIVED.with_label_values(&[event]).inc();
+            let start = Instant::now();
+
+            let res = handle_message(
+                media_bucket,
+                thumbs_bucket,
+                feeds.clone(),
+                settings,
+                message.clone(),
+            )
+            .instrument(span)
+            .await
+            .map_err(|err| {
+                log::error!("{}", err);
+                is_permanent(err.as_ref()).then(|| err.to_string())
+            });
+            let res = match res {
+                Ok(()) => Ok(()),
+                Err(failure) => record_failure(&feeds, &message.key, failure).await,
+            };
+
+            PROCESSING_DURATION
+                .with_label_values(&[event])
+                .observe(start.elapsed().as_secs_f64());
+            match res {
+                Ok(_) => EVENTS_PROCESSED.with_label_values(&[event]).inc(),
+                Err(_) => EVENTS_FAILED.with_label_values(&[event]).inc(),
+            }
+            res
+        })
+        .await?;
+        results.push(res);
+    }
+
+    for res in results {
+        match res {
+            Err(ProcessingError::PermanentError) => log::warn!("Gave up on an event"),
+            res => res?,
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_message(
+    media_bucket: Arc<S3Bucket<Media>>,
+    thumbs_bucket: Arc<S3Bucket<Thumbnails>>,
+    feeds: Arc<dyn FeedRepository>,
+    settings: Arc<ThumbnailSettings>,
+    message: Arc<Message>,
+) -> Result<(), Box<dyn Error>> {
+    // Originals of edited media are only kept for undo, they have no thumbnails
+    if is_original_media_key(&message.key) {
+        log::debug!("ignoring event for original {}", message.key);
+        return Ok(());
+    }
+
+    match &message.event_type {
+        EventType::ObjectCreated => {
+            let max_bytes = media_bucket.upload_max_bytes();
+            let processed = handle_object_created(
+                &message.key,
+                &*media_bucket,
+                thumbs_bucket,
+                feeds,
+                settings,
+                max_bytes,
+            )
+            .await?;
+
+            // Presigned PUTs can't limit the size of uploads, media they created larger
+            // than POST forms and multipart uploads allow is removed instead. Reprocessing
+            // keeps it, the limit may have been lowered since.
+            if processed == Processed::TooLarge {
+                log::warn!("deleting media {} larger than uploads allow", message.key);
+                media_bucket.delete_object(&message.key).await?;
+            }
+        }
+        EventType::ObjectRemoved => {
+            handle_object_deleted(&message.key, media_bucket, thumbs_bucket).await?;
+        }
+        EventType::Transform(transformations) => {
+            handle_transform(&message.key, transformations, media_bucket, settings).await?;
+        }
+        EventType::Revert => {
+            handle_revert(&message.key, media_bucket).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// What became of media whose thumbnails were requested
+#[derive(Debug, PartialEq)]
+pub enum Processed {
+    Thumbnails,
+    // The media no longer exists
+    Missing,
+    // Larger than uploads allow, recorded on its feed item and left in place
+    TooLarge,
+}
+
+pub async fn handle_object_created<M: MediaStore>(
+    key: &String,
+    media_bucket: &M,
+    thumbs_bucket: Arc<S3Bucket<Thumbnails>>,
+    feeds: Arc<dyn FeedRepository>,
+    settings: Arc<ThumbnailSettings>,
+    max_bytes: u64,
+) -> Result<Processed, Box<dyn Error>> {
+    log::info!("Creating thumbnail {}", key);
+
+    let size = media_bucket.size(key).await?;
+    match size {
+        None => {
+            log::info!("media {} no longer exists", key);
+            return Ok(Processed::Missing);
+        }
+        Some(size) if size > max_bytes => {
+            let failure = InputTooLarge {
+                size: size as usize,
+                max: max_bytes as usize,
+            };
+            log::warn!("skipping media {}: {}", key, failure);
+            feeds
+                .set_processing_error(key.clone(), failure.to_string())
+                .await?;
+            return Ok(Processed::TooLarge);
+        }
+        Some(_) => (),
+    }
+
+    let source = media_bucket
+        .read(key, settings.max_in_memory_bytes, settings.max_input_bytes)
+        .await?;
+
+    // Process image
+    let thumbnail = tokio::task::block_in_place(|| process_image(&source, &settings))?;
+    drop(source);
+
+    // Upload to thumbnails bucket
+    log::debug!("uploading image to thumbnails");
+    thumbs_bucket
+        .put_object(key, "image/jpeg", ByteStream::from(thumbnail.poster))
+        .await?;
+
+    let is_animated = if let Some(animation) = thumbnail.animation {
+        log::debug!("uploading animated image to thumbnails");
+        thumbs_bucket
+            .put_object(
+                &animated_thumbnail_key(key),
+                "image/webp",
+                ByteStream::from(animation),
+            )
+            .await?;
+        true
+    } else {
+        false
+    };
+
+    // Records whether the feed item has an animated thumbnail
+    feeds.set_animated(key.clone(), is_animated).await?;
+
+    Ok(Processed::Thumbnails)
+}
+
+async fn handle_object_deleted(
+    key: &String,
+    media_bucket: Arc<S3Bucket<Media>>,
+    thumbs_bucket: Arc<S3Bucket<Thumbnails>>,
+) -> Result<(), Box<dyn Error>> {
+    log::info!("Deleting thumbnail {}", key);
+    thumbs_bucket.delete_object(key).await?;
+    thumbs_bucket
+        .delete_object(&animated_thumbnail_key(key))
+        .await?;
+    media_bucket.delete_object(&original_media_key(key)).await?;
+    Ok(())
+}
+
+async fn handle_transform(
+    key: &String,
+    transformations: &[Transformation],
+    media_bucket: Arc<S3Bucket<Media>>,
+    settings: Arc<ThumbnailSettings>,
+) -> Result<(), Box<dyn Error>> {
+    log::info!("Transforming media {}", key);
+    let max_bytes = media_bucket.upload_max_bytes();
+    edit_media(
+        &*media_bucket,
+        key,
+        transformations,
+        settings.max_in_memory_bytes,
+        settings.max_input_bytes,
+        max_bytes,
+    )
+    .await
+}
+
+async fn handle_revert(
+    key: &String,
+    media_bucket: Arc<S3Bucket<Media>>,
+) -> Result<(), Box<dyn Error>> {
+    log::info!("Reverting media {}", key);
+    revert_media(&*media_bucket, key).await
+}
+
+// Failures retrying can't fix, such as media that can't be decoded or edits that don't
+// fit it
+fn is_permanent(err: &(dyn Error + 'static)) -> bool {
+    err.is::<TransformError>()
+        || err.is::<InputTooLarge>()
+        || matches!(
+            err.downcast_ref::<ImageError>(),
+            Some(ImageError::Decoding(_) | ImageError::Unsupported(_) | ImageError::Limits(_))
+        )
+}
+
+// The message of a permanent failure is deleted once the failure is recorded on the feed
+// item, any other one is retried
+async fn record_failure(
+    feeds: &Arc<dyn FeedRepository>,
+    key: &str,
+    failure: Option<String>,
+) -> Result<(), ProcessingError> {
+    let Some(failure) = failure else {
+        return Err(ProcessingError::GenericError);
+    };
+
+    feeds
+        .set_processing_error(key.to_string(), failure)
+        .await
+        .map_err(|err| {
+            log::error!("{}", err);
+            ProcessingError::GenericError
+        })?;
+    Err(ProcessingError::PermanentError)
+}
+
+impl Display for ProcessingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProcessingError::GenericError => write!(f, "Failed to process messages"),
+            ProcessingError::PermanentError => write!(f, "Failed to process media"),
+        }
+    }
+}
+
+impl Error for ProcessingError {}
+
+#[cfg(test)]
+mod tests {
+    use common::config::{ConfigArgs, Service};
+    use common_web::database::ReadFrom;
+    use common_web::models::NewFeedItem;
+
+    use crate::transform::tests::MemoryStore;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reprocessing_keeps_oversized_media() {
+        let args = ConfigArgs {
+            overrides: [
+                "database.dialect=sqlite",
+                "database.name=:memory:",
+                "aws.region=us-east-1",
+                "aws.media_bucket=media",
+                "aws.thumbnails_bucket=thumbnails",
+                "aws.sqs_queue=queue",
+            ]
+            .map(String::from)
+            .to_vec(),
+            ..ConfigArgs::default()
+        };
+        let config = Config::load(Service::Imgproc, &args).await.unwrap();
+        let pool = database::create_db_conn_pool(&config).await.unwrap();
+        let feeds: Arc<dyn FeedRepository> = Arc::new(DbFeedRepository::new(pool));
+        let thumbs_bucket = Arc::new(S3Bucket::<Thumbnails>::new(&config).await);
+
+        let key = "image".to_string();
+        let feed_item = feeds
+            .create(NewFeedItem {
+                created_by: "user@example.com".to_string(),
+                image_id: key.clone(),
+                caption: None,
+            })
+            .await
+            .unwrap();
+        let media_bucket = MemoryStore::default();
+        media_bucket.insert(&key, vec![0; 16]);
+
+        let processed = handle_object_created(
+            &key,
+            &media_bucket,
+            thumbs_bucket,
+            feeds.clone(),
+            Arc::new(ThumbnailSettings::default()),
+            8,
+        )
+        .await
+        .unwrap();
+        assert_eq!(processed, Processed::TooLarge);
+        assert_eq!(media_bucket.get(&key), Some(vec![0; 16]));
+
+        let feed_item = feeds
+            .find(feed_item.id, ReadFrom::Primary)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            feed_item.processing_error.as_deref(),
+            Some("Media of 16 bytes is larger than the 8 bytes allowed")
+        );
+    }
+}
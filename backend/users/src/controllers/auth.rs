@@ -1,30 +1,26 @@
 use std::sync::Arc;
 
-use actix_web::http::StatusCode;
-use actix_web::web::{block, Data, Json};
+use actix_web::web::{Data, Json};
 
 use actix_web::{get, post};
 
-use common_web::database::DBConnPool;
 use common_web::guards::IsLoggedIn;
-use common_web::messages::{ErrMessage, Message, OkMessage};
-use common_web::models::User;
+use common_web::messages::{ErrMessage, ErrorCode, Message, OkMessage, Problem};
+use common_web::rate_limit::RateLimiter;
+use common_web::repositories::UserRepository;
 use common_web::router::{RouteBuilder, Router};
+use common_web::validation::Valid;
 
 use serde_json::json;
 
 use common::{
     config::Config,
     jwt::generate_jwt,
-    passwords::{self, compare_with_hashed_password},
+    passwords::{self, compare_with_dummy_password, compare_with_hashed_password},
 };
 
 use log::error;
 
-use common_web::schema::users::dsl::*;
-use diesel::dsl::now;
-use diesel::prelude::*;
-
 use crate::requests::{UserAuthRequest, ValidSyntaxUserAuth};
 use crate::responses::AuthResultResponse;
 
@@ -38,6 +34,14 @@ impl Router for AuthRouter {
     }
 }
 
+#[utoipa::path(
+    tag = "auth",
+    responses(
+        (status = 200, description = "The token is valid"),
+        (status = 401, body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
 #[get("/verification")]
 async fn verification(_auth: IsLoggedIn) -> Message<serde_json::Value> {
     Ok(OkMessage::Success(json!({
@@ -46,32 +50,28 @@ async fn verification(_auth: IsLoggedIn) -> Message<serde_json::Value> {
     })))
 }
 
+#[utoipa::path(
+    tag = "auth",
+    request_body = UserAuthRequest,
+    responses(
+        (status = 201, body = AuthResultResponse),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 422, body = Problem, content_type = "application/problem+json"),
+        (status = 429, body = Problem, content_type = "application/problem+json")
+    )
+)]
 #[post("")]
 async fn register(
-    conn: Data<DBConnPool>,
+    repository: Data<dyn UserRepository>,
     config: Data<Config>,
-    auth: Json<UserAuthRequest>,
+    auth: Valid<Json<UserAuthRequest>>,
 ) -> Message<AuthResultResponse> {
 
-    // Check email is valid and password is specified
+    // Email and password were checked when extracting the request
     let ValidSyntaxUserAuth {
         user_email,
         user_password,
-    } = auth.into_inner().validate_syntax()?;
-
-    // Check if user already exists...
-    let result = block({
-        let conn = conn.get()?;
-        let user_email = user_email.clone();
-        move || users.find(&*user_email).get_result::<User>(&conn) 
-    }).await?;
-
-    if result.is_ok() {
-        return Err(ErrMessage::Generic {
-            status: StatusCode::UNPROCESSABLE_ENTITY,
-            message: "User may already exist",
-        });
-    }
+    } = auth.into_inner().into_inner().try_into()?;
 
     // Hash password
     let hashed_pass = passwords::generate_hashed_password(user_password)
@@ -81,25 +81,18 @@ async fn register(
             return ErrMessage::InternalServerError;
         })?;
 
-    // Create new user in DB, storing hashed password
-    let user = block({
-        let conn = conn.get()?;
-        move || {
-            diesel::insert_into(users)
-                .values((
-                    email.eq(&*user_email),
-                    password_hash.eq(hashed_pass),
-                    created_at.eq(now),
-                    updated_at.eq(now),
-                ))
-                .get_result::<User>(&conn)
-        }
-    })
-    .await?
-    .map_err(|e| {
-        error!("/register: Database error: {}", e);
-        return ErrMessage::InternalServerError;
-    })?;
+    // Create new user in DB, storing hashed password. The unique email decides which
+    // of concurrent registrations wins.
+    let user = repository
+        .create(user_email.to_string(), hashed_pass)
+        .await
+        .map_err(|e| match e.code() {
+            ErrorCode::Conflict => ErrMessage::Generic {
+                code: ErrorCode::UserAlreadyExists,
+                message: "User may already exist",
+            },
+            _ => e,
+        })?;
 
     let short = user.short().to_string();
 
@@ -115,40 +108,58 @@ async fn register(
     }))
 }
 
+#[utoipa::path(
+    tag = "auth",
+    request_body = UserAuthRequest,
+    responses(
+        (status = 200, body = AuthResultResponse),
+        (status = 400, body = Problem, content_type = "application/problem+json"),
+        (status = 401, body = Problem, content_type = "application/problem+json"),
+        (status = 429, body = Problem, content_type = "application/problem+json")
+    )
+)]
 #[post("/login")]
 async fn login(
-    conn: Data<DBConnPool>,
+    repository: Data<dyn UserRepository>,
     config: Data<Config>,
-    auth: Json<UserAuthRequest>,
+    limiter: Data<RateLimiter>,
+    auth: Valid<Json<UserAuthRequest>>,
 ) -> Message<AuthResultResponse> {
-    let conn = conn.get()?;
-
     let unauth_err = ErrMessage::Generic {
-        status: StatusCode::UNAUTHORIZED,
+        code: ErrorCode::InvalidCredentials,
         message: "Unauthorized",
     };
 
-    // Check email is valid and password is specified
+    // Email and password were checked when extracting the request
     let ValidSyntaxUserAuth {
         user_email,
         user_password,
-    } = auth.into_inner().validate_syntax()?;
+    } = auth.into_inner().into_inner().try_into()?;
 
-    // Find user...
-    let user = block(move || users.find(&*user_email).get_result::<User>(&conn))
-        .await?
-        .map_err(|_| unauth_err.clone())?;
+    // Refuse locked accounts before spending time on their password
+    limiter.check_account(&user_email).await?;
 
-    let known_pass = Arc::new(user
-        .password_hash
-        .as_ref()
-        .ok_or(unauth_err.clone())?
-        .clone());
+    // Find user...
+    let account = user_email.clone();
+    let user = repository.find(user_email.to_string()).await?;
+
+    let known_pass = user.as_ref().and_then(|user| user.password_hash.clone());
+    let (user, known_pass) = match (user, known_pass) {
+        (Some(user), Some(known_pass)) => (user, Arc::new(known_pass)),
+        _ => {
+            // Answers as late as for a wrong password
+            compare_with_dummy_password(user_password).await;
+            limiter.login_failed(&account).await;
+            return Err(unauth_err);
+        }
+    };
 
     // Verify user password matches
-    compare_with_hashed_password(user_password, known_pass)
-        .await
-        .map_err(|_| unauth_err)?;
+    if compare_with_hashed_password(user_password, known_pass).await.is_err() {
+        limiter.login_failed(&account).await;
+        return Err(unauth_err);
+    }
+    limiter.login_succeeded(&account).await;
 
     let short = user.short().to_string();
 
@@ -1,21 +1,23 @@
 use common_web::{
-    database::DBConnPool,
-    messages::{Message, OkMessage},
-    router::{RouteBuilder, Router}, models::User,
+    messages::{ErrMessage, ErrorCode, Message, OkMessage, Problem},
+    repositories::UserRepository,
+    router::{ApiVersion, RouteBuilder, Router},
 };
 
 use actix_web::{
     get,
-    web::{block, Data, Path},
+    web::{Data, Path},
 };
 
-use common_web::schema::users::dsl::*;
-use diesel::prelude::*;
-
 mod auth;
 use auth::AuthRouter;
 
-use crate::responses::UserResponse;
+use crate::responses::VersionedUser;
+
+const USER_NOT_FOUND: ErrMessage = ErrMessage::Generic {
+    code: ErrorCode::NotFound,
+    message: "Resource not found",
+};
 
 pub struct UserRouter;
 impl Router for UserRouter {
@@ -26,16 +28,234 @@ impl Router for UserRouter {
     }
 }
 
+#[utoipa::path(
+    tag = "users",
+    responses(
+        (status = 200, body = VersionedUser),
+        (status = 404, body = Problem, content_type = "application/problem+json")
+    )
+)]
 #[get("/{user_email}")]
-async fn get_user_by_id(conn: Data<DBConnPool>, user_email: Path<String>) -> Message<UserResponse> {
-    let conn = conn.get()?;
+async fn get_user_by_id(
+    repository: Data<dyn UserRepository>,
+    user_email: Path<String>,
+    version: ApiVersion,
+) -> Message<VersionedUser> {
+    let user = repository
+        .find(user_email.into_inner())
+        .await?
+        .ok_or(USER_NOT_FOUND)?;
+
+    Ok(OkMessage::Success(VersionedUser::new(user, version)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web::Data, App};
+    use serde_json::{json, Value};
+
+    use chrono::Utc;
+    use futures_util::future::{ready, BoxFuture};
+
+    use common::config::{Config, ConfigArgs, Service};
+    use common_web::database;
+    use common_web::messages::{ErrMessage, ErrorCode};
+    use common_web::models::User;
+    use common_web::rate_limit::{MemoryStore, RateLimiter};
+    use common_web::repositories::{DbUserRepository, UserRepository};
+    use common_web::router::{ApiVersion, RouteBuilder, VersionScope};
 
-    let user_email = user_email.into_inner();
+    use super::UserRouter;
+
+    // Knows a single user, without a database
+    struct SingleUser;
+
+    impl UserRepository for SingleUser {
+        fn find(&self, user_email: String) -> BoxFuture<'_, Result<Option<User>, ErrMessage>> {
+            let now = Utc::now().naive_utc();
+            let user = (user_email == "user@example.com").then_some(User {
+                id: 1,
+                email: user_email,
+                password_hash: None,
+                created_at: now,
+                updated_at: now,
+            });
+            Box::pin(ready(Ok(user)))
+        }
+
+        fn create(&self, _: String, _: String) -> BoxFuture<'_, Result<User, ErrMessage>> {
+            unimplemented!()
+        }
+    }
+
+    // Every email is taken, by a registration that won the race
+    struct TakenEmails;
+
+    impl UserRepository for TakenEmails {
+        fn find(&self, _: String) -> BoxFuture<'_, Result<Option<User>, ErrMessage>> {
+            unimplemented!()
+        }
+
+        fn create(&self, _: String, _: String) -> BoxFuture<'_, Result<User, ErrMessage>> {
+            Box::pin(ready(Err(ErrMessage::Generic {
+                code: ErrorCode::Conflict,
+                message: "Resource already exists",
+            })))
+        }
+    }
 
-    let user = block(move || users.find(user_email).get_result::<User>(&conn)).await??;
+    #[actix_web::test]
+    async fn gets_users_from_the_repository() {
+        let repository: Arc<dyn UserRepository> = Arc::new(SingleUser);
+        let app = test::init_service(App::new().app_data(Data::from(repository)).configure(
+            |srv| {
+                RouteBuilder::new(srv)
+                    .extend::<UserRouter>("/users")
+                    .build();
+            },
+        ))
+        .await;
 
-    Ok(OkMessage::Success(UserResponse{
-        email: user.email,
-        created_at: user.created_at
-    }))
+        let request = test::TestRequest::get()
+            .uri("/users/user@example.com")
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(response["email"], "user@example.com");
+
+        let request = test::TestRequest::get()
+            .uri("/users/unknown@example.com")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, request).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_web::test]
+    async fn versions_serve_different_shapes() {
+        let repository: Arc<dyn UserRepository> = Arc::new(SingleUser);
+        let app = test::init_service(App::new().app_data(Data::from(repository)).configure(
+            |srv| {
+                RouteBuilder::new(srv)
+                    .versioned(VersionScope::new(ApiVersion::V0), |routes| {
+                        routes.extend::<UserRouter>("/users")
+                    })
+                    .versioned(VersionScope::new(ApiVersion::V1), |routes| {
+                        routes.extend::<UserRouter>("/users")
+                    })
+                    .build();
+            },
+        ))
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/api/v0/users/user@example.com")
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&app, request).await;
+        assert!(!response["created_at"].as_str().unwrap().ends_with('Z'));
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/users/user@example.com")
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&app, request).await;
+        assert!(response["created_at"].as_str().unwrap().ends_with('Z'));
+    }
+
+    #[actix_web::test]
+    async fn registering_a_taken_email_conflicts() {
+        let repository: Arc<dyn UserRepository> = Arc::new(TakenEmails);
+        let args = ConfigArgs {
+            overrides: vec![
+                "database.dialect=sqlite".to_string(),
+                "database.name=:memory:".to_string(),
+                "jwt.secret=secret".to_string(),
+            ],
+            ..ConfigArgs::default()
+        };
+        let config = Config::load(Service::Users, &args).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository))
+                .app_data(Data::new(config))
+                .configure(|srv| {
+                    RouteBuilder::new(srv)
+                        .extend::<UserRouter>("/users")
+                        .build();
+                }),
+        )
+        .await;
+
+        let request = test::TestRequest::post()
+            .uri("/users/auth")
+            .set_json(json!({ "email": "user@example.com", "password": "password" }))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let response: Value = test::read_body_json(response).await;
+        assert_eq!(response["code"], "user_already_exists");
+    }
+
+    #[actix_web::test]
+    async fn registers_and_logs_in_with_sqlite() {
+        let args = ConfigArgs {
+            overrides: vec![
+                "database.dialect=sqlite".to_string(),
+                "database.name=:memory:".to_string(),
+                "jwt.secret=secret".to_string(),
+            ],
+            ..ConfigArgs::default()
+        };
+        let config = Config::load(Service::Users, &args).await.unwrap();
+        let pool = database::create_db_conn_pool(&config).await.unwrap();
+        let repository: Arc<dyn UserRepository> = Arc::new(DbUserRepository::new(pool));
+        let limiter = Data::new(RateLimiter::new(Arc::new(MemoryStore::default())));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(repository))
+                .app_data(Data::new(config))
+                .app_data(limiter)
+                .configure(|srv| {
+                    RouteBuilder::new(srv)
+                        .extend::<UserRouter>("/users")
+                        .build();
+                }),
+        )
+        .await;
+        let credentials = json!({ "email": "user@example.com", "password": "password" });
+
+        let request = test::TestRequest::post()
+            .uri("/users/auth")
+            .set_json(&credentials)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, request).await.status(),
+            StatusCode::CREATED
+        );
+
+        let request = test::TestRequest::post()
+            .uri("/users/auth")
+            .set_json(&credentials)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, request).await.status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        let request = test::TestRequest::post()
+            .uri("/users/auth/login")
+            .set_json(&credentials)
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(response["user"], "user@example.com");
+
+        let request = test::TestRequest::get()
+            .uri("/users/user@example.com")
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(response["email"], "user@example.com");
+    }
 }
@@ -1,16 +1,29 @@
 use actix_cors::Cors;
 use actix_web::middleware::Compress;
-use actix_web::middleware::Logger;
 use actix_web::middleware::NormalizePath;
+use actix_web::middleware::from_fn;
 use actix_web::{
-    web::Data,
+    http::Method,
+    web::{self, Data},
     App, HttpServer,
 };
-use env_logger;
 
-use common::config::Config;
+use clap::{Parser, Subcommand};
+use common::config::{Config, ConfigArgs, Service};
+use common::telemetry;
 use common_web::database;
-use common_web::router::RouteBuilder;
+use common_web::extractors;
+use common_web::health::{self, Readiness};
+use common_web::metrics;
+use common_web::migrations::MigrateCommand;
+use common_web::openapi;
+use common_web::rate_limit::{self, Limit, RateLimiter};
+use common_web::repositories::{DbUserRepository, UserRepository};
+use common_web::request_id;
+use common_web::router::{ApiVersion, RouteBuilder, VersionScope};
+use std::sync::Arc;
+use std::time::Duration;
+use utoipa::openapi::Info;
 
 mod controllers;
 mod requests;
@@ -18,27 +31,96 @@ mod responses;
 
 use controllers::UserRouter;
 
+#[derive(Parser)]
+#[command(about = "Serves the users API")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the API (default)
+    Serve,
+    /// Manage the database schema
+    #[command(subcommand)]
+    Migrate(MigrateCommand),
+}
+
 #[actix_web::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-
-    let config = Config::load_dotenv().await?;
-    let db_conn = Data::new(database::create_db_conn_pool(&config)?);
+    let cli = Cli::parse();
+    let service = match cli.command {
+        Some(Command::Migrate(_)) => Service::Migrate,
+        _ => Service::Users,
+    };
+    let config = Config::load(service, &cli.config).await?;
+    if cli.config.print_config {
+        print!("{}", config.render());
+        return Ok(());
+    }
+    let _telemetry = telemetry::init("users", config.telemetry.otlp_endpoint.as_deref())?;
+    if let Some(Command::Migrate(command)) = cli.command {
+        return command.run(&config);
+    }
+    config.watch_secrets()?;
+    let db_conn = Data::new(database::create_db_conn_pool(&config).await?);
+    let users: Arc<dyn UserRepository> = Arc::new(DbUserRepository::new(db_conn.get_ref().clone()));
+    let users = Data::from(users);
+    let limiter = Data::new(
+        RateLimiter::new(rate_limit::create_store(&config, &db_conn)?)
+            .route(
+                Method::POST,
+                "/users/auth/login",
+                Limit::new(10, Duration::from_secs(6)),
+            )
+            .route(
+                Method::POST,
+                "/users/auth",
+                Limit::new(5, Duration::from_secs(60)),
+            )
+            .trusted_proxies(config.http.trusted_proxies.clone()),
+    );
     let config = Data::new(config);
 
     HttpServer::new(move || {
         App::new()
-            .wrap(Logger::default())
+            .wrap(from_fn(rate_limit::middleware))
             .wrap(Cors::permissive())
             .wrap(Compress::default())
             .wrap(NormalizePath::trim())
+            .wrap(from_fn(metrics::middleware))
+            .wrap(from_fn(request_id::middleware))
             .app_data(db_conn.clone())
+            .app_data(users.clone())
             .app_data(config.clone())
+            .app_data(limiter.clone())
+            .configure(|srv| extractors::configure(srv, &config))
+            .configure(metrics::configure)
+            .configure(|srv| {
+                health::configure(
+                    srv,
+                    Readiness::new()
+                        .database(db_conn.clone())
+                        .jwt(config.clone()),
+                )
+            })
             .configure(|srv| {
                 RouteBuilder::new(srv)
-                    .extend::<UserRouter>("/api/v0/users")
-                    .build();
+                    .versioned(VersionScope::new(ApiVersion::V0), |routes| {
+                        routes.extend::<UserRouter>("/users")
+                    })
+                    .versioned(VersionScope::new(ApiVersion::V1), |routes| {
+                        routes.extend::<UserRouter>("/users")
+                    })
+                    .build_with_docs(|srv, mut api| {
+                        api.info = Info::new("users", env!("CARGO_PKG_VERSION"));
+                        openapi::configure(srv, api, &config);
+                    });
             })
+            .default_service(web::to(extractors::not_found))
     })
     .bind(("0.0.0.0", 8080))?
     .run()
@@ -1,14 +1,18 @@
 use std::sync::Arc;
 
-use actix_web::http::StatusCode;
-use common_web::messages::ErrMessage;
-use email_address::EmailAddress;
+use common_web::messages::{ErrMessage, FieldError};
 use serde::Deserialize;
+use utoipa::ToSchema;
+use validator::Validate;
 
-
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema, Validate)]
 pub struct UserAuthRequest {
+    #[validate(
+        required(message = "Email is required"),
+        email(message = "Email is malformed")
+    )]
     pub email: Option<String>,
+    #[validate(required(message = "Password is required"))]
     pub password: Option<String>,
 }
 
@@ -17,25 +21,29 @@ pub struct ValidSyntaxUserAuth {
     pub user_password: Arc<String>,
 }
 
-impl UserAuthRequest {
-    pub fn validate_syntax(self) -> Result<ValidSyntaxUserAuth, ErrMessage> {
-        let email_err = ErrMessage::Generic {
-            status: StatusCode::BAD_REQUEST,
-            message: "Email is required or malformed",
-        };
-        let passwd_err = ErrMessage::Generic {
-            status: StatusCode::BAD_REQUEST,
-            message: "Password is required",
-        };
-        let user_email = self.email.ok_or(email_err.clone())?;
-        if !EmailAddress::is_valid(&user_email) {
-            return Err(email_err);
+impl TryFrom<UserAuthRequest> for ValidSyntaxUserAuth {
+    type Error = ErrMessage;
+
+    fn try_from(request: UserAuthRequest) -> Result<Self, Self::Error> {
+        match (request.email, request.password) {
+            (Some(email), Some(password)) => Ok(ValidSyntaxUserAuth {
+                user_email: Arc::new(email),
+                user_password: Arc::new(password),
+            }),
+            (email, password) => {
+                let mut errors = Vec::new();
+                if email.is_none() {
+                    errors.push(FieldError::new("email", "required", "Email is required"));
+                }
+                if password.is_none() {
+                    errors.push(FieldError::new(
+                        "password",
+                        "required",
+                        "Password is required",
+                    ));
+                }
+                Err(ErrMessage::Validation(errors))
+            }
         }
-        let user_password = self.password.ok_or(passwd_err)?;
-        return Ok(ValidSyntaxUserAuth {
-            user_email: Arc::new(user_email),
-            user_password: Arc::new(user_password),
-        });
     }
 }
-
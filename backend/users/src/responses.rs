@@ -1,14 +1,48 @@
 use serde::Serialize;
+use utoipa::ToSchema;
 
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime, Utc};
 
-#[derive(Serialize)]
+use common_web::models::User;
+use common_web::router::ApiVersion;
+
+#[derive(Serialize, ToSchema)]
 pub struct UserResponse {
     pub email: String,
     pub created_at: NaiveDateTime, 
 }
 
-#[derive(Serialize)]
+/// A user as v1 answers it, with an explicit UTC creation time
+#[derive(Serialize, ToSchema)]
+pub struct UserResponseV1 {
+    pub email: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A user in the shape of the API version serving it
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum VersionedUser {
+    V0(UserResponse),
+    V1(UserResponseV1),
+}
+
+impl VersionedUser {
+    pub fn new(user: User, version: ApiVersion) -> Self {
+        match version {
+            ApiVersion::V0 => VersionedUser::V0(UserResponse {
+                email: user.email,
+                created_at: user.created_at,
+            }),
+            ApiVersion::V1 => VersionedUser::V1(UserResponseV1 {
+                email: user.email,
+                created_at: DateTime::from_naive_utc_and_offset(user.created_at, Utc),
+            }),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
 pub struct AuthResultResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub auth: Option<bool>,
@@ -73,7 +73,7 @@ cat <<__EOF__
       CAPTION: $CAPTION
 __EOF__
 
-try xh_authed POST $HOST/feed caption="$CAPTION" || \
+try xh_authed POST $HOST/feed caption="$CAPTION" content_type=image/jpeg || \
 	error Failed to create feed
 UPLOAD_URL=$(from_json_resp .url)
 FEED_ID=$(from_json_resp .id)
@@ -86,6 +86,27 @@ try xh PUT "$UPLOAD_URL" Content-Type:image/jpeg < $IMAGE || \
 	error Failed to upload $IMAGE
 
 
+# -----------------------------------------------------------------------------
+title Uploading feed image in parts
+try xh_authed POST $HOST/feed/$FEED_ID/uploads content_type=image/jpeg || \
+	error Failed to start the upload of feed $FEED_ID
+UPLOAD_ID=$(from_json_resp .upload_id)
+echo "   UPLOAD: $UPLOAD_ID"
+
+try xh_authed GET $HOST/feed/$FEED_ID/uploads/$UPLOAD_ID/parts/1 || \
+	error Failed to presign part 1 of upload $UPLOAD_ID
+PART_URL=$(from_json_resp .url)
+try xh PUT "$PART_URL" < $IMAGE || \
+	error Failed to upload part 1 of upload $UPLOAD_ID
+
+try xh_authed GET $HOST/feed/$FEED_ID/uploads/$UPLOAD_ID || \
+	error Failed to list the parts of upload $UPLOAD_ID
+echo "    PARTS: $(from_json_resp '.parts | length')"
+
+try xh_authed POST $HOST/feed/$FEED_ID/uploads/$UPLOAD_ID/complete || \
+	error Failed to complete upload $UPLOAD_ID
+
+
 # -----------------------------------------------------------------------------
 title Getting feed details
 try xh_authed GET $HOST/feed/$FEED_ID || \